@@ -1,42 +1,361 @@
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
 
 /// A canonical 64-bit virtual address.
-/// 
+///
 /// The upper 16 bits need to be copies of bit 47.
 #[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VAddr(u64);
 
 /// A physical 64-bit address.
-/// 
+///
 /// Only the lower 52 bits can be used. The upper 12 bits must always be zero.
 #[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PAddr(u64);
 
+/// Aligns `addr` upwards to the next multiple of `align`, which must be a power of two.
+#[inline]
+pub const fn align_up(addr: u64, align: u64) -> u64 {
+    assert!(align.is_power_of_two(), "Alignment must be a power of two");
+    let mask = align - 1;
+    if addr & mask == 0 {
+        addr
+    } else {
+        (addr | mask).checked_add(1).expect("Overflow while aligning address upwards")
+    }
+}
+
+/// Aligns `addr` downwards to the previous multiple of `align`, which must be a power of two.
+#[inline]
+pub const fn align_down(addr: u64, align: u64) -> u64 {
+    assert!(align.is_power_of_two(), "Alignment must be a power of two");
+    addr & !(align - 1)
+}
+
 impl VAddr {
+    /// Creates a new virtual address.
+    ///
+    /// Panics if the address is not canonical.
     #[inline]
-    pub fn from(value: u64) -> VAddr {
-        let upper = value >> 47;
-        if upper == 0_u64 || upper == 0x1FFFF_u64 {
-            VAddr(value)
-        } else {
-            VAddr(((value << 16) as i64 >> 16) as u64)
+    pub fn new(value: u64) -> VAddr {
+        VAddr::try_new(value).expect("Virtual address is not canonical")
+    }
+
+    /// Creates a new virtual address, fails if bits 48-63 are not copies of bit 47.
+    #[inline]
+    pub const fn try_new(value: u64) -> Result<VAddr, &'static str> {
+        match value >> 47 {
+            0 | 0x1FFFF => Ok(VAddr(value)),
+            _ => Err("Virtual address is not canonical"),
         }
     }
 
+    /// Creates a new virtual address and replaces bits 48-63 with copies of bit 47.
+    #[inline]
+    pub const fn new_truncate(value: u64) -> VAddr {
+        VAddr(((value << 16) as i64 >> 16) as u64)
+    }
+
     #[inline]
-    pub fn zero() -> VAddr {
+    pub const fn zero() -> VAddr {
         VAddr(0_u64)
     }
+
+    #[inline]
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    pub fn from_ptr<T: ?Sized>(ptr: *const T) -> VAddr {
+        VAddr::new(ptr as *const () as u64)
+    }
+
+    #[inline]
+    pub const fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    #[inline]
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Aligns the address upwards to the next multiple of `align`.
+    #[inline]
+    pub const fn align_up(self, align: u64) -> VAddr {
+        VAddr::new_truncate(align_up(self.0, align))
+    }
+
+    /// Aligns the address downwards to the previous multiple of `align`.
+    #[inline]
+    pub const fn align_down(self, align: u64) -> VAddr {
+        VAddr::new_truncate(align_down(self.0, align))
+    }
+
+    #[inline]
+    pub const fn is_aligned(self, align: u64) -> bool {
+        align_down(self.0, align) == self.0
+    }
+
+    /// Offset into a 4KiB page (bits 0-11).
+    #[inline]
+    pub const fn page_offset(self) -> u64 {
+        self.0 & 0xFFF
+    }
+
+    /// Index into the page table at `level` (4 = PML4, 1 = PT).
+    #[inline]
+    pub const fn page_table_index(self, level: u32) -> usize {
+        assert!(level >= 1 && level <= 4, "Page table levels range from 1 to 4");
+        ((self.0 >> (12 + 9 * (level - 1))) & 0x1FF) as usize
+    }
+
+    /// Index into the page table (bits 12-20).
+    #[inline]
+    pub const fn p1_index(self) -> usize {
+        self.page_table_index(1)
+    }
+
+    /// Index into the page directory (bits 21-29).
+    #[inline]
+    pub const fn p2_index(self) -> usize {
+        self.page_table_index(2)
+    }
+
+    /// Index into the page directory pointer table (bits 30-38).
+    #[inline]
+    pub const fn p3_index(self) -> usize {
+        self.page_table_index(3)
+    }
+
+    /// Index into the PML4 (bits 39-47).
+    #[inline]
+    pub const fn p4_index(self) -> usize {
+        self.page_table_index(4)
+    }
 }
 
 impl PAddr {
+    const PHYS_MASK: u64 = (1_u64 << 52) - 1;
+
+    /// Creates a new physical address.
+    ///
+    /// Panics if any of the bits 52-63 are set.
     #[inline]
-    pub fn from(value: u64) -> PAddr {
-        const PHYS_MASK: u64 = (1_u64 << 52) - 1;
-        PAddr(value & PHYS_MASK)
+    pub fn new(value: u64) -> PAddr {
+        PAddr::try_new(value).expect("Physical address has bits above bit 51 set")
     }
 
+    /// Creates a new physical address, fails if any of the bits 52-63 are set.
     #[inline]
-    pub fn zero() -> PAddr {
+    pub const fn try_new(value: u64) -> Result<PAddr, &'static str> {
+        if value & !Self::PHYS_MASK == 0 {
+            Ok(PAddr(value))
+        } else {
+            Err("Physical address has bits above bit 51 set")
+        }
+    }
+
+    /// Creates a new physical address and clears bits 52-63.
+    #[inline]
+    pub const fn new_truncate(value: u64) -> PAddr {
+        PAddr(value & Self::PHYS_MASK)
+    }
+
+    #[inline]
+    pub const fn zero() -> PAddr {
         PAddr(0_u64)
     }
+
+    #[inline]
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Aligns the address upwards to the next multiple of `align`.
+    #[inline]
+    pub fn align_up(self, align: u64) -> PAddr {
+        PAddr::new(align_up(self.0, align))
+    }
+
+    /// Aligns the address downwards to the previous multiple of `align`.
+    #[inline]
+    pub const fn align_down(self, align: u64) -> PAddr {
+        PAddr(align_down(self.0, align))
+    }
+
+    #[inline]
+    pub const fn is_aligned(self, align: u64) -> bool {
+        align_down(self.0, align) == self.0
+    }
+}
+
+macro_rules! impl_addr_ops {
+    ($addr:ident) => {
+        impl Add<u64> for $addr {
+            type Output = $addr;
+
+            #[inline]
+            fn add(self, rhs: u64) -> $addr {
+                $addr::new(self.0.checked_add(rhs).expect("Address overflow"))
+            }
+        }
+
+        impl AddAssign<u64> for $addr {
+            #[inline]
+            fn add_assign(&mut self, rhs: u64) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl Sub<u64> for $addr {
+            type Output = $addr;
+
+            #[inline]
+            fn sub(self, rhs: u64) -> $addr {
+                $addr::new(self.0.checked_sub(rhs).expect("Address underflow"))
+            }
+        }
+
+        impl SubAssign<u64> for $addr {
+            #[inline]
+            fn sub_assign(&mut self, rhs: u64) {
+                *self = *self - rhs;
+            }
+        }
+
+        /// Distance between two addresses in bytes.
+        impl Sub<$addr> for $addr {
+            type Output = u64;
+
+            #[inline]
+            fn sub(self, rhs: $addr) -> u64 {
+                self.0.checked_sub(rhs.0).expect("Address underflow")
+            }
+        }
+
+        impl fmt::Debug for $addr {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, concat!(stringify!($addr), "(0x{:X})"), self.0)
+            }
+        }
+
+        impl fmt::LowerHex for $addr {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::LowerHex::fmt(&self.0, f)
+            }
+        }
+
+        impl fmt::UpperHex for $addr {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::UpperHex::fmt(&self.0, f)
+            }
+        }
+
+        impl fmt::Binary for $addr {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Binary::fmt(&self.0, f)
+            }
+        }
+
+        impl fmt::Pointer for $addr {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Pointer::fmt(&(self.0 as *const ()), f)
+            }
+        }
+    };
+}
+
+impl_addr_ops!(VAddr);
+impl_addr_ops!(PAddr);
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::format;
+
+    use super::*;
+
+    #[test]
+    fn canonical_virtual_addresses() {
+        assert!(VAddr::try_new(0x0000_7FFF_FFFF_FFFF).is_ok());
+        assert!(VAddr::try_new(0xFFFF_8000_0000_0000).is_ok());
+        assert!(VAddr::try_new(0x0000_8000_0000_0000).is_err());
+        assert!(VAddr::try_new(0x1234_0000_0000_0000).is_err());
+
+        assert_eq!(VAddr::new_truncate(0x0000_8000_0000_0000).as_u64(), 0xFFFF_8000_0000_0000);
+        assert_eq!(VAddr::new_truncate(0x1234_0000_0000_1000).as_u64(), 0x1000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn non_canonical_virtual_address_panics() {
+        VAddr::new(0x0000_8000_0000_0000);
+    }
+
+    #[test]
+    fn physical_addresses() {
+        assert!(PAddr::try_new(0x000F_FFFF_FFFF_FFFF).is_ok());
+        assert!(PAddr::try_new(0x0010_0000_0000_0000).is_err());
+        assert_eq!(PAddr::new_truncate(0xFFF0_0000_0000_1000).as_u64(), 0x1000);
+    }
+
+    #[test]
+    fn alignment() {
+        assert_eq!(align_up(0x1001, 0x1000), 0x2000);
+        assert_eq!(align_up(0x2000, 0x1000), 0x2000);
+        assert_eq!(align_down(0x1FFF, 0x1000), 0x1000);
+        assert_eq!(align_up(0, 4096), 0);
+
+        let vaddr = VAddr::new(0x1234_5678);
+        assert_eq!(vaddr.align_down(0x20_0000).as_u64(), 0x1220_0000);
+        assert_eq!(vaddr.align_up(0x20_0000).as_u64(), 0x1240_0000);
+        assert!(!vaddr.is_aligned(4096));
+        assert!(vaddr.align_down(4096).is_aligned(4096));
+
+        // aligning upwards across the non-canonical hole ends up in the higher half
+        assert_eq!(VAddr::new(0x0000_7FFF_FFFF_F000).align_up(1 << 47).as_u64(), 0xFFFF_8000_0000_0000);
+
+        assert_eq!(PAddr::new(0x1234).align_up(0x1000).as_u64(), 0x2000);
+    }
+
+    #[test]
+    fn page_table_indices() {
+        let vaddr = VAddr::new(0xFFFF_8123_4567_89AB);
+        assert_eq!(vaddr.p4_index(), 0x102);
+        assert_eq!(vaddr.p3_index(), 0x08D);
+        assert_eq!(vaddr.p2_index(), 0x02B);
+        assert_eq!(vaddr.p1_index(), 0x078);
+        assert_eq!(vaddr.page_offset(), 0x9AB);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut vaddr = VAddr::new(0x1000);
+        vaddr += 0x234;
+        assert_eq!(vaddr.as_u64(), 0x1234);
+        assert_eq!((vaddr - 0x234).as_u64(), 0x1000);
+        assert_eq!(vaddr - VAddr::new(0x1000), 0x234);
+        assert_eq!((PAddr::new(0x1000) + 0x1000).as_u64(), 0x2000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn arithmetic_into_non_canonical_range_panics() {
+        let _ = VAddr::new(0x0000_7FFF_FFFF_FFFF) + 1;
+    }
+
+    #[test]
+    fn formatting() {
+        let vaddr = VAddr::new(0xFFFF_8000_0000_1000);
+        assert_eq!(format!("{:?}", vaddr), "VAddr(0xFFFF800000001000)");
+        assert_eq!(format!("{:x}", vaddr), "ffff800000001000");
+        assert_eq!(format!("{:#X}", PAddr::new(0xB8000)), "0xB8000");
+        assert_eq!(format!("{:p}", PAddr::new(0xB8000)), "0xb8000");
+        assert_eq!(VAddr::from_ptr(vaddr.as_ptr::<u8>()), vaddr);
+    }
 }
@@ -0,0 +1,232 @@
+/*!
+Parser for the ANSI/VT100 escape sequences understood by the consoles.
+
+Text written to the serial port is interpreted by the terminal on the other end. Screen consoles
+feed the same text through a `Parser` and act on the returned `Action`s, so both outputs can
+share colours and cursor movement.
+
+Only control sequences (`ESC [ params final`) are recognized. Other escape sequences are dropped.
+*/
+
+/// Maximum number of parameters of a control sequence, the remaining ones are ignored.
+pub const MAX_PARAMS: usize = 8;
+
+const ESC: char = '\x1B';
+
+/// What a console should do after a character was fed to the parser.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Print a character.
+    Print(char),
+    /// Execute a C0 control character (`\n`, `\r`, `\t`, backspace, ...).
+    Execute(char),
+    /// Execute a control sequence.
+    Csi(ControlSequence),
+}
+
+/// A complete control sequence `ESC [ params final`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ControlSequence {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    /// Set for sequences with a private marker (`ESC [ ? 25 l`), their meaning is terminal specific.
+    pub private: bool,
+    /// The byte that selects the function, e.g. `m` for SGR.
+    pub final_byte: u8,
+}
+
+impl ControlSequence {
+    /// The parameters, omitted ones are 0.
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    /// Parameter `index`, `default` if it was omitted or is 0.
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&param) if param != 0 => param,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Ground,
+    Escape,
+    /// Reading the parameters of a control sequence.
+    Csi,
+    /// Skipping the rest of a malformed or unsupported control sequence.
+    CsiIgnore,
+}
+
+/// Escape sequence state machine, fed one character at a time.
+pub struct Parser {
+    state: State,
+    sequence: ControlSequence,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            sequence: ControlSequence { params: [0; MAX_PARAMS], len: 0, private: false, final_byte: 0 },
+        }
+    }
+
+    /// Advances the state machine, returns what to do with `c` (if anything).
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\x00'..='\x1F' | '\x7F' => Some(Action::Execute(c)),
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => {
+                self.state = State::Ground;
+                match c {
+                    '[' => {
+                        self.sequence = Parser::new().sequence;
+                        self.state = State::Csi;
+                        None
+                    }
+                    // intermediate bytes, the sequence ends with the next character
+                    ESC | ' '..='/' => {
+                        self.state = State::Escape;
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            State::Csi | State::CsiIgnore => self.advance_csi(c),
+        }
+    }
+
+    fn advance_csi(&mut self, c: char) -> Option<Action> {
+        match c {
+            // an escape aborts the current sequence and starts a new one
+            ESC => {
+                self.state = State::Escape;
+                None
+            }
+            // control characters are executed in the middle of a sequence
+            '\x00'..='\x1F' | '\x7F' => Some(Action::Execute(c)),
+            '0'..='9' if self.state == State::Csi => {
+                let sequence = &mut self.sequence;
+                if sequence.len == 0 {
+                    sequence.len = 1;
+                }
+                if let Some(param) = sequence.params.get_mut(sequence.len - 1) {
+                    *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+                None
+            }
+            ';' if self.state == State::Csi => {
+                // a leading separator means the first parameter was omitted
+                let sequence = &mut self.sequence;
+                sequence.len = (sequence.len.max(1) + 1).min(MAX_PARAMS + 1);
+                None
+            }
+            '<'..='?' if self.state == State::Csi && self.sequence.len == 0 && !self.sequence.private => {
+                self.sequence.private = true;
+                None
+            }
+            '@'..='~' => {
+                let ignore = self.state == State::CsiIgnore;
+                self.state = State::Ground;
+                if ignore {
+                    return None;
+                }
+
+                let mut sequence = self.sequence;
+                sequence.len = sequence.len.min(MAX_PARAMS);
+                sequence.final_byte = c as u8;
+                Some(Action::Csi(sequence))
+            }
+            // intermediate bytes, misplaced markers or anything outside of ASCII
+            _ => {
+                self.state = State::CsiIgnore;
+                None
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn parse(input: &str) -> Vec<Action> {
+        let mut parser = Parser::new();
+        input.chars().filter_map(|c| parser.advance(c)).collect()
+    }
+
+    fn csi(input: &str) -> ControlSequence {
+        match parse(input)[..] {
+            [Action::Csi(sequence)] => sequence,
+            ref actions => panic!("expected a single control sequence, got {:?}", actions),
+        }
+    }
+
+    #[test]
+    fn text_and_controls() {
+        assert_eq!(
+            parse("a\tb\r\n\x08"),
+            [
+                Action::Print('a'),
+                Action::Execute('\t'),
+                Action::Print('b'),
+                Action::Execute('\r'),
+                Action::Execute('\n'),
+                Action::Execute('\x08'),
+            ]
+        );
+        assert_eq!(parse("ä"), [Action::Print('ä')]);
+    }
+
+    #[test]
+    fn parameters() {
+        let sequence = csi("\x1B[1;31m");
+        assert_eq!(sequence.final_byte, b'm');
+        assert_eq!(sequence.params(), &[1, 31]);
+        assert!(!sequence.private);
+
+        let sequence = csi("\x1B[H");
+        assert_eq!(sequence.params(), &[]);
+        assert_eq!((sequence.param(0, 1), sequence.param(1, 1)), (1, 1));
+
+        // omitted and zero parameters use the default
+        let sequence = csi("\x1B[;5H");
+        assert_eq!(sequence.params(), &[0, 5]);
+        assert_eq!((sequence.param(0, 1), sequence.param(1, 1)), (1, 5));
+
+        assert_eq!(csi("\x1B[99999A").params(), &[u16::MAX]);
+        assert_eq!(csi("\x1B[1;2;3;4;5;6;7;8;9;10m").params(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(csi("\x1B[?25l").private);
+    }
+
+    #[test]
+    fn malformed_sequences() {
+        // unsupported escape sequences, intermediate bytes and misplaced markers are dropped
+        assert_eq!(parse("\x1B(Ba\x1B7"), [Action::Print('a')]);
+        assert_eq!(parse("\x1B[1 qa"), [Action::Print('a')]);
+        assert_eq!(parse("\x1B[1?ma"), [Action::Print('a')]);
+
+        // an escape restarts the sequence, control characters are still executed
+        assert_eq!(csi("\x1B[12\x1B[3J").params(), &[3]);
+        assert_eq!(parse("\x1B[2\nK"), [Action::Execute('\n'), Action::Csi(csi("\x1B[2K"))]);
+    }
+}
@@ -0,0 +1,329 @@
+/*!
+Stack unwinding through the frame pointer chain.
+
+Requires code built with `-C force-frame-pointers=yes` (`"frame-pointer": "always"` in the target
+specification). Every function prologue then pushes the caller's `rbp` and points `rbp` at it:
+
+```text
+[rbp + 8]  return address into the caller
+[rbp]      rbp of the caller
+```
+
+The walk stops at a null frame pointer, so entry points should clear `rbp` before calling into Rust.
+*/
+
+#[cfg(feature = "instructions")]
+use core::arch::asm;
+use core::fmt;
+
+use crate::elf::SymbolTable;
+
+/// Maximum number of frames recorded in a backtrace.
+pub const MAX_FRAMES: usize = 32;
+
+/// The address range `[bottom, top)` of a stack, frames outside of it are never read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StackBounds {
+    pub bottom: u64,
+    pub top: u64,
+}
+
+impl StackBounds {
+    #[inline]
+    pub const fn new(bottom: u64, top: u64) -> StackBounds {
+        StackBounds { bottom, top }
+    }
+
+    /// Whether a stack frame (saved `rbp` and return address) at `frame_pointer` lies inside the stack.
+    #[inline]
+    pub fn contains_frame(&self, frame_pointer: u64) -> bool {
+        frame_pointer.is_multiple_of(8)
+            && frame_pointer >= self.bottom
+            && frame_pointer.checked_add(16).is_some_and(|end| end <= self.top)
+    }
+}
+
+/// Reads the frame pointer of the current function.
+#[cfg(feature = "instructions")]
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)); }
+    rbp
+}
+
+/// The return addresses of a call stack, innermost first.
+#[derive(Clone)]
+pub struct Backtrace {
+    return_addresses: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Records the callers of the current function.
+    #[cfg(feature = "instructions")]
+    #[inline(always)]
+    pub fn capture(bounds: StackBounds) -> Backtrace {
+        unsafe { Backtrace::from_frame_pointer(frame_pointer(), bounds) }
+    }
+
+    /// Walks the frame pointer chain starting at `frame_pointer`.
+    ///
+    /// Stops at the first frame outside of `bounds`, at a frame that is not above the previous one
+    /// (the stack grows down, so callers have higher addresses) and after `MAX_FRAMES` frames.
+    ///
+    /// # Safety
+    ///
+    /// All of `bounds` has to be readable memory.
+    pub unsafe fn from_frame_pointer(mut frame_pointer: u64, bounds: StackBounds) -> Backtrace {
+        let mut backtrace = Backtrace { return_addresses: [0; MAX_FRAMES], len: 0 };
+
+        while backtrace.len < MAX_FRAMES && bounds.contains_frame(frame_pointer) {
+            let frame = frame_pointer as *const u64;
+            let (caller_frame_pointer, return_address) = (frame.read(), frame.add(1).read());
+            if return_address == 0 {
+                break;
+            }
+
+            backtrace.return_addresses[backtrace.len] = return_address;
+            backtrace.len += 1;
+
+            if caller_frame_pointer <= frame_pointer {
+                break;
+            }
+            frame_pointer = caller_frame_pointer;
+        }
+
+        backtrace
+    }
+
+    pub fn return_addresses(&self) -> &[u64] {
+        &self.return_addresses[..self.len]
+    }
+
+    /// One printable `#N 0xADDR function+offset` line per frame.
+    ///
+    /// Without symbols (or if an address is not covered by a function) only the address is printed.
+    pub fn lines<'a>(&'a self, symbols: Option<&'a SymbolTable<'a>>) -> impl Iterator<Item = BacktraceLine<'a>> + 'a {
+        self.return_addresses()
+            .iter()
+            .enumerate()
+            .map(move |(index, &addr)| BacktraceLine::new(index, addr, symbols))
+    }
+}
+
+/// A single frame of a printed backtrace.
+pub struct BacktraceLine<'a> {
+    pub index: usize,
+    pub addr: u64,
+    /// Name and offset of the function the return address points into.
+    pub symbol: Option<(&'a str, u64)>,
+}
+
+impl<'a> BacktraceLine<'a> {
+    /// Resolves the return address `addr` of frame `index` against `symbols`.
+    pub fn new(index: usize, addr: u64, symbols: Option<&SymbolTable<'a>>) -> BacktraceLine<'a> {
+        // the return address might already belong to the next function if the call was the last
+        // instruction (e.g. a call to a function that never returns), so look up the call itself
+        let symbol = symbols
+            .and_then(|symbols| symbols.lookup(addr.checked_sub(1)?))
+            .map(|(name, offset)| (name, offset + 1));
+        BacktraceLine { index, addr, symbol }
+    }
+}
+
+impl fmt::Display for BacktraceLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:<2} 0x{:016X}", self.index, self.addr)?;
+        if let Some((name, offset)) = self.symbol {
+            write!(f, " {}+0x{:X}", Demangle(name), offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints a Rust symbol name in its readable form (`core::panicking::panic`).
+///
+/// Only the legacy mangling scheme (`_ZN...E`) is decoded, the hash suffix is omitted.
+/// Other names are printed unchanged.
+pub struct Demangle<'a>(pub &'a str);
+
+impl Demangle<'_> {
+    /// The path segments of a legacy mangled name, `None` if it is not one.
+    fn segments(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        let mut rest = self.0.strip_prefix("_ZN")?;
+        // validate the whole name first so that nothing is printed for malformed names
+        let mut count = 0;
+        while !rest.starts_with('E') {
+            let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+            let len: usize = rest[..digits].parse().ok()?;
+            rest = rest.get(digits..)?.get(len..)?;
+            count += 1;
+        }
+        if count == 0 || rest != "E" {
+            return None;
+        }
+
+        let mut rest = &self.0[3..];
+        Some((0..count).map(move |_| {
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap();
+            let len: usize = rest[..digits].parse().unwrap();
+            let segment = &rest[digits..digits + len];
+            rest = &rest[digits + len..];
+            segment
+        }))
+    }
+}
+
+/// Whether `segment` is the `h` followed by 16 hex digits hash that ends every legacy name.
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17
+        && segment.starts_with('h')
+        && segment[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Writes a path segment and replaces the `$...$` escapes and `..` (for `::`) of the legacy scheme.
+fn write_segment(f: &mut fmt::Formatter, segment: &str) -> fmt::Result {
+    // segments that start with an escape get an extra underscore
+    let mut rest = if segment.starts_with("_$") { &segment[1..] } else { segment };
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some((escape, after)) = rest.strip_prefix('$').and_then(|after| after.split_once('$')) {
+            match escape {
+                "SP" => f.write_str("@")?,
+                "BP" => f.write_str("*")?,
+                "RF" => f.write_str("&")?,
+                "LT" => f.write_str("<")?,
+                "GT" => f.write_str(">")?,
+                "LP" => f.write_str("(")?,
+                "RP" => f.write_str(")")?,
+                "C" => f.write_str(",")?,
+                _ => {
+                    let c = escape
+                        .strip_prefix('u')
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32);
+                    match c {
+                        Some(c) => write!(f, "{}", c)?,
+                        None => write!(f, "${}$", escape)?,
+                    }
+                }
+            }
+            rest = after;
+        } else {
+            // names are not necessarily ASCII, skip the whole first character
+            let first = rest.chars().next().map_or(1, char::len_utf8);
+            let end = rest[first..].find(['.', '$']).map_or(rest.len(), |end| end + first);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(segments) = self.segments() else {
+            return f.write_str(self.0);
+        };
+
+        let mut segments = segments.peekable();
+        let mut first = true;
+        while let Some(segment) = segments.next() {
+            if segments.peek().is_none() && is_hash(segment) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            write_segment(f, segment)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::format;
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn demangle() {
+        assert_eq!(format!("{}", Demangle("_ZN4core9panicking5panic17h0123456789abcdefE")), "core::panicking::panic");
+        assert_eq!(
+            format!("{}", Demangle("_ZN56_$LT$x86_64..addr..VAddr$u20$as$u20$core..fmt..Debug$GT$3fmt17h0123456789abcdefE")),
+            "<x86_64::addr::VAddr as core::fmt::Debug>::fmt"
+        );
+        assert_eq!(format!("{}", Demangle("_ZN7bean_os6_start17h0123456789abcdefE")), "bean_os::_start");
+        assert_eq!(format!("{}", Demangle("_ZN3foo3barE")), "foo::bar");
+        assert_eq!(format!("{}", Demangle("_ZN2éE")), "é");
+        assert_eq!(format!("{}", Demangle("_ZN6é..éE")), "é::é");
+
+        // not mangled or malformed
+        assert_eq!(format!("{}", Demangle("stage_4")), "stage_4");
+        assert_eq!(format!("{}", Demangle("_ZN3fooE_")), "_ZN3fooE_");
+        assert_eq!(format!("{}", Demangle("_ZN9fooE")), "_ZN9fooE");
+    }
+
+    #[test]
+    fn stack_bounds() {
+        let bounds = StackBounds::new(0x1000, 0x2000);
+        assert!(bounds.contains_frame(0x1000));
+        assert!(bounds.contains_frame(0x1FF0));
+        assert!(!bounds.contains_frame(0x1FF8));
+        assert!(!bounds.contains_frame(0x1004));
+        assert!(!bounds.contains_frame(0));
+        assert!(!bounds.contains_frame(u64::MAX - 7));
+    }
+
+    /// Writes a frame (saved frame pointer and return address) at `stack[index]`.
+    fn push_frame(stack: &mut [u64], index: usize, caller_index: Option<usize>, return_address: u64) {
+        let base = stack.as_ptr() as u64;
+        stack[index] = caller_index.map_or(0, |caller_index| base + caller_index as u64 * 8);
+        stack[index + 1] = return_address;
+    }
+
+    #[test]
+    fn walk_frames() {
+        // three frames, the outermost one terminates the chain with a null frame pointer
+        let mut stack = [0_u64; 16];
+        let base = stack.as_ptr() as u64;
+        push_frame(&mut stack, 2, Some(6), 0x1111);
+        push_frame(&mut stack, 6, Some(12), 0x2222);
+        push_frame(&mut stack, 12, None, 0x3333);
+
+        let bounds = StackBounds::new(base, base + 16 * 8);
+        let backtrace = unsafe { Backtrace::from_frame_pointer(base + 2 * 8, bounds) };
+        assert_eq!(backtrace.return_addresses(), &[0x1111, 0x2222, 0x3333]);
+
+        // a chain outside of the stack or pointing back down ends the walk
+        let backtrace = unsafe { Backtrace::from_frame_pointer(base + 2 * 8, StackBounds::new(base, base + 8 * 8)) };
+        assert_eq!(backtrace.return_addresses(), &[0x1111, 0x2222]);
+
+        push_frame(&mut stack, 6, Some(0), 0x2222);
+        let backtrace = unsafe { Backtrace::from_frame_pointer(base + 2 * 8, bounds) };
+        assert_eq!(backtrace.return_addresses(), &[0x1111, 0x2222]);
+
+        let backtrace = unsafe { Backtrace::from_frame_pointer(0, bounds) };
+        assert!(backtrace.return_addresses().is_empty());
+    }
+
+    #[test]
+    fn print_lines() {
+        let mut stack = [0_u64; 4];
+        let base = stack.as_ptr() as u64;
+        push_frame(&mut stack, 0, None, 0x1234);
+
+        let backtrace = unsafe { Backtrace::from_frame_pointer(base, StackBounds::new(base, base + 32)) };
+        let lines: Vec<_> = backtrace.lines(None).map(|line| format!("{}", line)).collect();
+        assert_eq!(lines, ["#0  0x0000000000001234"]);
+    }
+}
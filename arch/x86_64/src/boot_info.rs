@@ -0,0 +1,118 @@
+use core::slice;
+
+use crate::addr::VAddr;
+use crate::backtrace::StackBounds;
+use crate::cpuid::CpuFeatures;
+use crate::elf::SymbolTable;
+
+/// Information passed from the bootloader to the kernel entry point.
+///
+/// All addresses are virtual addresses inside the mapping of physical memory at
+/// `physical_memory_offset`, so they stay valid without the identity mapping.
+#[repr(C)]
+pub struct BootInfo {
+    /// Virtual address at which all physical memory is mapped.
+    pub physical_memory_offset: u64,
+    /// Address of the memory region array.
+    pub memory_regions_addr: u64,
+    /// Number of entries in the memory region array.
+    pub memory_regions_len: u64,
+    /// Start address of the initial ramdisk (cpio newc archive).
+    pub initrd_addr: u64,
+    /// Size of the initial ramdisk in bytes. Zero if no ramdisk was loaded.
+    pub initrd_len: u64,
+    /// Address of the kernel command line (ASCII, not null-terminated).
+    pub cmdline_addr: u64,
+    /// Length of the kernel command line in bytes.
+    pub cmdline_len: u64,
+    /// Processor features detected by the bootloader.
+    pub cpu_features: CpuFeatures,
+    /// Whether the bootloader enabled no-execute pages (EFER.NXE).
+    pub nx_enabled: bool,
+    /// Frequency of the time stamp counter in Hz. Zero if it could not be determined.
+    pub tsc_frequency: u64,
+    /// Lowest address of the stack the kernel entry point is called on.
+    pub kernel_stack_addr: u64,
+    /// Size of the kernel stack in bytes.
+    pub kernel_stack_len: u64,
+    /// Address of the kernel's `.symtab` section.
+    pub kernel_symtab_addr: u64,
+    /// Size of the kernel symbol table in bytes. Zero if the kernel was stripped.
+    pub kernel_symtab_len: u64,
+    /// Address of the string table linked to the kernel symbol table.
+    pub kernel_strtab_addr: u64,
+    /// Size of the kernel string table in bytes.
+    pub kernel_strtab_len: u64,
+}
+
+impl BootInfo {
+    /// Virtual address at which all physical memory is mapped.
+    pub fn physical_memory_offset(&self) -> VAddr {
+        VAddr::new(self.physical_memory_offset)
+    }
+
+    /// The physical memory map (derived from the e820 map).
+    pub fn memory_regions(&self) -> &[MemoryRegion] {
+        let ptr = self.memory_regions_addr as *const MemoryRegion;
+        unsafe { slice::from_raw_parts(ptr, self.memory_regions_len as usize) }
+    }
+
+    /// The initial ramdisk, if one was loaded.
+    pub fn initrd(&self) -> Option<&[u8]> {
+        if self.initrd_len == 0 {
+            return None;
+        }
+
+        let ptr = self.initrd_addr as *const u8;
+        Some(unsafe { slice::from_raw_parts(ptr, self.initrd_len as usize) })
+    }
+
+    /// The kernel command line selected or edited in the boot menu.
+    pub fn cmdline(&self) -> &str {
+        let ptr = self.cmdline_addr as *const u8;
+        let bytes = unsafe { slice::from_raw_parts(ptr, self.cmdline_len as usize) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+
+    /// The bounds of the initial kernel stack, used to walk it for backtraces.
+    pub fn kernel_stack(&self) -> StackBounds {
+        StackBounds::new(self.kernel_stack_addr, self.kernel_stack_addr + self.kernel_stack_len)
+    }
+
+    /// The symbol table of the kernel, if it was not stripped.
+    pub fn kernel_symbols(&self) -> Option<SymbolTable<'static>> {
+        if self.kernel_symtab_len == 0 {
+            return None;
+        }
+
+        let (symtab, strtab) = unsafe {
+            (
+                slice::from_raw_parts(self.kernel_symtab_addr as *const u8, self.kernel_symtab_len as usize),
+                slice::from_raw_parts(self.kernel_strtab_addr as *const u8, self.kernel_strtab_len as usize),
+            )
+        };
+        Some(SymbolTable::new(symtab, strtab))
+    }
+}
+
+/// A range of physical memory `[start, end)`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryRegionKind,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MemoryRegionKind {
+    /// Free memory.
+    Usable,
+    /// Memory reserved by the firmware or hardware.
+    Reserved,
+    /// Memory used by the bootloader, the kernel segments, page tables and the boot info.
+    Bootloader,
+    /// Memory containing the initial ramdisk.
+    Initrd,
+}
@@ -0,0 +1,206 @@
+#[cfg(feature = "instructions")]
+use core::arch::asm;
+use core::fmt;
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// Processor features reported by CPUID.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct CpuFeatures: u64 {
+        // leaf 0x1, edx
+        const FPU           = 1_u64 << 0;
+        const TSC           = 1_u64 << 1;
+        const MSR           = 1_u64 << 2;
+        const PAE           = 1_u64 << 3;
+        const APIC          = 1_u64 << 4;
+        const PGE           = 1_u64 << 5;
+        const PAT           = 1_u64 << 6;
+        const SSE           = 1_u64 << 7;
+        const SSE2          = 1_u64 << 8;
+        // leaf 0x1, ecx
+        const PCID          = 1_u64 << 16;
+        const X2APIC        = 1_u64 << 17;
+        const TSC_DEADLINE  = 1_u64 << 18;
+        const XSAVE         = 1_u64 << 19;
+        const OSXSAVE       = 1_u64 << 20;
+        const RDRAND        = 1_u64 << 21;
+        const HYPERVISOR    = 1_u64 << 22;
+        // leaf 0x7, ebx
+        const FSGSBASE      = 1_u64 << 32;
+        const SMEP          = 1_u64 << 33;
+        const INVPCID       = 1_u64 << 34;
+        const SMAP          = 1_u64 << 35;
+        // leaf 0x80000001, edx
+        const NX            = 1_u64 << 48;
+        const PDPE1GB       = 1_u64 << 49;
+        const RDTSCP        = 1_u64 << 50;
+        const LONG_MODE     = 1_u64 << 51;
+        // leaf 0x80000007, edx
+        const INVARIANT_TSC = 1_u64 << 52;
+    }
+}
+
+/// Register values returned by the CPUID instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Executes CPUID for the given leaf and subleaf.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        // rbx is reserved by LLVM, so it has to be saved manually
+        asm!(
+            "mov {tmp:r}, rbx",
+            "cpuid",
+            "xchg {tmp:r}, rbx",
+            tmp = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+/// Initial APIC ID of the current processor, identifies the CPU in SMP systems.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn apic_id() -> u8 {
+    (cpuid(1, 0).ebx >> 24) as u8
+}
+
+/// Decoded processor identification and feature flags.
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    pub features: CpuFeatures,
+}
+
+impl CpuInfo {
+    /// Queries all supported CPUID leaves of the current processor.
+    #[cfg(feature = "instructions")]
+    pub fn read() -> CpuInfo {
+        let leaf_0 = cpuid(0, 0);
+        let max_leaf = leaf_0.eax;
+
+        let mut vendor = [0_u8; 12];
+        vendor[0..4].copy_from_slice(&leaf_0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf_0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf_0.ecx.to_le_bytes());
+
+        let leaf_1 = cpuid(1, 0);
+        let (family, model, stepping) = decode_signature(leaf_1.eax);
+
+        let mut features = CpuFeatures::empty();
+        let mut set = |feature: CpuFeatures, register: u32, bit: u32| {
+            features.set(feature, register & (1 << bit) != 0);
+        };
+
+        set(CpuFeatures::FPU, leaf_1.edx, 0);
+        set(CpuFeatures::TSC, leaf_1.edx, 4);
+        set(CpuFeatures::MSR, leaf_1.edx, 5);
+        set(CpuFeatures::PAE, leaf_1.edx, 6);
+        set(CpuFeatures::APIC, leaf_1.edx, 9);
+        set(CpuFeatures::PGE, leaf_1.edx, 13);
+        set(CpuFeatures::PAT, leaf_1.edx, 16);
+        set(CpuFeatures::SSE, leaf_1.edx, 25);
+        set(CpuFeatures::SSE2, leaf_1.edx, 26);
+
+        set(CpuFeatures::PCID, leaf_1.ecx, 17);
+        set(CpuFeatures::X2APIC, leaf_1.ecx, 21);
+        set(CpuFeatures::TSC_DEADLINE, leaf_1.ecx, 24);
+        set(CpuFeatures::XSAVE, leaf_1.ecx, 26);
+        set(CpuFeatures::OSXSAVE, leaf_1.ecx, 27);
+        set(CpuFeatures::RDRAND, leaf_1.ecx, 30);
+        set(CpuFeatures::HYPERVISOR, leaf_1.ecx, 31);
+
+        if max_leaf >= 7 {
+            let leaf_7 = cpuid(7, 0);
+            set(CpuFeatures::FSGSBASE, leaf_7.ebx, 0);
+            set(CpuFeatures::SMEP, leaf_7.ebx, 7);
+            set(CpuFeatures::INVPCID, leaf_7.ebx, 10);
+            set(CpuFeatures::SMAP, leaf_7.ebx, 20);
+        }
+
+        let max_extended_leaf = cpuid(0x8000_0000, 0).eax;
+
+        if max_extended_leaf >= 0x8000_0001 {
+            let leaf = cpuid(0x8000_0001, 0);
+            set(CpuFeatures::NX, leaf.edx, 20);
+            set(CpuFeatures::PDPE1GB, leaf.edx, 26);
+            set(CpuFeatures::RDTSCP, leaf.edx, 27);
+            set(CpuFeatures::LONG_MODE, leaf.edx, 29);
+        }
+
+        // the brand string is spread over three leaves
+        let mut brand = [0_u8; 48];
+        if max_extended_leaf >= 0x8000_0004 {
+            for (i, chunk) in brand.chunks_exact_mut(16).enumerate() {
+                let leaf = cpuid(0x8000_0002 + i as u32, 0);
+                chunk[0..4].copy_from_slice(&leaf.eax.to_le_bytes());
+                chunk[4..8].copy_from_slice(&leaf.ebx.to_le_bytes());
+                chunk[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+                chunk[12..16].copy_from_slice(&leaf.edx.to_le_bytes());
+            }
+        }
+
+        if max_extended_leaf >= 0x8000_0007 {
+            set(CpuFeatures::INVARIANT_TSC, cpuid(0x8000_0007, 0).edx, 8);
+        }
+
+        CpuInfo { vendor, brand, family, model, stepping, max_leaf, max_extended_leaf, features }
+    }
+
+    /// Vendor identification string, e.g. "GenuineIntel" or "AuthenticAMD".
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("")
+    }
+
+    /// Processor brand string, empty if not supported.
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&c| c == 0).unwrap_or(self.brand.len());
+        core::str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "CPU: {} ({})", self.brand(), self.vendor())?;
+        writeln!(f, "     Family 0x{:X}, Model 0x{:X}, Stepping 0x{:X}", self.family, self.model, self.stepping)?;
+        write!(f, "     Features: {:?}", self.features)
+    }
+}
+
+/// Splits the processor signature (leaf 0x1, eax) into family, model and stepping.
+#[cfg(feature = "instructions")]
+fn decode_signature(signature: u32) -> (u32, u32, u32) {
+    let stepping = signature & 0xF;
+    let base_model = (signature >> 4) & 0xF;
+    let base_family = (signature >> 8) & 0xF;
+    let extended_model = (signature >> 16) & 0xF;
+    let extended_family = (signature >> 20) & 0xFF;
+
+    let family = if base_family == 0xF { base_family + extended_family } else { base_family };
+    let model = if base_family == 0x6 || base_family == 0xF {
+        (extended_model << 4) + base_model
+    } else {
+        base_model
+    };
+
+    (family, model, stepping)
+}
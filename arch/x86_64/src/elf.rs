@@ -1,5 +1,7 @@
 use core::slice;
 
+use log::info;
+
 use crate::read_from_packed;
 
 #[repr(C, packed)]
@@ -28,6 +30,81 @@ pub struct SectionHeader {
     pub entsize: u64,
 }
 
+/// Section type of a symbol table.
+pub const SHT_SYMTAB: u32 = 2;
+
+/// Symbol type of a function.
+pub const STT_FUNC: u8 = 2;
+
+#[repr(C, packed)]
+pub struct Symbol {
+    pub name: u32,
+    pub info: u8,
+    pub other: u8,
+    pub shndx: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+impl Symbol {
+    pub fn symbol_type(&self) -> u8 {
+        self.info & 0xF
+    }
+}
+
+/// A `.symtab` section together with its string table, used to symbolize addresses.
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    symtab: &'a [u8],
+    strtab: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Wraps the raw contents of a symbol table section and its linked string table.
+    ///
+    /// Trailing bytes that do not form a complete symbol are ignored.
+    pub fn new(symtab: &'a [u8], strtab: &'a [u8]) -> SymbolTable<'a> {
+        let len = symtab.len() - symtab.len() % core::mem::size_of::<Symbol>();
+        SymbolTable { symtab: &symtab[..len], strtab }
+    }
+
+    /// The raw symbol table section.
+    pub fn symtab(&self) -> &'a [u8] {
+        self.symtab
+    }
+
+    /// The raw string table section.
+    pub fn strtab(&self) -> &'a [u8] {
+        self.strtab
+    }
+
+    pub fn symbols(&self) -> &'a [Symbol] {
+        let ptr = self.symtab.as_ptr() as *const Symbol;
+        unsafe { slice::from_raw_parts(ptr, self.symtab.len() / core::mem::size_of::<Symbol>()) }
+    }
+
+    /// The name of `symbol`, `None` if it is missing or not valid UTF-8.
+    pub fn name(&self, symbol: &Symbol) -> Option<&'a str> {
+        let start = read_from_packed!(symbol.name) as usize;
+        let bytes = self.strtab.get(start..)?;
+        let len = bytes.iter().position(|&c| c == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok().filter(|name| !name.is_empty())
+    }
+
+    /// Finds the function that contains `addr`, returns its name and the offset of `addr` into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        self.symbols()
+            .iter()
+            .filter(|symbol| symbol.symbol_type() == STT_FUNC)
+            .filter(|symbol| {
+                let start = read_from_packed!(symbol.value);
+                addr >= start && addr - start < read_from_packed!(symbol.size)
+            })
+            .max_by_key(|symbol| read_from_packed!(symbol.value))
+            .and_then(|symbol| Some((self.name(symbol)?, addr - read_from_packed!(symbol.value))))
+    }
+}
+
 pub struct ElfFile {
     pub bytes: &'static [u8],
     pub entry_point: u64,
@@ -36,15 +113,19 @@ pub struct ElfFile {
 }
 
 impl ElfFile {
-    pub fn from(bytes: &'static [u8]) -> ElfFile {
+    pub fn from(bytes: &'static [u8]) -> Result<ElfFile, &'static str> {
+        if bytes.len() < 64 {
+            return Err("ELF file is smaller than its header");
+        }
+
         let header = &bytes[..64];
 
         // sanity checks
-        assert_eq!(header[..0x4], [0x7F_u8, 0x45, 0x4C, 0x46], "Wrong magic number in ELF header");
-        assert_eq!(header[0x4], 2, "64-bit ELF file required");
-        assert_eq!(header[0x5], 1, "ELF file needs to be little endian");
-        assert_eq!(header[0x7], 0, "Invalid target ABI");
-        assert_eq!(header[0x10..0x12], [0x02_u8, 0x00], "ELF file needs to be an executable");
+        if header[..0x4] != [0x7F_u8, 0x45, 0x4C, 0x46] { return Err("Wrong magic number in ELF header"); }
+        if header[0x4] != 2 { return Err("64-bit ELF file required"); }
+        if header[0x5] != 1 { return Err("ELF file needs to be little endian"); }
+        if header[0x7] != 0 { return Err("Invalid target ABI"); }
+        if header[0x10..0x12] != [0x02_u8, 0x00] { return Err("ELF file needs to be an executable"); }
 
         debug_assert_eq!(56, core::mem::size_of::<ProgramHeader>());
         debug_assert_eq!(64, core::mem::size_of::<SectionHeader>());
@@ -55,6 +136,10 @@ impl ElfFile {
             let prog_headers_offset = u64::from_le_bytes(header[0x20..0x28].try_into().unwrap()) as usize;
             let prog_headers_num = u16::from_le_bytes(header[0x38..0x3A].try_into().unwrap()) as usize;
 
+            if !Self::table_in_bounds::<ProgramHeader>(bytes, prog_headers_offset, prog_headers_num) {
+                return Err("Program header table lies outside of the ELF file");
+            }
+
             unsafe {
                 let prog_headers_ptr = 
                     bytes.as_ptr().add(prog_headers_offset) as *const ProgramHeader;
@@ -66,6 +151,10 @@ impl ElfFile {
             let sect_headers_offset = u64::from_le_bytes(header[0x28..0x30].try_into().unwrap()) as usize;
             let sect_headers_num = u16::from_le_bytes(header[0x3C..0x3E].try_into().unwrap()) as usize;
 
+            if !Self::table_in_bounds::<SectionHeader>(bytes, sect_headers_offset, sect_headers_num) {
+                return Err("Section header table lies outside of the ELF file");
+            }
+
             unsafe {
                 let sect_headers_ptr = 
                     bytes.as_ptr().add(sect_headers_offset) as *const SectionHeader;
@@ -73,17 +162,137 @@ impl ElfFile {
             }
         };
 
-        ElfFile { bytes, entry_point, prog_headers, sect_headers }
+        Ok(ElfFile { bytes, entry_point, prog_headers, sect_headers })
+    }
+
+    fn table_in_bounds<T>(bytes: &[u8], offset: usize, num: usize) -> bool {
+        num.checked_mul(core::mem::size_of::<T>())
+            .and_then(|size| size.checked_add(offset))
+            .is_some_and(|end| end <= bytes.len())
+    }
+
+    /// The contents of a section, `None` if it has no data in the file or lies outside of it.
+    pub fn section_data(&self, header: &SectionHeader) -> Option<&'static [u8]> {
+        if read_from_packed!(header.sect_type) == 8 {
+            // SHT_NOBITS (.bss)
+            return None;
+        }
+        let offset = read_from_packed!(header.offset) as usize;
+        let size = read_from_packed!(header.size) as usize;
+        self.bytes.get(offset..offset.checked_add(size)?)
+    }
+
+    /// The first symbol table of the file, `None` if it was stripped.
+    pub fn symbol_table(&self) -> Option<SymbolTable<'static>> {
+        let symtab = self.sect_headers.iter().find(|header| read_from_packed!(header.sect_type) == SHT_SYMTAB)?;
+        let strtab = self.sect_headers.get(read_from_packed!(symtab.link) as usize)?;
+        Some(SymbolTable::new(self.section_data(symtab)?, self.section_data(strtab)?))
     }
 
     pub fn print_prog_header(&self) {
-        println!("Program Headers:");
-        println!("  Type           Offset             VirtAddr           PhysAddr");
-        println!("                 FileSiz            MemSiz              Flags  Align");
+        info!("Program Headers:");
+        info!("  Type           Offset             VirtAddr           PhysAddr");
+        info!("                 FileSiz            MemSiz              Flags  Align");
 
         for header in self.prog_headers {
-            println!("  {:14} 0x{:016X} 0x{:016X} 0x{:016X}", read_from_packed!(header.prog_type), read_from_packed!(header.offset), read_from_packed!(header.vaddr), read_from_packed!(header.paddr));
-            println!("  {:14} 0x{:016X} 0x{:016X}  {}  0x{:X}", "", read_from_packed!(header.filesz), read_from_packed!(header.memsz), read_from_packed!(header.flags), read_from_packed!(header.align));
+            info!("  {:14} 0x{:016X} 0x{:016X} 0x{:016X}", read_from_packed!(header.prog_type), read_from_packed!(header.offset), read_from_packed!(header.vaddr), read_from_packed!(header.paddr));
+            info!("  {:14} 0x{:016X} 0x{:016X}  {}  0x{:X}", "", read_from_packed!(header.filesz), read_from_packed!(header.memsz), read_from_packed!(header.flags), read_from_packed!(header.align));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn symbol(name: u32, info: u8, value: u64, size: u64) -> [u8; 24] {
+        let mut bytes = [0; 24];
+        bytes[0..4].copy_from_slice(&name.to_le_bytes());
+        bytes[4] = info;
+        bytes[8..16].copy_from_slice(&value.to_le_bytes());
+        bytes[16..24].copy_from_slice(&size.to_le_bytes());
+        bytes
+    }
+
+    static FIXTURE: &[u8] = include_bytes!("../testdata/fixture.elf");
+
+    #[test]
+    fn parse_executable() {
+        let elf = ElfFile::from(FIXTURE).unwrap();
+        assert_eq!(elf.entry_point, 0x20_1200);
+
+        let load_segments: Vec<_> = elf.prog_headers.iter().filter(|header| header.prog_type == 1).collect();
+        assert_eq!(load_segments.len(), 3);
+        // .text is the second segment
+        assert_eq!(read_from_packed!(load_segments[1].vaddr), 0x20_1200);
+        assert_eq!(read_from_packed!(load_segments[1].flags), 0b101);
+        // .bss only exists in memory
+        assert_eq!(read_from_packed!(load_segments[2].filesz), 0);
+        assert_eq!(read_from_packed!(load_segments[2].memsz), 8);
+
+        let bss = elf.sect_headers.iter().find(|header| read_from_packed!(header.sect_type) == 8).unwrap();
+        assert!(elf.section_data(bss).is_none());
+    }
+
+    #[test]
+    fn executable_symbols() {
+        let symbols = ElfFile::from(FIXTURE).unwrap().symbol_table().unwrap();
+        assert_eq!(symbols.lookup(0x20_1200), Some(("_start", 0)));
+        assert_eq!(symbols.lookup(0x20_1221), Some(("_start", 0x21)));
+        assert_eq!(symbols.lookup(0x20_1232), Some(("add", 2)));
+        // padding between the functions
+        assert_eq!(symbols.lookup(0x20_1222), None);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let copy = |modify: fn(&mut Vec<u8>)| -> &'static [u8] {
+            let mut bytes = FIXTURE.to_vec();
+            modify(&mut bytes);
+            bytes.leak()
+        };
+
+        assert!(ElfFile::from(&FIXTURE[..63]).is_err());
+        assert!(ElfFile::from(copy(|bytes| bytes[0] = 0)).is_err());
+        // 32-bit
+        assert!(ElfFile::from(copy(|bytes| bytes[0x4] = 1)).is_err());
+        // shared object
+        assert!(ElfFile::from(copy(|bytes| bytes[0x10] = 3)).is_err());
+        // program header table beyond the end of the file
+        assert!(ElfFile::from(copy(|bytes| bytes[0x21] = 0xFF)).is_err());
+        // section header table cut off
+        assert!(ElfFile::from(&FIXTURE[..FIXTURE.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn symbol_lookup() {
+        let strtab = b"\0outer\0inner\0data\0";
+        let symtab: Vec<u8> = [
+            symbol(0, 0, 0, 0),
+            symbol(1, STT_FUNC, 0x1000, 0x100),
+            // nested symbols (e.g. aliases) resolve to the closest start address
+            symbol(7, STT_FUNC, 0x1080, 0x10),
+            // only functions are considered
+            symbol(13, 1, 0x2000, 0x100),
+        ]
+        .concat();
+
+        // a trailing partial entry is ignored
+        let mut padded = symtab.clone();
+        padded.push(0xFF);
+        let symbols = SymbolTable::new(&padded, strtab);
+        assert_eq!(symbols.symbols().len(), 4);
+        assert_eq!(symbols.symtab(), &symtab[..]);
+
+        assert_eq!(symbols.lookup(0x1000), Some(("outer", 0)));
+        assert_eq!(symbols.lookup(0x1084), Some(("inner", 4)));
+        assert_eq!(symbols.lookup(0x10FF), Some(("outer", 0xFF)));
+        assert_eq!(symbols.lookup(0x1100), None);
+        assert_eq!(symbols.lookup(0x2000), None);
+        assert_eq!(symbols.name(&symbols.symbols()[0]), None);
+    }
+}
@@ -1,33 +1,292 @@
+use crate::addr::PAddr;
 use crate::paging::{PageSize, Page4KiB};
 
 use core::marker::PhantomData;
 use core::slice;
-use core::ops::AddAssign;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
 
 /// A physical memory frame.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Frame<S: PageSize = Page4KiB> {
-    pub start_addr: u64,
+    start_addr: PAddr,
     size: PhantomData<S>,
 }
 
 impl<S: PageSize> Frame<S> {
-    const FRAME_MASK: u64 = !(S::SIZE - 1);
+    pub const SIZE: u64 = S::SIZE;
 
-    pub fn containing_address(address: u64) -> Frame<S> {
-        Frame { start_addr: address & Self::FRAME_MASK, size: PhantomData }
+    /// Returns the frame that contains `address`.
+    #[inline]
+    pub fn containing_address(address: PAddr) -> Frame<S> {
+        Frame { start_addr: address.align_down(S::SIZE), size: PhantomData }
     }
 
+    /// Returns the frame starting at `address`, fails if the address is not aligned to the frame size.
+    #[inline]
+    pub fn from_start_address(address: PAddr) -> Result<Frame<S>, &'static str> {
+        if !address.is_aligned(S::SIZE) {
+            return Err("Frame start address is not aligned to the frame size");
+        }
+        Ok(Frame::containing_address(address))
+    }
+
+    #[inline]
+    pub fn start_address(self) -> PAddr {
+        self.start_addr
+    }
+
+    #[inline]
+    pub fn size(self) -> u64 {
+        S::SIZE
+    }
+
+    /// Frames in `[start, end)`.
+    #[inline]
+    pub fn range(start: Frame<S>, end: Frame<S>) -> FrameRange<S> {
+        FrameRange { start, end }
+    }
+
+    /// Frames in `[start, end]`.
+    #[inline]
+    pub fn range_inclusive(start: Frame<S>, end: Frame<S>) -> FrameRangeInclusive<S> {
+        FrameRangeInclusive { start, end, exhausted: start > end }
+    }
+
+    /// Returns the larger frame of size `L` that contains this frame.
+    #[inline]
+    pub fn containing_frame<L: PageSize>(self) -> Frame<L> {
+        assert!(L::SIZE >= S::SIZE, "Target frame size must not be smaller");
+        Frame::containing_address(self.start_addr)
+    }
+
+    /// Returns the smaller frames of size `T` this frame consists of.
+    #[inline]
+    pub fn sub_frames<T: PageSize>(self) -> FrameRange<T> {
+        assert!(T::SIZE <= S::SIZE, "Target frame size must not be larger");
+        let start = Frame::containing_address(self.start_addr);
+        FrameRange { start, end: start + S::SIZE / T::SIZE }
+    }
+
+    /// Zeroes the frame.
+    ///
+    /// Requires the frame to be identity mapped.
     pub fn clear(&mut self) {
         let frame_slice = {
-            let ptr = self.start_addr as *mut u64;
-            unsafe { slice::from_raw_parts_mut(ptr, 512) }
+            let ptr = self.start_addr.as_u64() as *mut u64;
+            unsafe { slice::from_raw_parts_mut(ptr, (S::SIZE / 8) as usize) }
         };
         frame_slice.fill(0_u64);
     }
 }
 
-impl AddAssign<u64> for Frame {
-    fn add_assign(&mut self, other: u64) {
-        self.start_addr += other * 4096;
+/// Steps `rhs` frames forward.
+impl<S: PageSize> Add<u64> for Frame<S> {
+    type Output = Frame<S>;
+
+    #[inline]
+    fn add(self, rhs: u64) -> Frame<S> {
+        Frame::containing_address(self.start_addr + rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> AddAssign<u64> for Frame<S> {
+    #[inline]
+    fn add_assign(&mut self, rhs: u64) {
+        *self = *self + rhs;
+    }
+}
+
+/// Steps `rhs` frames backward.
+impl<S: PageSize> Sub<u64> for Frame<S> {
+    type Output = Frame<S>;
+
+    #[inline]
+    fn sub(self, rhs: u64) -> Frame<S> {
+        Frame::containing_address(self.start_addr - rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> SubAssign<u64> for Frame<S> {
+    #[inline]
+    fn sub_assign(&mut self, rhs: u64) {
+        *self = *self - rhs;
+    }
+}
+
+/// Number of frames between two frames.
+impl<S: PageSize> Sub<Frame<S>> for Frame<S> {
+    type Output = u64;
+
+    #[inline]
+    fn sub(self, rhs: Frame<S>) -> u64 {
+        (self.start_addr - rhs.start_addr) / S::SIZE
+    }
+}
+
+/// A range of frames `[start, end)`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameRange<S: PageSize = Page4KiB> {
+    pub start: Frame<S>,
+    pub end: Frame<S>,
+}
+
+impl<S: PageSize> FrameRange<S> {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Number of frames in the range.
+    #[inline]
+    pub fn count_frames(&self) -> u64 {
+        if self.is_empty() { 0 } else { self.end - self.start }
+    }
+}
+
+impl<S: PageSize> Iterator for FrameRange<S> {
+    type Item = Frame<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Frame<S>> {
+        if self.is_empty() {
+            return None;
+        }
+        let frame = self.start;
+        self.start += 1;
+        Some(frame)
+    }
+}
+
+/// A range of frames `[start, end]`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameRangeInclusive<S: PageSize = Page4KiB> {
+    pub start: Frame<S>,
+    pub end: Frame<S>,
+    // the last frame of the physical address space has no successor
+    exhausted: bool,
+}
+
+impl<S: PageSize> FrameRangeInclusive<S> {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.exhausted || self.start > self.end
+    }
+
+    /// Number of frames in the range.
+    #[inline]
+    pub fn count_frames(&self) -> u64 {
+        if self.is_empty() { 0 } else { self.end - self.start + 1 }
+    }
+}
+
+impl<S: PageSize> Iterator for FrameRangeInclusive<S> {
+    type Item = Frame<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Frame<S>> {
+        if self.is_empty() {
+            return None;
+        }
+        let frame = self.start;
+        if frame == self.end {
+            self.exhausted = true;
+        } else {
+            self.start += 1;
+        }
+        Some(frame)
+    }
+}
+
+/// Hands out physical frames, e.g. for new page tables.
+///
+/// # Safety
+///
+/// Implementations must only return frames that are unused.
+pub unsafe trait FrameAllocator<S: PageSize = Page4KiB> {
+    fn allocate_frame(&mut self) -> Option<Frame<S>>;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+
+    use super::*;
+    use crate::paging::{Page1GiB, Page2MiB};
+
+    #[test]
+    fn frame_arithmetic() {
+        let frame = Frame::<Page4KiB>::containing_address(PAddr::new(0x5678));
+        assert_eq!(frame.start_address(), PAddr::new(0x5000));
+        assert_eq!(frame.size(), 4096);
+
+        let mut next = frame + 2;
+        assert_eq!(next.start_address(), PAddr::new(0x7000));
+        assert_eq!(next - frame, 2);
+        next -= 1;
+        assert_eq!(next, frame + 1);
+        next += 1;
+        assert_eq!(next - 2, frame);
+
+        assert!(Frame::<Page4KiB>::from_start_address(PAddr::new(0x5001)).is_err());
+        assert_eq!(Frame::<Page4KiB>::from_start_address(PAddr::new(0x5000)), Ok(frame));
+    }
+
+    #[test]
+    fn frame_ranges() {
+        let start = Frame::<Page4KiB>::containing_address(PAddr::new(0x1000));
+        let end = start + 4;
+
+        let range = Frame::range(start, end);
+        assert_eq!(range.count_frames(), 4);
+        assert_eq!(range.last(), Some(end - 1));
+        assert!(Frame::range(end, start).is_empty());
+        assert_eq!(Frame::range(end, start).count_frames(), 0);
+
+        let range = Frame::range_inclusive(start, end);
+        assert_eq!(range.count_frames(), 5);
+        assert_eq!(range.last(), Some(end));
+        assert_eq!(Frame::range_inclusive(end, start).next(), None);
+
+        // the last frame of the physical address space has no successor
+        let last = Frame::<Page4KiB>::containing_address(PAddr::new(0x000F_FFFF_FFFF_FFFF));
+        let mut range = Frame::range_inclusive(last, last);
+        assert_eq!(range.next(), Some(last));
+        assert_eq!(range.next(), None);
+        assert_eq!(range.count_frames(), 0);
+    }
+
+    #[test]
+    fn frame_size_conversions() {
+        assert!(Frame::<Page1GiB>::from_start_address(PAddr::new(0x20_0000)).is_err());
+
+        let frame = Frame::<Page4KiB>::containing_address(PAddr::new(0x4030_1000));
+        let huge_frame = frame.containing_frame::<Page2MiB>();
+        assert_eq!(huge_frame.start_address(), PAddr::new(0x4020_0000));
+        assert_eq!(frame.containing_frame::<Page1GiB>().start_address(), PAddr::new(0x4000_0000));
+
+        let sub_frames = huge_frame.sub_frames::<Page4KiB>();
+        assert_eq!(sub_frames.count_frames(), 512);
+        assert_eq!(sub_frames.start, Frame::containing_address(PAddr::new(0x4020_0000)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn smaller_containing_frame_panics() {
+        Frame::<Page2MiB>::containing_address(PAddr::zero()).containing_frame::<Page4KiB>();
+    }
+
+    #[test]
+    fn clear() {
+        // heap memory is identity mapped on the host
+        let mut memory = vec![0xFF_u8; 2 * 4096];
+        let start = memory.as_mut_ptr() as u64;
+        let mut frame = Frame::<Page4KiB>::containing_address(PAddr::new(start.next_multiple_of(4096)));
+        frame.clear();
+
+        let offset = (frame.start_address().as_u64() - start) as usize;
+        assert!(memory[offset..offset + 4096].iter().all(|&byte| byte == 0));
+        assert!(memory[..offset].iter().all(|&byte| byte == 0xFF));
     }
 }
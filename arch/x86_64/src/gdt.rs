@@ -0,0 +1,386 @@
+#[cfg(feature = "instructions")]
+use core::arch::asm;
+use core::mem;
+
+use bitflags::bitflags;
+
+/// Privilege level of a segment or selector.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PrivilegeLevel {
+    Ring0 = 0,
+    Ring1 = 1,
+    Ring2 = 2,
+    Ring3 = 3,
+}
+
+impl PrivilegeLevel {
+    #[inline]
+    pub const fn from_u16(value: u16) -> PrivilegeLevel {
+        match value & 0b11 {
+            0 => PrivilegeLevel::Ring0,
+            1 => PrivilegeLevel::Ring1,
+            2 => PrivilegeLevel::Ring2,
+            _ => PrivilegeLevel::Ring3,
+        }
+    }
+}
+
+/// Index into the GDT combined with the requested privilege level (RPL).
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SegmentSelector(pub u16);
+
+impl SegmentSelector {
+    #[inline]
+    pub const fn new(index: u16, rpl: PrivilegeLevel) -> SegmentSelector {
+        SegmentSelector(index << 3 | rpl as u16)
+    }
+
+    #[inline]
+    pub const fn index(self) -> u16 {
+        self.0 >> 3
+    }
+
+    #[inline]
+    pub const fn rpl(self) -> PrivilegeLevel {
+        PrivilegeLevel::from_u16(self.0)
+    }
+}
+
+/// Pointer to a descriptor table, used by `lgdt` and `lidt`.
+#[repr(C, packed(2))]
+#[derive(Clone, Copy, Debug)]
+pub struct DescriptorTablePointer {
+    /// Size of the table in bytes minus one.
+    pub limit: u16,
+    /// Virtual address of the table.
+    pub base: u64,
+}
+
+bitflags! {
+    /// Bits of a code or data segment descriptor.
+    ///
+    /// Base and limit are ignored in 64-bit mode, but they are set to the full address space
+    /// so that the descriptors also work in compatibility mode.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct DescriptorFlags: u64 {
+        const Accessed      = 1 << 40;
+        const Writable      = 1 << 41;
+        const Conforming    = 1 << 42;
+        const Executable    = 1 << 43;
+        /// Set for code and data segments, cleared for system segments (e.g. the TSS).
+        const UserSegment   = 1 << 44;
+        const DplRing3      = 3 << 45;
+        const Present       = 1 << 47;
+        const Available     = 1 << 52;
+        const LongMode      = 1 << 53;
+        const DefaultSize   = 1 << 54;
+        const Granularity   = 1 << 55;
+        const Limit0To15    = 0xFFFF;
+        const Limit16To19   = 0xF << 48;
+    }
+}
+
+impl DescriptorFlags {
+    const COMMON: DescriptorFlags = DescriptorFlags::UserSegment
+        .union(DescriptorFlags::Present)
+        .union(DescriptorFlags::Writable)
+        .union(DescriptorFlags::Accessed)
+        .union(DescriptorFlags::Limit0To15)
+        .union(DescriptorFlags::Limit16To19)
+        .union(DescriptorFlags::Granularity);
+
+    pub const KERNEL_CODE: DescriptorFlags = Self::COMMON
+        .union(DescriptorFlags::Executable)
+        .union(DescriptorFlags::LongMode);
+    pub const KERNEL_DATA: DescriptorFlags = Self::COMMON.union(DescriptorFlags::DefaultSize);
+    pub const USER_CODE: DescriptorFlags = Self::KERNEL_CODE.union(DescriptorFlags::DplRing3);
+    pub const USER_DATA: DescriptorFlags = Self::KERNEL_DATA.union(DescriptorFlags::DplRing3);
+}
+
+/// An entry of the GDT.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Descriptor {
+    /// Code or data segment, takes up one entry.
+    UserSegment(u64),
+    /// System segment like the TSS, takes up two entries.
+    SystemSegment(u64, u64),
+}
+
+impl Descriptor {
+    #[inline]
+    pub const fn kernel_code_segment() -> Descriptor {
+        Descriptor::UserSegment(DescriptorFlags::KERNEL_CODE.bits())
+    }
+
+    #[inline]
+    pub const fn kernel_data_segment() -> Descriptor {
+        Descriptor::UserSegment(DescriptorFlags::KERNEL_DATA.bits())
+    }
+
+    #[inline]
+    pub const fn user_code_segment() -> Descriptor {
+        Descriptor::UserSegment(DescriptorFlags::USER_CODE.bits())
+    }
+
+    #[inline]
+    pub const fn user_data_segment() -> Descriptor {
+        Descriptor::UserSegment(DescriptorFlags::USER_DATA.bits())
+    }
+
+    /// Creates a 64-bit TSS descriptor.
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        let base = tss as *const TaskStateSegment as u64;
+        let limit = (mem::size_of::<TaskStateSegment>() - 1) as u64;
+
+        let mut low = DescriptorFlags::Present.bits();
+        low |= limit & 0xFFFF;
+        low |= (base & 0xFF_FFFF) << 16;
+        // type: available 64-bit TSS
+        low |= 0b1001 << 40;
+        low |= ((limit >> 16) & 0xF) << 48;
+        low |= ((base >> 24) & 0xFF) << 56;
+
+        Descriptor::SystemSegment(low, base >> 32)
+    }
+
+    /// Descriptor privilege level.
+    #[inline]
+    pub fn dpl(&self) -> PrivilegeLevel {
+        let low = match self {
+            Descriptor::UserSegment(low) => *low,
+            Descriptor::SystemSegment(low, _) => *low,
+        };
+        PrivilegeLevel::from_u16((low >> 45) as u16)
+    }
+}
+
+/// The 64-bit task state segment.
+///
+/// Holds the stacks the CPU switches to on privilege level changes and for interrupts with an IST index.
+#[repr(C, packed(4))]
+#[derive(Clone, Copy, Debug)]
+pub struct TaskStateSegment {
+    reserved_1: u32,
+    /// Stack pointers loaded when switching to ring 0-2 (RSP0-RSP2).
+    pub privilege_stack_table: [u64; 3],
+    reserved_2: u64,
+    /// Stack pointers for interrupts with an IST index of 1-7 (stored at index 0-6).
+    pub interrupt_stack_table: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    /// Offset of the I/O permission bitmap from the start of the TSS.
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    /// Creates a TSS with empty stack tables and without an I/O permission bitmap.
+    #[inline]
+    pub const fn new() -> TaskStateSegment {
+        TaskStateSegment {
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            iomap_base: mem::size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+impl Default for TaskStateSegment {
+    fn default() -> Self {
+        TaskStateSegment::new()
+    }
+}
+
+/// A global descriptor table with room for `N` entries (including the null descriptor).
+#[derive(Clone, Debug)]
+pub struct GlobalDescriptorTable<const N: usize = 8> {
+    table: [u64; N],
+    len: usize,
+}
+
+impl<const N: usize> GlobalDescriptorTable<N> {
+    /// Creates a GDT that only contains the null descriptor.
+    #[inline]
+    pub const fn new() -> GlobalDescriptorTable<N> {
+        assert!(N > 0 && N <= 8192, "A GDT has between 1 and 8192 entries");
+        GlobalDescriptorTable { table: [0; N], len: 1 }
+    }
+
+    /// Adds a descriptor and returns its selector.
+    ///
+    /// Panics if the table is full.
+    pub fn add_entry(&mut self, descriptor: Descriptor) -> SegmentSelector {
+        let index = match descriptor {
+            Descriptor::UserSegment(value) => self.push(value),
+            Descriptor::SystemSegment(low, high) => {
+                let index = self.push(low);
+                self.push(high);
+                index
+            }
+        };
+        SegmentSelector::new(index as u16, descriptor.dpl())
+    }
+
+    fn push(&mut self, value: u64) -> usize {
+        assert!(self.len < N, "GDT is full");
+        let index = self.len;
+        self.table[index] = value;
+        self.len += 1;
+        index
+    }
+
+    /// Raw entries of the table.
+    pub fn entries(&self) -> &[u64] {
+        &self.table[..self.len]
+    }
+
+    /// Pointer to the table for `lgdt`.
+    pub fn pointer(&self) -> DescriptorTablePointer {
+        DescriptorTablePointer {
+            limit: (self.len * mem::size_of::<u64>() - 1) as u16,
+            base: self.table.as_ptr() as u64,
+        }
+    }
+
+    /// Loads the table with `lgdt`.
+    ///
+    /// The segment registers keep their cached descriptors until they are reloaded.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn load(&'static self) {
+        unsafe { lgdt(&self.pointer()) };
+    }
+}
+
+impl<const N: usize> Default for GlobalDescriptorTable<N> {
+    fn default() -> Self {
+        GlobalDescriptorTable::new()
+    }
+}
+
+/// Loads a GDT.
+///
+/// # Safety
+///
+/// The table has to stay valid for as long as it is loaded.
+#[cfg(feature = "instructions")]
+#[inline]
+pub unsafe fn lgdt(pointer: &DescriptorTablePointer) {
+    asm!("lgdt [{}]", in(reg) pointer, options(readonly, nostack, preserves_flags));
+}
+
+/// Loads the task register with a TSS selector.
+///
+/// # Safety
+///
+/// The selector has to point to a valid, available TSS descriptor in the loaded GDT.
+#[cfg(feature = "instructions")]
+#[inline]
+pub unsafe fn load_tss(selector: SegmentSelector) {
+    asm!("ltr {0:x}", in(reg) selector.0, options(nostack, preserves_flags));
+}
+
+/// Reloads CS through a far return.
+///
+/// # Safety
+///
+/// The selector has to point to a valid 64-bit code segment in the loaded GDT.
+#[cfg(feature = "instructions")]
+#[inline]
+pub unsafe fn set_cs(selector: SegmentSelector) {
+    asm!(
+        "push {sel}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        sel = in(reg) u64::from(selector.0),
+        tmp = lateout(reg) _,
+        options(preserves_flags)
+    );
+}
+
+macro_rules! segment_register {
+    ($set:ident, $get:ident, $reg:literal) => {
+        #[doc = concat!("Loads ", $reg, " with the given selector.")]
+        ///
+        /// # Safety
+        ///
+        /// The selector has to point to a valid data segment in the loaded GDT (or be null).
+        #[cfg(feature = "instructions")]
+        #[inline]
+        pub unsafe fn $set(selector: SegmentSelector) {
+            asm!(concat!("mov ", $reg, ", {0:x}"), in(reg) selector.0, options(nostack, preserves_flags));
+        }
+
+        #[cfg(feature = "instructions")]
+        #[doc = concat!("Returns the current value of ", $reg, ".")]
+        #[inline]
+        pub fn $get() -> SegmentSelector {
+            let value: u16;
+            unsafe { asm!(concat!("mov {0:x}, ", $reg), out(reg) value, options(nomem, nostack, preserves_flags)); }
+            SegmentSelector(value)
+        }
+    };
+}
+
+segment_register!(load_ss, ss, "ss");
+segment_register!(load_ds, ds, "ds");
+segment_register!(load_es, es, "es");
+segment_register!(load_fs, fs, "fs");
+segment_register!(load_gs, gs, "gs");
+
+/// Returns the current value of CS.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn cs() -> SegmentSelector {
+    let value: u16;
+    unsafe { asm!("mov {0:x}, cs", out(reg) value, options(nomem, nostack, preserves_flags)); }
+    SegmentSelector(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TSS: TaskStateSegment = TaskStateSegment::new();
+
+    #[test]
+    fn segment_descriptors() {
+        assert_eq!(DescriptorFlags::KERNEL_CODE.bits(), 0x00AF_9B00_0000_FFFF);
+        assert_eq!(DescriptorFlags::KERNEL_DATA.bits(), 0x00CF_9300_0000_FFFF);
+        assert_eq!(DescriptorFlags::USER_CODE.bits(), 0x00AF_FB00_0000_FFFF);
+        assert_eq!(DescriptorFlags::USER_DATA.bits(), 0x00CF_F300_0000_FFFF);
+    }
+
+    #[test]
+    fn tss_descriptor() {
+        assert_eq!(mem::size_of::<TaskStateSegment>(), 104);
+
+        let Descriptor::SystemSegment(low, high) = Descriptor::tss_segment(&TSS) else { panic!() };
+        let base = &TSS as *const TaskStateSegment as u64;
+
+        assert_eq!(low & 0xFFFF, 103);
+        assert_eq!((low >> 40) & 0xFF, 0x89);
+        assert_eq!(((low >> 16) & 0xFF_FFFF) | ((low >> 56) << 24) | (high << 32), base);
+    }
+
+    #[test]
+    fn selectors() {
+        let mut gdt = GlobalDescriptorTable::<8>::new();
+        assert_eq!(gdt.add_entry(Descriptor::kernel_code_segment()), SegmentSelector(0x08));
+        assert_eq!(gdt.add_entry(Descriptor::kernel_data_segment()), SegmentSelector(0x10));
+        assert_eq!(gdt.add_entry(Descriptor::tss_segment(&TSS)), SegmentSelector(0x18));
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        assert_eq!(user_data, SegmentSelector(0x2B));
+        assert_eq!(user_data.index(), 5);
+        assert_eq!(user_data.rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(gdt.pointer().limit, 6 * 8 - 1);
+    }
+}
@@ -0,0 +1,451 @@
+#[cfg(feature = "instructions")]
+use core::arch::asm;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Index, IndexMut};
+
+use bitflags::bitflags;
+
+use crate::addr::VAddr;
+#[cfg(feature = "instructions")]
+use crate::gdt;
+use crate::gdt::{DescriptorTablePointer, PrivilegeLevel};
+
+/// Handler for interrupts and exceptions without an error code.
+pub type HandlerFunc = extern "x86-interrupt" fn(InterruptStackFrame);
+/// Handler for exceptions that push an error code.
+pub type HandlerFuncWithErrCode = extern "x86-interrupt" fn(InterruptStackFrame, u64);
+/// Handler for page faults.
+pub type PageFaultHandlerFunc = extern "x86-interrupt" fn(InterruptStackFrame, PageFaultErrorCode);
+/// Handler for exceptions that can not be recovered from.
+pub type DivergingHandlerFunc = extern "x86-interrupt" fn(InterruptStackFrame) -> !;
+/// Handler for exceptions that push an error code and can not be recovered from.
+pub type DivergingHandlerFuncWithErrCode = extern "x86-interrupt" fn(InterruptStackFrame, u64) -> !;
+
+/// Function pointer types that can be stored in an IDT entry.
+///
+/// # Safety
+///
+/// Implementations must only be function pointers with the `x86-interrupt` ABI.
+pub unsafe trait HandlerFuncType {
+    fn to_virt_addr(self) -> VAddr;
+}
+
+macro_rules! impl_handler_func_type {
+    ($f:ty) => {
+        unsafe impl HandlerFuncType for $f {
+            #[inline]
+            fn to_virt_addr(self) -> VAddr {
+                VAddr::from_ptr(self as *const ())
+            }
+        }
+    };
+}
+
+impl_handler_func_type!(HandlerFunc);
+impl_handler_func_type!(HandlerFuncWithErrCode);
+impl_handler_func_type!(PageFaultHandlerFunc);
+impl_handler_func_type!(DivergingHandlerFunc);
+impl_handler_func_type!(DivergingHandlerFuncWithErrCode);
+
+/// The state the CPU pushes onto the stack before calling an interrupt handler.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct InterruptStackFrame {
+    /// Address of the instruction that is executed when the handler returns.
+    ///
+    /// For faults this is the faulting instruction, for traps the one after it.
+    pub instruction_pointer: VAddr,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    /// Stack pointer at the time of the interrupt.
+    pub stack_pointer: VAddr,
+    pub stack_segment: u64,
+}
+
+impl fmt::Debug for InterruptStackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InterruptStackFrame")
+            .field("instruction_pointer", &self.instruction_pointer)
+            .field("code_segment", &format_args!("{:#X}", self.code_segment))
+            .field("cpu_flags", &format_args!("{:#X}", self.cpu_flags))
+            .field("stack_pointer", &self.stack_pointer)
+            .field("stack_segment", &format_args!("{:#X}", self.stack_segment))
+            .finish()
+    }
+}
+
+bitflags! {
+    /// Error code pushed by a page fault, the faulting address is stored in CR2.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct PageFaultErrorCode: u64 {
+        /// Set for protection violations, cleared if the page was not present.
+        const ProtectionViolation   = 1 << 0;
+        const CausedByWrite         = 1 << 1;
+        const UserMode              = 1 << 2;
+        const MalformedTable        = 1 << 3;
+        const InstructionFetch      = 1 << 4;
+        const ProtectionKey         = 1 << 5;
+        const ShadowStack           = 1 << 6;
+        const Sgx                   = 1 << 15;
+    }
+}
+
+/// Options of an IDT entry (bits 32-47 of the descriptor).
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EntryOptions(u16);
+
+impl EntryOptions {
+    /// A non-present interrupt gate without an IST index.
+    #[inline]
+    const fn minimal() -> EntryOptions {
+        // bits 9-11 are always set for 64-bit gates
+        EntryOptions(0b1110_0000_0000)
+    }
+
+    #[inline]
+    pub fn set_present(&mut self, present: bool) -> &mut EntryOptions {
+        self.set_bit(15, present);
+        self
+    }
+
+    /// Interrupt gates clear IF when the handler is entered, trap gates keep it.
+    #[inline]
+    pub fn disable_interrupts(&mut self, disable: bool) -> &mut EntryOptions {
+        self.set_bit(8, !disable);
+        self
+    }
+
+    /// Sets the minimum privilege level that can invoke the handler with `int`.
+    #[inline]
+    pub fn set_privilege_level(&mut self, dpl: PrivilegeLevel) -> &mut EntryOptions {
+        self.0 = (self.0 & !(0b11 << 13)) | (dpl as u16) << 13;
+        self
+    }
+
+    /// Switches to the stack at `index` of the TSS interrupt stack table when the handler is entered.
+    ///
+    /// # Safety
+    ///
+    /// The index has to point to a valid stack that is not used by any other handler that can interrupt this one.
+    #[inline]
+    pub unsafe fn set_stack_index(&mut self, index: u16) -> &mut EntryOptions {
+        assert!(index < 7, "The interrupt stack table has 7 entries");
+        // the hardware uses 0 for "no stack switch"
+        self.0 = (self.0 & !0b111) | (index + 1);
+        self
+    }
+
+    /// The TSS interrupt stack table index, `None` if the stack is not switched.
+    #[inline]
+    pub fn stack_index(&self) -> Option<u16> {
+        match self.0 & 0b111 {
+            0 => None,
+            index => Some(index - 1),
+        }
+    }
+
+    #[inline]
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    fn set_bit(&mut self, bit: u16, value: bool) {
+        if value {
+            self.0 |= 1 << bit;
+        } else {
+            self.0 &= !(1 << bit);
+        }
+    }
+}
+
+impl fmt::Debug for EntryOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EntryOptions({:#06X})", self.0)
+    }
+}
+
+/// A 16-byte IDT gate descriptor for handlers of type `F`.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Entry<F> {
+    pointer_low: u16,
+    gdt_selector: u16,
+    options: EntryOptions,
+    pointer_middle: u16,
+    pointer_high: u32,
+    reserved: u32,
+    handler: PhantomData<F>,
+}
+
+impl<F> Entry<F> {
+    /// A non-present entry, triggers a #GP (or #DF) when the vector is raised.
+    #[inline]
+    pub const fn missing() -> Entry<F> {
+        Entry {
+            pointer_low: 0,
+            gdt_selector: 0,
+            options: EntryOptions::minimal(),
+            pointer_middle: 0,
+            pointer_high: 0,
+            reserved: 0,
+            handler: PhantomData,
+        }
+    }
+
+    /// Points the entry to the handler at `address` and marks it present.
+    ///
+    /// Uses the current code segment.
+    ///
+    /// # Safety
+    ///
+    /// The address has to point to a function with the `x86-interrupt` ABI that matches the vector.
+    #[cfg(feature = "instructions")]
+    pub unsafe fn set_handler_addr(&mut self, address: VAddr) -> &mut EntryOptions {
+        let address = address.as_u64();
+        self.pointer_low = address as u16;
+        self.pointer_middle = (address >> 16) as u16;
+        self.pointer_high = (address >> 32) as u32;
+        self.gdt_selector = gdt::cs().0;
+        self.options.set_present(true);
+        &mut self.options
+    }
+
+    /// Address of the handler.
+    #[inline]
+    pub fn handler_addr(&self) -> VAddr {
+        let address = self.pointer_low as u64
+            | (self.pointer_middle as u64) << 16
+            | (self.pointer_high as u64) << 32;
+        VAddr::new_truncate(address)
+    }
+
+    #[inline]
+    pub fn options(&self) -> EntryOptions {
+        self.options
+    }
+}
+
+#[cfg(feature = "instructions")]
+impl<F: HandlerFuncType> Entry<F> {
+    /// Points the entry to `handler` and marks it present.
+    #[inline]
+    pub fn set_handler_fn(&mut self, handler: F) -> &mut EntryOptions {
+        unsafe { self.set_handler_addr(handler.to_virt_addr()) }
+    }
+}
+
+impl<F> fmt::Debug for Entry<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("handler_addr", &self.handler_addr())
+            .field("gdt_selector", &format_args!("{:#X}", self.gdt_selector))
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// The interrupt descriptor table.
+///
+/// The first 32 vectors are reserved for CPU exceptions and have typed entries,
+/// the remaining ones can be accessed by their vector number.
+#[repr(C, align(16))]
+#[derive(Clone, Debug)]
+pub struct InterruptDescriptorTable {
+    /// #DE, e.g. division by zero.
+    pub divide_error: Entry<HandlerFunc>,
+    /// #DB
+    pub debug: Entry<HandlerFunc>,
+    pub non_maskable_interrupt: Entry<HandlerFunc>,
+    /// #BP, raised by `int3`.
+    pub breakpoint: Entry<HandlerFunc>,
+    /// #OF, raised by `into` (not available in 64-bit mode).
+    pub overflow: Entry<HandlerFunc>,
+    /// #BR
+    pub bound_range_exceeded: Entry<HandlerFunc>,
+    /// #UD
+    pub invalid_opcode: Entry<HandlerFunc>,
+    /// #NM
+    pub device_not_available: Entry<HandlerFunc>,
+    /// #DF, raised if the CPU fails to invoke an exception handler. The error code is always zero.
+    pub double_fault: Entry<DivergingHandlerFuncWithErrCode>,
+    coprocessor_segment_overrun: Entry<HandlerFunc>,
+    /// #TS
+    pub invalid_tss: Entry<HandlerFuncWithErrCode>,
+    /// #NP
+    pub segment_not_present: Entry<HandlerFuncWithErrCode>,
+    /// #SS
+    pub stack_segment_fault: Entry<HandlerFuncWithErrCode>,
+    /// #GP
+    pub general_protection_fault: Entry<HandlerFuncWithErrCode>,
+    /// #PF
+    pub page_fault: Entry<PageFaultHandlerFunc>,
+    reserved_1: Entry<HandlerFunc>,
+    /// #MF
+    pub x87_floating_point: Entry<HandlerFunc>,
+    /// #AC
+    pub alignment_check: Entry<HandlerFuncWithErrCode>,
+    /// #MC
+    pub machine_check: Entry<DivergingHandlerFunc>,
+    /// #XM
+    pub simd_floating_point: Entry<HandlerFunc>,
+    /// #VE
+    pub virtualization: Entry<HandlerFunc>,
+    /// #CP
+    pub cp_protection_exception: Entry<HandlerFuncWithErrCode>,
+    reserved_2: [Entry<HandlerFunc>; 6],
+    /// #HV
+    pub hv_injection_exception: Entry<HandlerFunc>,
+    /// #VC
+    pub vmm_communication_exception: Entry<HandlerFuncWithErrCode>,
+    /// #SX
+    pub security_exception: Entry<HandlerFuncWithErrCode>,
+    reserved_3: Entry<HandlerFunc>,
+    interrupts: [Entry<HandlerFunc>; 256 - 32],
+}
+
+impl InterruptDescriptorTable {
+    /// Creates an IDT where every entry is missing.
+    #[inline]
+    pub const fn new() -> InterruptDescriptorTable {
+        InterruptDescriptorTable {
+            divide_error: Entry::missing(),
+            debug: Entry::missing(),
+            non_maskable_interrupt: Entry::missing(),
+            breakpoint: Entry::missing(),
+            overflow: Entry::missing(),
+            bound_range_exceeded: Entry::missing(),
+            invalid_opcode: Entry::missing(),
+            device_not_available: Entry::missing(),
+            double_fault: Entry::missing(),
+            coprocessor_segment_overrun: Entry::missing(),
+            invalid_tss: Entry::missing(),
+            segment_not_present: Entry::missing(),
+            stack_segment_fault: Entry::missing(),
+            general_protection_fault: Entry::missing(),
+            page_fault: Entry::missing(),
+            reserved_1: Entry::missing(),
+            x87_floating_point: Entry::missing(),
+            alignment_check: Entry::missing(),
+            machine_check: Entry::missing(),
+            simd_floating_point: Entry::missing(),
+            virtualization: Entry::missing(),
+            cp_protection_exception: Entry::missing(),
+            reserved_2: [Entry::missing(); 6],
+            hv_injection_exception: Entry::missing(),
+            vmm_communication_exception: Entry::missing(),
+            security_exception: Entry::missing(),
+            reserved_3: Entry::missing(),
+            interrupts: [Entry::missing(); 256 - 32],
+        }
+    }
+
+    /// Pointer to the table for `lidt`.
+    pub fn pointer(&self) -> DescriptorTablePointer {
+        DescriptorTablePointer {
+            limit: (mem::size_of::<InterruptDescriptorTable>() - 1) as u16,
+            base: self as *const InterruptDescriptorTable as u64,
+        }
+    }
+
+    /// Loads the table with `lidt`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn load(&'static self) {
+        unsafe { lidt(&self.pointer()) };
+    }
+}
+
+impl Default for InterruptDescriptorTable {
+    fn default() -> Self {
+        InterruptDescriptorTable::new()
+    }
+}
+
+/// Entries of the vectors 32-255, exceptions have to be accessed through their named fields.
+impl Index<u8> for InterruptDescriptorTable {
+    type Output = Entry<HandlerFunc>;
+
+    #[inline]
+    fn index(&self, vector: u8) -> &Entry<HandlerFunc> {
+        assert!(vector >= 32, "Vectors 0-31 are CPU exceptions, use the named fields");
+        &self.interrupts[vector as usize - 32]
+    }
+}
+
+impl IndexMut<u8> for InterruptDescriptorTable {
+    #[inline]
+    fn index_mut(&mut self, vector: u8) -> &mut Entry<HandlerFunc> {
+        assert!(vector >= 32, "Vectors 0-31 are CPU exceptions, use the named fields");
+        &mut self.interrupts[vector as usize - 32]
+    }
+}
+
+/// Loads an IDT.
+///
+/// # Safety
+///
+/// The table has to stay valid for as long as it is loaded.
+#[cfg(feature = "instructions")]
+#[inline]
+pub unsafe fn lidt(pointer: &DescriptorTablePointer) {
+    asm!("lidt [{}]", in(reg) pointer, options(readonly, nostack, preserves_flags));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "instructions")]
+    extern "x86-interrupt" fn handler(_frame: InterruptStackFrame) {}
+
+    #[cfg(feature = "instructions")]
+    extern "x86-interrupt" fn double_fault_handler(_frame: InterruptStackFrame, _error_code: u64) -> ! {
+        panic!()
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(mem::size_of::<Entry<HandlerFunc>>(), 16);
+        assert_eq!(mem::size_of::<InterruptDescriptorTable>(), 256 * 16);
+        assert_eq!(mem::size_of::<InterruptStackFrame>(), 5 * 8);
+
+        let idt = InterruptDescriptorTable::new();
+        let base = &idt as *const InterruptDescriptorTable as usize;
+        assert_eq!(&idt.double_fault as *const _ as usize - base, 8 * 16);
+        assert_eq!(&idt.page_fault as *const _ as usize - base, 14 * 16);
+        assert_eq!(&idt.security_exception as *const _ as usize - base, 30 * 16);
+        assert_eq!(&idt[32] as *const _ as usize - base, 32 * 16);
+        assert_eq!(idt.pointer().limit, 4095);
+    }
+
+    #[cfg(feature = "instructions")]
+    #[test]
+    fn entries() {
+        let mut idt = InterruptDescriptorTable::new();
+        assert_eq!(idt.breakpoint.options().bits(), 0x0E00);
+
+        idt.breakpoint.set_handler_fn(handler).set_privilege_level(PrivilegeLevel::Ring3);
+        assert_eq!(idt.breakpoint.handler_addr().as_u64(), handler as HandlerFunc as usize as u64);
+        assert_eq!(idt.breakpoint.options().bits(), 0xEE00);
+        assert_eq!(idt.breakpoint.gdt_selector, gdt::cs().0);
+
+        unsafe { idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(0) };
+        assert_eq!(idt.double_fault.options().stack_index(), Some(0));
+        assert_eq!(idt.double_fault.options().bits() & 0b111, 1);
+
+        idt[0x80].set_handler_fn(handler).disable_interrupts(false);
+        assert_eq!(idt[0x80].options().bits(), 0x8F00);
+        assert_eq!(idt[0x81].options().stack_index(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn exception_vectors_can_not_be_indexed() {
+        let idt = InterruptDescriptorTable::new();
+        let _ = &idt[14];
+    }
+}
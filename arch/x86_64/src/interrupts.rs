@@ -0,0 +1,58 @@
+use core::arch::asm;
+
+use crate::registers::{self, RFlags};
+
+/// Enables maskable interrupts (`sti`).
+///
+/// Not `nomem`, so memory accesses can not be moved out of the preceding critical section.
+#[inline]
+pub fn enable() {
+    unsafe { asm!("sti", options(preserves_flags, nostack)); }
+}
+
+/// Disables maskable interrupts (`cli`).
+///
+/// Not `nomem`, so memory accesses can not be moved into the preceding code.
+#[inline]
+pub fn disable() {
+    unsafe { asm!("cli", options(preserves_flags, nostack)); }
+}
+
+/// Whether maskable interrupts are enabled (RFLAGS.IF).
+#[inline]
+pub fn are_enabled() -> bool {
+    registers::read_rflags().contains(RFlags::InterruptEnable)
+}
+
+/// Runs `f` with interrupts disabled and restores the previous state afterwards.
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+
+    let result = f();
+
+    if enabled {
+        enable();
+    }
+    result
+}
+
+/// Enables interrupts and halts until the next one arrives.
+///
+/// `sti` only takes effect after the following instruction, so no interrupt can slip in between.
+#[inline]
+pub fn enable_and_hlt() {
+    unsafe { asm!("sti; hlt", options(preserves_flags, nostack)); }
+}
+
+/// Raises a breakpoint exception (`int3`).
+#[inline]
+pub fn int3() {
+    unsafe { asm!("int3", options(nomem, nostack)); }
+}
@@ -1,37 +1,36 @@
 /*!
 A library containing wrappers and helper functions for the x86_64 architecture.
 
+Everything that executes special instructions (inline assembly) is only available with the
+`instructions` feature, which is enabled by default. Without it only the plain data structures and
+their logic remain, e.g. for host tools like the image builder.
 */
 
 #![no_std]
-
-use core::fmt::Arguments;
-type PrintFn = fn(Arguments);
-
-/// Function pointer that needs to point to a print(fmt::Arguments) function in either the bootloader or kernel.
-pub static mut PRINT: Option<PrintFn> = None;
-
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => {
-        let print_fn = unsafe { $crate::PRINT.expect("PRINT callback was not defined in main program") };
-        print_fn(format_args!($($arg)*));
-    };
-}
-
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
-}
-
+#![feature(abi_x86_interrupt)]
 
 /// Various utility functions.
 pub mod utils;
 
 /// Provides wrapper functions for routines that require inline assembly.
+#[cfg(feature = "instructions")]
 pub mod asm_wrappers;
 
+/// Typed access to I/O ports.
+#[cfg(feature = "instructions")]
+pub mod port;
+
+/// Enabling, disabling and querying interrupts.
+#[cfg(feature = "instructions")]
+pub mod interrupts;
+
+/// The legacy 8259 interrupt controllers.
+#[cfg(feature = "instructions")]
+pub mod pic;
+
+/// Spinlocks and lazily initialized values that are safe to use with interrupts.
+pub mod sync;
+
 /// Canonical virtual and physical 64-bit address types.
 pub mod addr;
 
@@ -44,5 +43,35 @@ pub mod page_table;
 /// Abstractions for physical frames.
 pub mod frame;
 
-/// ELF file structs.
+/// ELF file structs and symbol tables.
 pub mod elf;
+
+/// Stack unwinding through frame pointers and symbolized backtraces.
+pub mod backtrace;
+
+/// Processor identification and feature detection.
+pub mod cpuid;
+
+/// Control registers, RFLAGS and model specific registers.
+pub mod registers;
+
+/// TLB invalidation and process context identifiers.
+pub mod tlb;
+
+/// Global descriptor table and task state segment.
+pub mod gdt;
+
+/// Interrupt descriptor table and interrupt handler types.
+pub mod idt;
+
+/// Time stamp counter and its calibration.
+pub mod time;
+
+/// Backend for the `log` crate with multiple sinks.
+pub mod logger;
+
+/// ANSI escape sequence parser for consoles.
+pub mod ansi;
+
+/// Information passed from the bootloader to the kernel.
+pub mod boot_info;
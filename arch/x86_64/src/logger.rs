@@ -0,0 +1,173 @@
+/*!
+A `log` backend that forwards every record to a set of registered sinks.
+
+Library code uses the macros of the `log` crate (`info!`, `warn!`, ...). The bootloader and the kernel
+call `init` once and register their outputs (serial port, VGA, ...) with `add_sink`.
+*/
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::sync::Once;
+#[cfg(feature = "instructions")]
+use crate::time::nanoseconds;
+
+/// Without `instructions` the TSC can not be read, so records have no timestamp.
+#[cfg(not(feature = "instructions"))]
+fn nanoseconds() -> Option<u64> {
+    None
+}
+
+/// Maximum number of sinks that can be registered at the same time.
+pub const MAX_SINKS: usize = 4;
+
+/// An output for log records.
+pub trait LogSink: Sync {
+    /// Writes one formatted log line, including the trailing newline.
+    fn write_line(&self, args: fmt::Arguments);
+
+    /// The most verbose level this sink wants to receive.
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+}
+
+struct Logger {
+    sinks: [Once<&'static dyn LogSink>; MAX_SINKS],
+    sink_count: AtomicUsize,
+}
+
+static LOGGER: Logger = Logger {
+    sinks: [const { Once::new() }; MAX_SINKS],
+    sink_count: AtomicUsize::new(0),
+};
+
+impl Logger {
+    fn sinks(&self) -> impl Iterator<Item = &'static dyn LogSink> + '_ {
+        self.sinks.iter().filter_map(|sink| sink.get().copied())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.sinks().any(|sink| metadata.level() <= sink.level())
+    }
+
+    fn log(&self, record: &Record) {
+        for sink in self.sinks().filter(|sink| record.level() <= sink.level()) {
+            sink.write_line(format_args!("{}{:5} {}\n", Timestamp, record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// `[seconds.microseconds] ` since power on, empty while the TSC frequency is unknown.
+struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match nanoseconds() {
+            Some(nanoseconds) => write!(f, "[{:5}.{:06}] ", nanoseconds / 1_000_000_000, nanoseconds / 1000 % 1_000_000),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Installs the logger and sets the global maximum level.
+///
+/// Fails if a logger was already installed.
+pub fn init(level: LevelFilter) -> Result<(), &'static str> {
+    log::set_logger(&LOGGER).map_err(|_| "A logger was already installed")?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Registers an additional sink, fails if `MAX_SINKS` sinks are already registered.
+pub fn add_sink(sink: &'static dyn LogSink) -> Result<(), &'static str> {
+    let index = LOGGER.sink_count.fetch_add(1, Ordering::Relaxed);
+    if index >= MAX_SINKS {
+        LOGGER.sink_count.store(MAX_SINKS, Ordering::Relaxed);
+        return Err("Too many log sinks");
+    }
+    LOGGER.sinks[index].call_once(|| sink);
+    Ok(())
+}
+
+/// Writes `args` to every sink regardless of its level, e.g. for panic messages.
+///
+/// Returns the number of sinks that were written to.
+pub fn write_to_all_sinks(args: fmt::Arguments) -> usize {
+    let mut count = 0;
+    for sink in LOGGER.sinks() {
+        sink.write_line(args);
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use log::{debug, info, warn};
+
+    use super::*;
+    use crate::sync::Mutex;
+
+    struct TestSink {
+        level: LevelFilter,
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl LogSink for TestSink {
+        fn write_line(&self, args: fmt::Arguments) {
+            self.lines.lock().push(args.to_string());
+        }
+
+        fn level(&self) -> LevelFilter {
+            self.level
+        }
+    }
+
+    static VERBOSE: TestSink = TestSink { level: LevelFilter::Trace, lines: Mutex::new(Vec::new()) };
+    static QUIET: TestSink = TestSink { level: LevelFilter::Warn, lines: Mutex::new(Vec::new()) };
+
+    // the logger is global, so everything is tested at once
+    #[test]
+    fn sinks() {
+        init(LevelFilter::Debug).unwrap();
+        assert!(init(LevelFilter::Debug).is_err());
+
+        add_sink(&VERBOSE).unwrap();
+        add_sink(&QUIET).unwrap();
+
+        debug!("debug {}", 1);
+        info!("info");
+        warn!("warning");
+        // above the global maximum level
+        log::trace!("trace");
+
+        // other tests might have set a TSC frequency, so ignore the timestamps
+        let verbose: Vec<_> = VERBOSE.lines.lock().iter().map(|line| line.rsplit("] ").next().unwrap().to_string()).collect();
+        assert_eq!(verbose, ["DEBUG debug 1\n", "INFO  info\n", "WARN  warning\n"]);
+        let quiet = QUIET.lines.lock();
+        assert_eq!(quiet.len(), 1);
+        assert!(quiet[0].ends_with("WARN  warning\n"));
+        drop(quiet);
+
+        // bypasses the levels and adds no timestamp
+        assert_eq!(write_to_all_sinks(format_args!("panic\n")), 2);
+        assert_eq!(VERBOSE.lines.lock().last().unwrap(), "panic\n");
+        assert_eq!(QUIET.lines.lock()[1..], ["panic\n"]);
+
+        add_sink(&QUIET).unwrap();
+        add_sink(&QUIET).unwrap();
+        assert!(add_sink(&QUIET).is_err());
+    }
+}
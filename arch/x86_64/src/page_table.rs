@@ -1,12 +1,774 @@
+use core::fmt;
+use core::ops::{Index, IndexMut};
+
 use bitflags::bitflags;
 
+use crate::addr::{PAddr, VAddr};
+use crate::frame::{Frame, FrameAllocator};
+use crate::paging::{Page, PageSize, Page1GiB, Page2MiB, Page4KiB};
+#[cfg(feature = "instructions")]
+use crate::registers::Cr3;
+#[cfg(feature = "instructions")]
+use crate::tlb;
+
 bitflags! {
+    /// Flags of a page table entry.
     #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
     pub struct PageDir: u64 {
-        const Present   = 1_u64 << 0;
-        const Write     = 1_u64 << 1;
-        const Accessed  = 1_u64 << 5;
-        const Dirty     = 1_u64 << 6;
-        const HugePage  = 1_u64 << 7;
+        const Present       = 1_u64 << 0;
+        const Write         = 1_u64 << 1;
+        const User          = 1_u64 << 2;
+        /// Page level write-through (PWT).
+        const WriteThrough  = 1_u64 << 3;
+        /// Page level cache disable (PCD).
+        const CacheDisable  = 1_u64 << 4;
+        const Accessed      = 1_u64 << 5;
+        const Dirty         = 1_u64 << 6;
+        /// Maps a 2MiB page in a PD entry or a 1GiB page in a PDPT entry.
+        const HugePage      = 1_u64 << 7;
+        /// Page attribute table bit of a 4KiB page (same bit as `HugePage`).
+        const Pat           = 1_u64 << 7;
+        /// Keep the TLB entry on CR3 reloads (requires CR4.PGE).
+        const Global        = 1_u64 << 8;
+        /// Page attribute table bit of a 2MiB or 1GiB page.
+        const PatHuge       = 1_u64 << 12;
+        /// Protection key of the page (requires CR4.PKE).
+        const ProtectionKey = 0xF_u64 << 59;
+        /// Forbid instruction fetches from the page (requires EFER.NXE).
+        const NoExecute     = 1_u64 << 63;
+    }
+}
+
+impl PageDir {
+    /// Flags of an entry that points to the next page table.
+    const TABLE: PageDir = PageDir::Present.union(PageDir::Write);
+
+    /// Returns the protection key (0-15) encoded in the flags.
+    pub fn protection_key(&self) -> u8 {
+        ((self.bits() & PageDir::ProtectionKey.bits()) >> 59) as u8
+    }
+
+    /// Sets the protection key (0-15) of the flags.
+    pub fn set_protection_key(&mut self, key: u8) {
+        assert!(key < 16, "Protection keys are 4 bits wide");
+        self.remove(PageDir::ProtectionKey);
+        *self |= PageDir::from_bits_retain((key as u64) << 59);
+    }
+}
+
+/// A single 64-bit page table entry.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    /// Bits 12-51 contain the physical address.
+    const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+    /// Creates an unused entry.
+    #[inline]
+    pub const fn new() -> PageTableEntry {
+        PageTableEntry(0)
+    }
+
+    #[inline]
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    #[inline]
+    pub fn set_unused(&mut self) {
+        self.0 = 0;
+    }
+
+    /// Raw value of the entry.
+    #[inline]
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Flags of the entry.
+    ///
+    /// Bit 7 is read as `HugePage`, which is only right for entries above level 1, use
+    /// `flags_at_level` for the entries of a PT.
+    #[inline]
+    pub fn flags(&self) -> PageDir {
+        self.flags_at_level(4)
+    }
+
+    /// Flags of an entry in a table of the given level.
+    ///
+    /// In a PT (level 1) bit 7 is `Pat` and bit 12 belongs to the address. Above that, bit 12 is
+    /// `PatHuge` if the entry maps a hugepage.
+    #[inline]
+    pub fn flags_at_level(&self, level: u32) -> PageDir {
+        let flags = PageDir::from_bits_truncate(self.0);
+        if level > 1 && flags.contains(PageDir::HugePage) { flags } else { flags - PageDir::PatHuge }
+    }
+
+    /// Physical address the entry points to.
+    ///
+    /// Includes the `PatHuge` bit for hugepages, `frame` strips it.
+    #[inline]
+    pub fn addr(&self) -> PAddr {
+        PAddr::new(self.0 & Self::ADDR_MASK)
+    }
+
+    /// Frame the entry points to.
+    ///
+    /// Fails if the entry is not present.
+    #[inline]
+    pub fn frame<S: PageSize>(&self) -> Result<Frame<S>, &'static str> {
+        if !self.flags().contains(PageDir::Present) {
+            return Err("Page table entry is not present");
+        }
+        Ok(Frame::containing_address(self.addr()))
+    }
+
+    /// Points the entry to `addr` with the given flags.
+    #[inline]
+    pub fn set_addr(&mut self, addr: PAddr, flags: PageDir) {
+        assert!(addr.is_aligned(4096), "Page table entry address must be 4KiB aligned");
+        self.0 = addr.as_u64() | flags.bits();
+    }
+
+    /// Points the entry to `frame` with the given flags.
+    #[inline]
+    pub fn set_frame<S: PageSize>(&mut self, frame: Frame<S>, flags: PageDir) {
+        self.set_addr(frame.start_address(), flags);
+    }
+
+    /// Replaces the flags of an entry in a table of the given level and keeps the address.
+    #[inline]
+    pub fn set_flags(&mut self, level: u32, flags: PageDir) {
+        let (addr, flags) = if level > 1 && self.flags_at_level(level).contains(PageDir::HugePage) {
+            (self.addr().as_u64() & !PageDir::PatHuge.bits(), flags)
+        } else {
+            // `PatHuge` would overwrite bit 12 of the address
+            (self.addr().as_u64(), flags - PageDir::PatHuge)
+        };
+        self.0 = addr | flags.bits();
+    }
+}
+
+impl Default for PageTableEntry {
+    fn default() -> Self {
+        PageTableEntry::new()
+    }
+}
+
+impl fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageTableEntry")
+            .field("addr", &format_args!("0x{:X}", self.addr()))
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+/// Number of entries in a page table.
+pub const ENTRY_COUNT: usize = 512;
+
+/// A page table of any level (PML4, PDPT, PD or PT).
+#[repr(C, align(4096))]
+#[derive(Clone)]
+pub struct PageTable {
+    entries: [PageTableEntry; ENTRY_COUNT],
+}
+
+impl PageTable {
+    /// Creates an empty page table.
+    #[inline]
+    pub const fn new() -> PageTable {
+        PageTable { entries: [PageTableEntry::new(); ENTRY_COUNT] }
+    }
+
+    /// Marks all entries as unused.
+    #[inline]
+    pub fn zero(&mut self) {
+        self.entries.iter_mut().for_each(PageTableEntry::set_unused);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PageTableEntry> {
+        self.entries.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut PageTableEntry> {
+        self.entries.iter_mut()
+    }
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        PageTable::new()
+    }
+}
+
+impl Index<usize> for PageTable {
+    type Output = PageTableEntry;
+
+    #[inline]
+    fn index(&self, index: usize) -> &PageTableEntry {
+        &self.entries[index]
+    }
+}
+
+impl IndexMut<usize> for PageTable {
+    #[inline]
+    fn index_mut(&mut self, index: usize) -> &mut PageTableEntry {
+        &mut self.entries[index]
+    }
+}
+
+/// Maps and unmaps pages of size `S`.
+pub trait Mapper<S: PageSize> {
+    /// Maps `page` to `frame`, creating missing page tables with frames from `allocator`.
+    ///
+    /// Fails if the page is already mapped or lies inside a larger hugepage.
+    fn map_to<A: FrameAllocator>(
+        &mut self,
+        page: Page<S>,
+        frame: Frame<S>,
+        flags: PageDir,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<S>, &'static str>;
+
+    /// Removes the mapping of `page` and returns the frame it was mapped to.
+    fn unmap(&mut self, page: Page<S>) -> Result<(Frame<S>, MapperFlush<S>), &'static str>;
+
+    /// Replaces the flags of the mapping of `page`.
+    fn update_flags(&mut self, page: Page<S>, flags: PageDir) -> Result<MapperFlush<S>, &'static str>;
+
+    /// Returns the frame `page` is mapped to.
+    fn translate_page(&self, page: Page<S>) -> Result<Frame<S>, &'static str>;
+}
+
+/// A changed mapping whose TLB entry still has to be invalidated.
+#[must_use = "Page table changes must be flushed or ignored."]
+#[derive(Debug)]
+#[cfg_attr(not(feature = "instructions"), allow(dead_code))]
+pub struct MapperFlush<S: PageSize>(Page<S>);
+
+impl<S: PageSize> MapperFlush<S> {
+    #[inline]
+    fn new(page: Page<S>) -> MapperFlush<S> {
+        MapperFlush(page)
+    }
+
+    /// Invalidates the TLB entry of the page.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn flush(self) {
+        tlb::flush(self.0.start_address());
+    }
+
+    /// Skips the invalidation, e.g. because the page table is not active or the whole TLB is flushed later.
+    #[inline]
+    pub fn ignore(self) {}
+}
+
+/// Translates virtual addresses, regardless of the size of the page they are mapped with.
+pub trait Translate {
+    /// Returns the physical address `vaddr` is mapped to.
+    fn translate_addr(&self, vaddr: VAddr) -> Option<PAddr>;
+}
+
+/// Makes page table frames accessible to a `MappedPageTable`.
+///
+/// # Safety
+///
+/// The returned pointer must point to the page table stored in `frame`.
+pub unsafe trait PageTableFrameMapping {
+    fn frame_to_pointer(&self, frame: Frame) -> *mut PageTable;
+}
+
+/// Used when all page tables are identity mapped (virtual address == physical address).
+pub struct IdentityMapping;
+
+unsafe impl PageTableFrameMapping for IdentityMapping {
+    #[inline]
+    fn frame_to_pointer(&self, frame: Frame) -> *mut PageTable {
+        frame.start_address().as_u64() as *mut PageTable
+    }
+}
+
+/// Used when all physical memory is mapped at a fixed virtual offset.
+pub struct OffsetMapping {
+    offset: VAddr,
+}
+
+impl OffsetMapping {
+    #[inline]
+    pub fn new(physical_memory_offset: VAddr) -> OffsetMapping {
+        OffsetMapping { offset: physical_memory_offset }
+    }
+
+    /// Virtual address at which the physical address `addr` is mapped.
+    #[inline]
+    pub fn phys_to_virt(&self, addr: PAddr) -> VAddr {
+        self.offset + addr.as_u64()
+    }
+}
+
+unsafe impl PageTableFrameMapping for OffsetMapping {
+    #[inline]
+    fn frame_to_pointer(&self, frame: Frame) -> *mut PageTable {
+        self.phys_to_virt(frame.start_address()).as_mut_ptr()
+    }
+}
+
+/// A page table hierarchy whose tables are accessible through `P`.
+pub struct MappedPageTable<'a, P: PageTableFrameMapping> {
+    level_4_table: &'a mut PageTable,
+    mapping: P,
+}
+
+/// A page table hierarchy in which every page table is identity mapped.
+pub type IdentityMappedPageTable<'a> = MappedPageTable<'a, IdentityMapping>;
+
+/// A page table hierarchy that is accessed through a mapping of all physical memory at a fixed offset.
+pub type OffsetPageTable<'a> = MappedPageTable<'a, OffsetMapping>;
+
+impl<'a> OffsetPageTable<'a> {
+    /// Creates a mapper for the currently active hierarchy (CR3).
+    ///
+    /// # Safety
+    ///
+    /// All physical memory has to be mapped at `physical_memory_offset`. Must only be called once
+    /// to avoid aliasing `&mut` references to the level 4 table.
+    #[cfg(feature = "instructions")]
+    pub unsafe fn active(physical_memory_offset: VAddr) -> OffsetPageTable<'a> {
+        let mapping = OffsetMapping::new(physical_memory_offset);
+        let (level_4_frame, _) = Cr3::read();
+        let level_4_table = &mut *mapping.phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>();
+        OffsetPageTable::new(level_4_table, mapping)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> MappedPageTable<'a, P> {
+    /// Creates a mapper for the hierarchy starting at `level_4_table`.
+    ///
+    /// # Safety
+    ///
+    /// `mapping` has to make every page table of the hierarchy accessible.
+    pub unsafe fn new(level_4_table: &'a mut PageTable, mapping: P) -> MappedPageTable<'a, P> {
+        MappedPageTable { level_4_table, mapping }
+    }
+
+    pub fn level_4_table(&mut self) -> &mut PageTable {
+        self.level_4_table
+    }
+
+    /// Returns the table `entry` points to.
+    fn next_table<'b>(&self, entry: &'b PageTableEntry) -> Result<&'b PageTable, &'static str> {
+        let ptr = self.next_table_ptr(entry)?;
+        Ok(unsafe { &*ptr })
+    }
+
+    /// Returns the table `entry` points to.
+    fn next_table_mut<'b>(&self, entry: &'b mut PageTableEntry) -> Result<&'b mut PageTable, &'static str> {
+        let ptr = self.next_table_ptr(entry)?;
+        Ok(unsafe { &mut *ptr })
+    }
+
+    fn next_table_ptr(&self, entry: &PageTableEntry) -> Result<*mut PageTable, &'static str> {
+        let flags = entry.flags();
+        if !flags.contains(PageDir::Present) {
+            return Err("Page is not mapped");
+        }
+        if flags.contains(PageDir::HugePage) {
+            return Err("Page lies inside a hugepage");
+        }
+        Ok(self.mapping.frame_to_pointer(Frame::containing_address(entry.addr())))
+    }
+
+    /// Returns the table `entry` points to and creates it if the entry is unused.
+    ///
+    /// User accessible mappings need user accessible parent tables.
+    fn create_next_table<'b, A: FrameAllocator>(
+        &self,
+        entry: &'b mut PageTableEntry,
+        level: u32,
+        flags: PageDir,
+        allocator: &mut A,
+    ) -> Result<&'b mut PageTable, &'static str> {
+        let table_flags = PageDir::TABLE | (flags & PageDir::User);
+
+        if entry.is_unused() {
+            let frame = allocator.allocate_frame().ok_or("Out of frames for page tables")?;
+            entry.set_frame(frame, table_flags);
+
+            let table = self.next_table_mut(entry)?;
+            table.zero();
+            return Ok(table);
+        }
+
+        // hugepages and unmapped entries are rejected before any flag is changed
+        self.next_table_ptr(entry)?;
+        if !entry.flags().contains(table_flags) {
+            entry.set_flags(level, entry.flags() | table_flags);
+        }
+
+        self.next_table_mut(entry)
+    }
+
+    /// Walks to the table at `level` that contains the entry for `vaddr`, creating missing tables.
+    fn create_table_at<A: FrameAllocator>(
+        &mut self,
+        vaddr: VAddr,
+        level: u32,
+        flags: PageDir,
+        allocator: &mut A,
+    ) -> Result<&mut PageTable, &'static str> {
+        let mut table: *mut PageTable = self.level_4_table;
+        for current in (level + 1..=4).rev() {
+            let entry = unsafe { &mut (&mut *table)[vaddr.page_table_index(current)] };
+            table = self.create_next_table(entry, current, flags, allocator)?;
+        }
+        Ok(unsafe { &mut *table })
+    }
+
+    /// Walks to the table at `level` that contains the entry for `vaddr`.
+    fn table_at(&self, vaddr: VAddr, level: u32) -> Result<&PageTable, &'static str> {
+        let mut table: &PageTable = self.level_4_table;
+        for current in (level + 1..=4).rev() {
+            table = self.next_table(&table[vaddr.page_table_index(current)])?;
+        }
+        Ok(table)
+    }
+
+    fn table_at_mut(&mut self, vaddr: VAddr, level: u32) -> Result<&mut PageTable, &'static str> {
+        let mut table: *mut PageTable = self.level_4_table;
+        for current in (level + 1..=4).rev() {
+            let entry = unsafe { &mut (&mut *table)[vaddr.page_table_index(current)] };
+            table = self.next_table_mut(entry)?;
+        }
+        Ok(unsafe { &mut *table })
+    }
+
+    fn map_at_level<S: PageSize, A: FrameAllocator>(
+        &mut self,
+        page: Page<S>,
+        frame: Frame<S>,
+        flags: PageDir,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<S>, &'static str> {
+        let (level, huge) = page_level::<S>();
+        let vaddr = page.start_address();
+
+        let table = self.create_table_at(vaddr, level, flags, allocator)?;
+        let entry = &mut table[vaddr.page_table_index(level)];
+        if !entry.is_unused() {
+            return Err("Page is already mapped");
+        }
+
+        // like `set_flags`, `PatHuge` would overwrite bit 12 of a 4KiB frame address
+        let flags = if level == 1 { flags - PageDir::PatHuge } else { flags };
+        entry.set_frame(frame, flags | PageDir::Present | huge);
+        Ok(MapperFlush::new(page))
+    }
+
+    fn unmap_at_level<S: PageSize>(&mut self, page: Page<S>) -> Result<(Frame<S>, MapperFlush<S>), &'static str> {
+        let (level, huge) = page_level::<S>();
+        let vaddr = page.start_address();
+
+        let entry = &mut self.table_at_mut(vaddr, level)?[vaddr.page_table_index(level)];
+        check_leaf(entry, huge)?;
+
+        let frame = entry.frame()?;
+        entry.set_unused();
+        Ok((frame, MapperFlush::new(page)))
+    }
+
+    fn update_flags_at_level<S: PageSize>(&mut self, page: Page<S>, flags: PageDir) -> Result<MapperFlush<S>, &'static str> {
+        let (level, huge) = page_level::<S>();
+        let vaddr = page.start_address();
+
+        let entry = &mut self.table_at_mut(vaddr, level)?[vaddr.page_table_index(level)];
+        check_leaf(entry, huge)?;
+
+        entry.set_flags(level, flags | PageDir::Present | huge);
+        Ok(MapperFlush::new(page))
+    }
+
+    fn translate_at_level<S: PageSize>(&self, page: Page<S>) -> Result<Frame<S>, &'static str> {
+        let (level, huge) = page_level::<S>();
+        let vaddr = page.start_address();
+
+        let entry = &self.table_at(vaddr, level)?[vaddr.page_table_index(level)];
+        check_leaf(entry, huge)?;
+
+        entry.frame()
+    }
+}
+
+/// Level of the table that maps pages of size `S` and the flag that marks such entries.
+fn page_level<S: PageSize>() -> (u32, PageDir) {
+    match S::SIZE {
+        Page4KiB::SIZE => (1, PageDir::empty()),
+        Page2MiB::SIZE => (2, PageDir::HugePage),
+        Page1GiB::SIZE => (3, PageDir::HugePage),
+        _ => unreachable!(),
+    }
+}
+
+/// Makes sure that `entry` maps a page instead of pointing to a page table.
+fn check_leaf(entry: &PageTableEntry, huge: PageDir) -> Result<(), &'static str> {
+    if !entry.flags().contains(PageDir::Present) {
+        return Err("Page is not mapped");
+    }
+    // bit 7 of a PT entry is the PAT bit
+    if !huge.is_empty() && !entry.flags().contains(PageDir::HugePage) {
+        return Err("Page is mapped with a different page size");
+    }
+    Ok(())
+}
+
+macro_rules! impl_mapper {
+    ($size:ty) => {
+        impl<'a, P: PageTableFrameMapping> Mapper<$size> for MappedPageTable<'a, P> {
+            fn map_to<A: FrameAllocator>(
+                &mut self,
+                page: Page<$size>,
+                frame: Frame<$size>,
+                flags: PageDir,
+                allocator: &mut A,
+            ) -> Result<MapperFlush<$size>, &'static str> {
+                self.map_at_level(page, frame, flags, allocator)
+            }
+
+            fn unmap(&mut self, page: Page<$size>) -> Result<(Frame<$size>, MapperFlush<$size>), &'static str> {
+                self.unmap_at_level(page)
+            }
+
+            fn update_flags(&mut self, page: Page<$size>, flags: PageDir) -> Result<MapperFlush<$size>, &'static str> {
+                self.update_flags_at_level(page, flags)
+            }
+
+            fn translate_page(&self, page: Page<$size>) -> Result<Frame<$size>, &'static str> {
+                self.translate_at_level(page)
+            }
+        }
+    };
+}
+
+impl_mapper!(Page4KiB);
+impl_mapper!(Page2MiB);
+impl_mapper!(Page1GiB);
+
+impl<'a, P: PageTableFrameMapping> Translate for MappedPageTable<'a, P> {
+    fn translate_addr(&self, vaddr: VAddr) -> Option<PAddr> {
+        let mut table: &PageTable = self.level_4_table;
+        for level in (1..=4).rev() {
+            let entry = &table[vaddr.page_table_index(level)];
+            let flags = entry.flags();
+            if !flags.contains(PageDir::Present) {
+                return None;
+            }
+
+            // PT entries and hugepages map memory directly
+            if level == 1 || (level <= 3 && flags.contains(PageDir::HugePage)) {
+                let page_mask = (1_u64 << (12 + 9 * (level - 1))) - 1;
+                return Some(entry.addr().align_down(page_mask + 1) + (vaddr.as_u64() & page_mask));
+            }
+
+            table = self.next_table(entry).ok()?;
+        }
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Hands out heap allocated page tables, their addresses are identity mapped on the host.
+    struct TestAllocator {
+        tables: Vec<Box<PageTable>>,
+    }
+
+    unsafe impl FrameAllocator for TestAllocator {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            let mut table = Box::new(PageTable::new());
+            let addr = &mut *table as *mut PageTable as u64;
+            self.tables.push(table);
+            Some(Frame::containing_address(PAddr::new(addr)))
+        }
+    }
+
+    fn with_page_table(test: impl FnOnce(&mut IdentityMappedPageTable, &mut TestAllocator)) {
+        let mut allocator = TestAllocator { tables: Vec::new() };
+        let mut level_4_table = Box::new(PageTable::new());
+        let mut page_table = unsafe { IdentityMappedPageTable::new(&mut level_4_table, IdentityMapping) };
+        test(&mut page_table, &mut allocator);
+    }
+
+    #[test]
+    fn map_translate_unmap_4kib() {
+        with_page_table(|page_table, allocator| {
+            let page = Page::<Page4KiB>::containing_address(VAddr::new(0xFFFF_8000_1234_5000));
+            let frame = Frame::<Page4KiB>::containing_address(PAddr::new(0x20_0000));
+
+            page_table.map_to(page, frame, PageDir::Write, allocator).unwrap().ignore();
+            // PDPT, PD and PT
+            assert_eq!(allocator.tables.len(), 3);
+
+            assert_eq!(page_table.translate_page(page).unwrap().start_address().as_u64(), 0x20_0000);
+            assert_eq!(page_table.translate_addr(VAddr::new(0xFFFF_8000_1234_5678)), Some(PAddr::new(0x20_0678)));
+            assert!(page_table.map_to(page, frame, PageDir::Write, allocator).is_err());
+
+            assert_eq!(page_table.unmap(page).unwrap().0.start_address().as_u64(), 0x20_0000);
+            assert_eq!(page_table.translate_addr(VAddr::new(0xFFFF_8000_1234_5678)), None);
+            assert!(page_table.unmap(page).is_err());
+        });
+    }
+
+    #[test]
+    fn map_hugepages() {
+        with_page_table(|page_table, allocator| {
+            let page_2mib = Page::<Page2MiB>::containing_address(VAddr::new(0x4000_0000));
+            let frame_2mib = Frame::<Page2MiB>::containing_address(PAddr::new(0x80_0000));
+            page_table.map_to(page_2mib, frame_2mib, PageDir::Write, allocator).unwrap().ignore();
+
+            let page_1gib = Page::<Page1GiB>::containing_address(VAddr::new(0x80_0000_0000));
+            let frame_1gib = Frame::<Page1GiB>::containing_address(PAddr::new(0xC000_0000));
+            page_table.map_to(page_1gib, frame_1gib, PageDir::Write, allocator).unwrap().ignore();
+
+            assert_eq!(page_table.translate_addr(VAddr::new(0x4012_3456)), Some(PAddr::new(0x92_3456)));
+            assert_eq!(page_table.translate_addr(VAddr::new(0x80_1234_5678)), Some(PAddr::new(0xD234_5678)));
+            assert_eq!(page_table.translate_page(page_1gib).unwrap().start_address().as_u64(), 0xC000_0000);
+
+            // pages inside a hugepage cannot be mapped or translated with a smaller size
+            let inner_page = Page::<Page4KiB>::containing_address(VAddr::new(0x4000_1000));
+            assert!(page_table.translate_page(inner_page).is_err());
+            assert!(page_table.map_to(inner_page, Frame::containing_address(PAddr::zero()), PageDir::Write, allocator).is_err());
+
+            assert_eq!(page_table.unmap(page_2mib).unwrap().0.start_address().as_u64(), 0x80_0000);
+            assert_eq!(page_table.translate_addr(VAddr::new(0x4012_3456)), None);
+        });
+    }
+
+    #[test]
+    fn map_inside_read_only_hugepage() {
+        with_page_table(|page_table, allocator| {
+            let page_2mib = Page::<Page2MiB>::containing_address(VAddr::new(0x4000_0000));
+            let frame_2mib = Frame::<Page2MiB>::containing_address(PAddr::new(0x80_0000));
+            page_table.map_to(page_2mib, frame_2mib, PageDir::empty(), allocator).unwrap().ignore();
+            let huge_entry = page_table.table_at(VAddr::new(0x4000_0000), 2).unwrap()[0];
+
+            // the walk must not make the hugepage writable or user accessible
+            let inner_page = Page::<Page4KiB>::containing_address(VAddr::new(0x4000_1000));
+            let frame = Frame::containing_address(PAddr::new(0x1000));
+            let result = page_table.map_to(inner_page, frame, PageDir::Write | PageDir::User, allocator);
+            assert_eq!(result.err(), Some("Page lies inside a hugepage"));
+
+            assert_eq!(page_table.table_at(VAddr::new(0x4000_0000), 2).unwrap()[0], huge_entry);
+            assert_eq!(page_table.translate_addr(VAddr::new(0x4012_3456)), Some(PAddr::new(0x92_3456)));
+        });
+    }
+
+    #[test]
+    fn update_flags() {
+        with_page_table(|page_table, allocator| {
+            let page = Page::<Page4KiB>::containing_address(VAddr::new(0x1000));
+            let frame = Frame::<Page4KiB>::containing_address(PAddr::new(0x5000));
+            page_table.map_to(page, frame, PageDir::Write | PageDir::User, allocator).unwrap().ignore();
+
+            // parent tables inherit the user flag
+            assert!(page_table.level_4_table()[0].flags().contains(PageDir::User));
+
+            page_table.update_flags(page, PageDir::NoExecute | PageDir::Global).unwrap().ignore();
+            let level_1_table = page_table.table_at(VAddr::new(0x1000), 1).unwrap();
+            assert_eq!(level_1_table[1].flags(), PageDir::Present | PageDir::NoExecute | PageDir::Global);
+            assert_eq!(level_1_table[1].addr(), PAddr::new(0x5000));
+
+            let unmapped = Page::<Page4KiB>::containing_address(VAddr::new(0x2000));
+            assert!(page_table.update_flags(unmapped, PageDir::Write).is_err());
+        });
+    }
+
+    #[test]
+    fn pat_4kib() {
+        with_page_table(|page_table, allocator| {
+            // bit 12 of the frame address is `PatHuge` in a hugepage entry
+            let page = Page::<Page4KiB>::containing_address(VAddr::new(0x1000));
+            let frame = Frame::<Page4KiB>::containing_address(PAddr::new(0x20_1000));
+            page_table.map_to(page, frame, PageDir::Pat, allocator).unwrap().ignore();
+
+            page_table.update_flags(page, PageDir::Pat | PageDir::Write).unwrap().ignore();
+            assert_eq!(page_table.translate_page(page).unwrap(), frame);
+            assert_eq!(page_table.translate_addr(VAddr::new(0x1234)), Some(PAddr::new(0x20_1234)));
+
+            let entry = page_table.table_at(VAddr::new(0x1000), 1).unwrap()[1];
+            assert_eq!(entry.flags_at_level(1), PageDir::Present | PageDir::Write | PageDir::Pat);
+
+            // `PatHuge` does not move a 4KiB mapping to the next frame
+            let page = Page::<Page4KiB>::containing_address(VAddr::new(0x2000));
+            let frame = Frame::<Page4KiB>::containing_address(PAddr::new(0x20_0000));
+            page_table.map_to(page, frame, PageDir::Write | PageDir::PatHuge, allocator).unwrap().ignore();
+            assert_eq!(page_table.translate_page(page).unwrap(), frame);
+            assert_eq!(page_table.translate_addr(VAddr::new(0x2345)), Some(PAddr::new(0x20_0345)));
+
+            // hugepages lose `PatHuge` when it is not part of the new flags
+            let page_2mib = Page::<Page2MiB>::containing_address(VAddr::new(0x4000_0000));
+            let frame_2mib = Frame::<Page2MiB>::containing_address(PAddr::new(0x80_0000));
+            page_table.map_to(page_2mib, frame_2mib, PageDir::PatHuge, allocator).unwrap().ignore();
+            page_table.update_flags(page_2mib, PageDir::Write).unwrap().ignore();
+            assert_eq!(page_table.translate_page(page_2mib).unwrap(), frame_2mib);
+            let entry = page_table.table_at(VAddr::new(0x4000_0000), 2).unwrap()[0];
+            assert_eq!(entry.flags_at_level(2), PageDir::Present | PageDir::Write | PageDir::HugePage);
+        });
+    }
+
+    /// Hands out the tables of a contiguous pool, the physical address of a frame is its offset in the pool.
+    struct PoolAllocator {
+        next: u64,
+    }
+
+    unsafe impl FrameAllocator for PoolAllocator {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            let frame = Frame::containing_address(PAddr::new(self.next * 4096));
+            self.next += 1;
+            Some(frame)
+        }
+    }
+
+    #[test]
+    fn offset_mapping() {
+        let mut pool = std::vec![PageTable::new(); 8];
+        let offset = VAddr::from_ptr(pool.as_mut_ptr());
+
+        // the first table of the pool is the level 4 table
+        let mut allocator = PoolAllocator { next: 1 };
+        let level_4_table = unsafe { &mut *offset.as_mut_ptr::<PageTable>() };
+        let mut page_table = unsafe { OffsetPageTable::new(level_4_table, OffsetMapping::new(offset)) };
+
+        let page = Page::<Page4KiB>::containing_address(VAddr::new(0xFFFF_C000_0000_1000));
+        let frame = Frame::<Page4KiB>::containing_address(PAddr::new(0x7_0000));
+        page_table.map_to(page, frame, PageDir::Write, &mut allocator).unwrap().ignore();
+        assert_eq!(allocator.next, 4);
+
+        assert_eq!(page_table.translate_addr(VAddr::new(0xFFFF_C000_0000_1234)), Some(PAddr::new(0x7_0234)));
+        assert_eq!(page_table.unmap(page).unwrap().0, frame);
+
+        // tables are linked through their physical addresses
+        assert_eq!(pool[0][384].addr(), PAddr::new(0x1000));
+    }
+
+    #[test]
+    fn protection_key() {
+        let mut flags = PageDir::Present;
+        flags.set_protection_key(0xA);
+        assert_eq!(flags.protection_key(), 0xA);
+        assert!(flags.contains(PageDir::Present));
+
+        flags.set_protection_key(3);
+        assert_eq!(flags.protection_key(), 3);
     }
 }
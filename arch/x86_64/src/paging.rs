@@ -1,11 +1,15 @@
 use core::marker::PhantomData;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+use crate::addr::VAddr;
 
 /// Trait used for all the available x86_64 page sizes.
-pub trait PageSize {
+pub trait PageSize: Copy + Eq + Ord {
     const SIZE: u64;
 }
 
 /// Standard 4 KiB page size.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum Page4KiB {}
 
 impl PageSize for Page4KiB {
@@ -13,6 +17,7 @@ impl PageSize for Page4KiB {
 }
 
 /// 2 MiB hugepage.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum Page2MiB {}
 
 impl PageSize for Page2MiB {
@@ -20,6 +25,7 @@ impl PageSize for Page2MiB {
 }
 
 /// 1 GiB hugepage.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum Page1GiB {}
 
 impl PageSize for Page1GiB {
@@ -27,13 +33,227 @@ impl PageSize for Page1GiB {
 }
 
 /// A virtual page.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Page<S: PageSize = Page4KiB> {
-    start_addr: u64,
+    start_addr: VAddr,
     size: PhantomData<S>,
 }
 
 impl<S: PageSize> Page<S> {
     pub const SIZE: u64 = S::SIZE;
 
+    /// Returns the page that contains `address`.
+    #[inline]
+    pub fn containing_address(address: VAddr) -> Page<S> {
+        Page { start_addr: address.align_down(S::SIZE), size: PhantomData }
+    }
+
+    /// Returns the page starting at `address`, fails if the address is not aligned to the page size.
+    #[inline]
+    pub fn from_start_address(address: VAddr) -> Result<Page<S>, &'static str> {
+        if !address.is_aligned(S::SIZE) {
+            return Err("Page start address is not aligned to the page size");
+        }
+        Ok(Page::containing_address(address))
+    }
+
+    #[inline]
+    pub fn start_address(self) -> VAddr {
+        self.start_addr
+    }
+
+    #[inline]
+    pub fn size(self) -> u64 {
+        S::SIZE
+    }
+
+    /// Pages in `[start, end)`.
+    #[inline]
+    pub fn range(start: Page<S>, end: Page<S>) -> PageRange<S> {
+        PageRange { start, end }
+    }
+
+    /// Pages in `[start, end]`.
+    #[inline]
+    pub fn range_inclusive(start: Page<S>, end: Page<S>) -> PageRangeInclusive<S> {
+        PageRangeInclusive { start, end, exhausted: start > end }
+    }
+
+    /// Returns the larger page of size `L` that contains this page.
+    #[inline]
+    pub fn containing_page<L: PageSize>(self) -> Page<L> {
+        assert!(L::SIZE >= S::SIZE, "Target page size must not be smaller");
+        Page::containing_address(self.start_addr)
+    }
+
+    /// Returns the smaller pages of size `T` this page consists of.
+    #[inline]
+    pub fn sub_pages<T: PageSize>(self) -> PageRange<T> {
+        assert!(T::SIZE <= S::SIZE, "Target page size must not be larger");
+        let start = Page::containing_address(self.start_addr);
+        PageRange { start, end: start + S::SIZE / T::SIZE }
+    }
+}
+
+/// Steps `rhs` pages forward.
+impl<S: PageSize> Add<u64> for Page<S> {
+    type Output = Page<S>;
+
+    #[inline]
+    fn add(self, rhs: u64) -> Page<S> {
+        Page::containing_address(self.start_addr + rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> AddAssign<u64> for Page<S> {
+    #[inline]
+    fn add_assign(&mut self, rhs: u64) {
+        *self = *self + rhs;
+    }
+}
+
+/// Steps `rhs` pages backward.
+impl<S: PageSize> Sub<u64> for Page<S> {
+    type Output = Page<S>;
+
+    #[inline]
+    fn sub(self, rhs: u64) -> Page<S> {
+        Page::containing_address(self.start_addr - rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> SubAssign<u64> for Page<S> {
+    #[inline]
+    fn sub_assign(&mut self, rhs: u64) {
+        *self = *self - rhs;
+    }
+}
+
+/// Number of pages between two pages.
+impl<S: PageSize> Sub<Page<S>> for Page<S> {
+    type Output = u64;
+
+    #[inline]
+    fn sub(self, rhs: Page<S>) -> u64 {
+        (self.start_addr - rhs.start_addr) / S::SIZE
+    }
+}
+
+/// A range of pages `[start, end)`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PageRange<S: PageSize = Page4KiB> {
+    pub start: Page<S>,
+    pub end: Page<S>,
+}
+
+impl<S: PageSize> PageRange<S> {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Number of pages in the range.
+    #[inline]
+    pub fn count_pages(&self) -> u64 {
+        if self.is_empty() { 0 } else { self.end - self.start }
+    }
+}
+
+impl<S: PageSize> Iterator for PageRange<S> {
+    type Item = Page<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Page<S>> {
+        if self.is_empty() {
+            return None;
+        }
+        let page = self.start;
+        self.start += 1;
+        Some(page)
+    }
+}
+
+/// A range of pages `[start, end]`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PageRangeInclusive<S: PageSize = Page4KiB> {
+    pub start: Page<S>,
+    pub end: Page<S>,
+    // the last page of the address space has no successor
+    exhausted: bool,
+}
+
+impl<S: PageSize> PageRangeInclusive<S> {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.exhausted || self.start > self.end
+    }
+
+    /// Number of pages in the range.
+    #[inline]
+    pub fn count_pages(&self) -> u64 {
+        if self.is_empty() { 0 } else { self.end - self.start + 1 }
+    }
+}
+
+impl<S: PageSize> Iterator for PageRangeInclusive<S> {
+    type Item = Page<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Page<S>> {
+        if self.is_empty() {
+            return None;
+        }
+        let page = self.start;
+        if page == self.end {
+            self.exhausted = true;
+        } else {
+            self.start += 1;
+        }
+        Some(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_ranges() {
+        let start = Page::<Page4KiB>::containing_address(VAddr::new(0x1234));
+        let end = start + 3;
+        assert_eq!(end.start_address(), VAddr::new(0x4000));
+        assert_eq!(end - start, 3);
+
+        let range = Page::range(start, end);
+        assert_eq!(range.count_pages(), 3);
+        assert_eq!(range.last(), Some(end - 1));
+
+        let range = Page::range_inclusive(start, end);
+        assert_eq!(range.count_pages(), 4);
+        assert_eq!(range.last(), Some(end));
+        assert_eq!(Page::range_inclusive(end, start).next(), None);
+    }
+
+    #[test]
+    fn last_page_of_address_space() {
+        let last = Page::<Page4KiB>::containing_address(VAddr::new(u64::MAX));
+        let mut range = Page::range_inclusive(last - 1, last);
+        assert_eq!(range.next(), Some(last - 1));
+        assert_eq!(range.next(), Some(last));
+        assert_eq!(range.next(), None);
+    }
+
+    #[test]
+    fn page_size_conversions() {
+        assert!(Page::<Page2MiB>::from_start_address(VAddr::new(0x1000)).is_err());
+
+        let page = Page::<Page4KiB>::containing_address(VAddr::new(0x4030_1000));
+        let huge_page = page.containing_page::<Page2MiB>();
+        assert_eq!(huge_page.start_address(), VAddr::new(0x4020_0000));
+        assert_eq!(page.containing_page::<Page1GiB>().start_address(), VAddr::new(0x4000_0000));
 
+        let sub_pages = huge_page.sub_pages::<Page4KiB>();
+        assert_eq!(sub_pages.count_pages(), 512);
+        assert_eq!(sub_pages.start.start_address(), VAddr::new(0x4020_0000));
+    }
 }
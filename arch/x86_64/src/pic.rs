@@ -0,0 +1,171 @@
+/*!
+The two cascaded 8259 programmable interrupt controllers.
+
+After reset the PICs deliver IRQs 0-7 on vectors 8-15, which collide with CPU exceptions, so they
+have to be remapped before interrupts are enabled. The secondary PIC is connected to IRQ 2 of the
+primary one and handles IRQs 8-15.
+*/
+
+use crate::port::Port;
+
+const PRIMARY_COMMAND: u16 = 0x20;
+const PRIMARY_DATA: u16 = 0x21;
+const SECONDARY_COMMAND: u16 = 0xA0;
+const SECONDARY_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const END_OF_INTERRUPT: u8 = 0x20;
+const READ_ISR: u8 = 0x0B;
+
+/// IRQ line of the secondary PIC on the primary one.
+const CASCADE_IRQ: u8 = 2;
+
+/// Port used for short delays while the PICs process the initialization words.
+const WAIT_PORT: u16 = 0x80;
+
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    fn handles_interrupt(&self, vector: u8) -> bool {
+        (self.offset..self.offset + 8).contains(&vector)
+    }
+
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(END_OF_INTERRUPT);
+    }
+
+    /// In-service register, the IRQs that are currently being handled.
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(READ_ISR);
+        self.command.read()
+    }
+}
+
+/// The primary and secondary PIC.
+pub struct ChainedPics {
+    primary: Pic,
+    secondary: Pic,
+}
+
+impl ChainedPics {
+    /// Delivers IRQs 0-7 on the vectors `primary_offset..primary_offset + 8` and IRQs 8-15 on
+    /// `secondary_offset..secondary_offset + 8`.
+    ///
+    /// # Safety
+    ///
+    /// The offsets must not overlap with each other or with the CPU exceptions (vectors 0-31).
+    pub const unsafe fn new(primary_offset: u8, secondary_offset: u8) -> ChainedPics {
+        ChainedPics {
+            primary: Pic { offset: primary_offset, command: Port::new(PRIMARY_COMMAND), data: Port::new(PRIMARY_DATA) },
+            secondary: Pic {
+                offset: secondary_offset,
+                command: Port::new(SECONDARY_COMMAND),
+                data: Port::new(SECONDARY_DATA),
+            },
+        }
+    }
+
+    /// Remaps the PICs to the configured vectors and masks every IRQ.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts have to be disabled.
+    pub unsafe fn initialize(&mut self) {
+        let mut wait_port = Port::<u8>::new(WAIT_PORT);
+        let mut wait = || wait_port.write(0);
+
+        // ICW1: start the initialization sequence, the PICs expect three more words on the data port
+        self.primary.command.write(ICW1_INIT);
+        wait();
+        self.secondary.command.write(ICW1_INIT);
+        wait();
+
+        // ICW2: vector offsets
+        self.primary.data.write(self.primary.offset);
+        wait();
+        self.secondary.data.write(self.secondary.offset);
+        wait();
+
+        // ICW3: the primary PIC gets a bit mask of its cascade line, the secondary one its number
+        self.primary.data.write(1 << CASCADE_IRQ);
+        wait();
+        self.secondary.data.write(CASCADE_IRQ);
+        wait();
+
+        // ICW4: 8086 mode
+        self.primary.data.write(ICW4_8086);
+        wait();
+        self.secondary.data.write(ICW4_8086);
+        wait();
+
+        // only the cascade stays unmasked so that IRQs 8-15 can be enabled individually
+        self.primary.data.write(!(1 << CASCADE_IRQ));
+        self.secondary.data.write(0xFF);
+    }
+
+    /// Masks or unmasks the IRQ line `irq` (0-15).
+    ///
+    /// # Safety
+    ///
+    /// An unmasked IRQ needs a handler in the IDT.
+    pub unsafe fn set_masked(&mut self, irq: u8, masked: bool) {
+        assert!(irq < 16, "The PICs only have 16 IRQ lines");
+        let (pic, line) = if irq < 8 { (&mut self.primary, irq) } else { (&mut self.secondary, irq - 8) };
+
+        let mask = pic.data.read();
+        let mask = if masked { mask | (1 << line) } else { mask & !(1 << line) };
+        pic.data.write(mask);
+    }
+
+    /// Masks all IRQs, e.g. before switching to the APIC.
+    ///
+    /// # Safety
+    ///
+    /// Drivers that rely on IRQs stop working.
+    pub unsafe fn disable(&mut self) {
+        self.primary.data.write(0xFF);
+        self.secondary.data.write(0xFF);
+    }
+
+    /// Whether `vector` belongs to one of the PICs.
+    pub fn handles_interrupt(&self, vector: u8) -> bool {
+        self.primary.handles_interrupt(vector) || self.secondary.handles_interrupt(vector)
+    }
+
+    /// Vector of the IRQ line `irq` (0-15).
+    pub fn vector(&self, irq: u8) -> u8 {
+        assert!(irq < 16, "The PICs only have 16 IRQ lines");
+        if irq < 8 { self.primary.offset + irq } else { self.secondary.offset + irq - 8 }
+    }
+
+    /// Signals the end of the interrupt `vector`, so the PICs can deliver the next one.
+    ///
+    /// Spurious interrupts (IRQ 7 or 15 without the in-service bit set) are only acknowledged on
+    /// the primary PIC if they came through the cascade.
+    ///
+    /// # Safety
+    ///
+    /// Has to be called exactly once at the end of every handler of a PIC interrupt.
+    pub unsafe fn notify_end_of_interrupt(&mut self, vector: u8) {
+        if !self.handles_interrupt(vector) {
+            return;
+        }
+
+        if vector == self.primary.offset + 7 && self.primary.in_service() & 0x80 == 0 {
+            return;
+        }
+        if self.secondary.handles_interrupt(vector) {
+            if vector == self.secondary.offset + 7 && self.secondary.in_service() & 0x80 == 0 {
+                self.primary.end_of_interrupt();
+                return;
+            }
+            self.secondary.end_of_interrupt();
+        }
+        self.primary.end_of_interrupt();
+    }
+}
@@ -0,0 +1,188 @@
+use core::arch::asm;
+use core::fmt;
+use core::marker::PhantomData;
+
+/// Types that can be read from an I/O port.
+pub trait PortRead: Sized {
+    /// # Safety
+    ///
+    /// Reading from a port can have side effects, e.g. acknowledging an interrupt.
+    unsafe fn read_from_port(port: u16) -> Self;
+
+    /// Fills `buffer` with consecutive reads from the same port (`rep ins`).
+    ///
+    /// # Safety
+    ///
+    /// See `read_from_port`.
+    unsafe fn read_string_from_port(port: u16, buffer: &mut [Self]);
+}
+
+/// Types that can be written to an I/O port.
+pub trait PortWrite: Sized {
+    /// # Safety
+    ///
+    /// Writing to a port can reconfigure or break hardware.
+    unsafe fn write_to_port(port: u16, value: Self);
+
+    /// Writes every element of `buffer` to the same port (`rep outs`).
+    ///
+    /// # Safety
+    ///
+    /// See `write_to_port`.
+    unsafe fn write_string_to_port(port: u16, buffer: &[Self]);
+}
+
+macro_rules! impl_port_access {
+    ($t:ty, $reg:tt, $ins:literal, $outs:literal) => {
+        impl PortRead for $t {
+            #[inline]
+            unsafe fn read_from_port(port: u16) -> $t {
+                let value: $t;
+                asm!(concat!("in ", $reg, ", dx"), out($reg) value, in("dx") port, options(nomem, nostack, preserves_flags));
+                value
+            }
+
+            #[inline]
+            unsafe fn read_string_from_port(port: u16, buffer: &mut [$t]) {
+                asm!(
+                    $ins,
+                    in("dx") port,
+                    inout("rdi") buffer.as_mut_ptr() => _,
+                    inout("rcx") buffer.len() => _,
+                    options(nostack, preserves_flags)
+                );
+            }
+        }
+
+        impl PortWrite for $t {
+            #[inline]
+            unsafe fn write_to_port(port: u16, value: $t) {
+                asm!(concat!("out dx, ", $reg), in("dx") port, in($reg) value, options(nomem, nostack, preserves_flags));
+            }
+
+            #[inline]
+            unsafe fn write_string_to_port(port: u16, buffer: &[$t]) {
+                asm!(
+                    $outs,
+                    in("dx") port,
+                    inout("rsi") buffer.as_ptr() => _,
+                    inout("rcx") buffer.len() => _,
+                    options(readonly, nostack, preserves_flags)
+                );
+            }
+        }
+    };
+}
+
+impl_port_access!(u8, "al", "rep insb", "rep outsb");
+impl_port_access!(u16, "ax", "rep insw", "rep outsw");
+impl_port_access!(u32, "eax", "rep insd", "rep outsd");
+
+macro_rules! port_type {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, PartialEq, Eq)]
+        pub struct $name<T> {
+            port: u16,
+            width: PhantomData<T>,
+        }
+
+        impl<T> $name<T> {
+            #[inline]
+            pub const fn new(port: u16) -> $name<T> {
+                $name { port, width: PhantomData }
+            }
+
+            #[inline]
+            pub const fn port(&self) -> u16 {
+                self.port
+            }
+        }
+
+        impl<T> fmt::Debug for $name<T> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, concat!(stringify!($name), "<{}>({:#X})"), core::any::type_name::<T>(), self.port)
+            }
+        }
+    };
+}
+
+macro_rules! impl_port_read {
+    ($name:ident) => {
+        impl<T: PortRead> $name<T> {
+            /// # Safety
+            ///
+            /// See `PortRead::read_from_port`.
+            #[inline]
+            pub unsafe fn read(&mut self) -> T {
+                T::read_from_port(self.port)
+            }
+
+            /// # Safety
+            ///
+            /// See `PortRead::read_from_port`.
+            #[inline]
+            pub unsafe fn read_string(&mut self, buffer: &mut [T]) {
+                T::read_string_from_port(self.port, buffer)
+            }
+        }
+    };
+}
+
+macro_rules! impl_port_write {
+    ($name:ident) => {
+        impl<T: PortWrite> $name<T> {
+            /// # Safety
+            ///
+            /// See `PortWrite::write_to_port`.
+            #[inline]
+            pub unsafe fn write(&mut self, value: T) {
+                T::write_to_port(self.port, value)
+            }
+
+            /// # Safety
+            ///
+            /// See `PortWrite::write_to_port`.
+            #[inline]
+            pub unsafe fn write_string(&mut self, buffer: &[T]) {
+                T::write_string_to_port(self.port, buffer)
+            }
+        }
+    };
+}
+
+port_type!(
+    /// A read-write I/O port of width `T` (`u8`, `u16` or `u32`).
+    Port
+);
+port_type!(
+    /// An I/O port that can only be read from.
+    PortReadOnly
+);
+port_type!(
+    /// An I/O port that can only be written to.
+    PortWriteOnly
+);
+
+impl_port_read!(Port);
+impl_port_read!(PortReadOnly);
+impl_port_write!(Port);
+impl_port_write!(PortWriteOnly);
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::format;
+
+    use super::*;
+
+    const PCI_CONFIG_ADDRESS: Port<u32> = Port::new(0xCF8);
+
+    #[test]
+    fn ports() {
+        assert_eq!(PCI_CONFIG_ADDRESS.port(), 0xCF8);
+        assert_eq!(format!("{:?}", PCI_CONFIG_ADDRESS), "Port<u32>(0xCF8)");
+        assert_eq!(format!("{:?}", PortReadOnly::<u8>::new(0x3FD)), "PortReadOnly<u8>(0x3FD)");
+    }
+}
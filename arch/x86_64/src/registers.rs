@@ -0,0 +1,781 @@
+#[cfg(feature = "instructions")]
+use core::arch::asm;
+
+use bitflags::bitflags;
+
+#[cfg(feature = "instructions")]
+use crate::addr::{PAddr, VAddr};
+#[cfg(feature = "instructions")]
+use crate::frame::Frame;
+#[cfg(any(test, feature = "instructions"))]
+use crate::gdt::SegmentSelector;
+#[cfg(feature = "instructions")]
+use crate::tlb::Pcid;
+
+bitflags! {
+    /// Bits of the CR0 register.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Cr0Flags: u64 {
+        const ProtectedModeEnable   = 1 << 0;
+        const MonitorCoprocessor    = 1 << 1;
+        /// Emulate x87 instructions, causes #NM on every FPU instruction.
+        const Emulation             = 1 << 2;
+        const TaskSwitched          = 1 << 3;
+        const ExtensionType         = 1 << 4;
+        const NumericError          = 1 << 5;
+        /// Honor read-only pages in ring 0.
+        const WriteProtect          = 1 << 16;
+        const AlignmentMask         = 1 << 18;
+        const NotWriteThrough       = 1 << 29;
+        const CacheDisable          = 1 << 30;
+        const Paging                = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// Bits of the CR3 register below the address of the level 4 table (if PCIDs are disabled).
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Cr3Flags: u64 {
+        const PageLevelWriteThrough = 1 << 3;
+        const PageLevelCacheDisable = 1 << 4;
+    }
+}
+
+bitflags! {
+    /// Bits of the CR4 register.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Cr4Flags: u64 {
+        const VirtualModeExtensions         = 1 << 0;
+        const ProtectedModeVirtualInterrupts = 1 << 1;
+        /// Restrict `rdtsc` to ring 0.
+        const TimestampDisable              = 1 << 2;
+        const DebuggingExtensions           = 1 << 3;
+        const PageSizeExtension             = 1 << 4;
+        const PhysicalAddressExtension      = 1 << 5;
+        const MachineCheckException         = 1 << 6;
+        const PageGlobal                    = 1 << 7;
+        const PerformanceCounter            = 1 << 8;
+        const OsFxsr                        = 1 << 9;
+        const OsXmmExceptions               = 1 << 10;
+        const UserModeInstructionPrevention = 1 << 11;
+        const FiveLevelPaging               = 1 << 12;
+        const VirtualMachineExtensions      = 1 << 13;
+        const SaferModeExtensions           = 1 << 14;
+        /// Enables `rdfsbase`/`wrfsbase` and friends.
+        const FsGsBase                      = 1 << 16;
+        const Pcid                          = 1 << 17;
+        const OsXsave                       = 1 << 18;
+        const KeyLocker                     = 1 << 19;
+        const SupervisorModeExecutionProtection = 1 << 20;
+        const SupervisorModeAccessPrevention = 1 << 21;
+        const ProtectionKeyUser             = 1 << 22;
+        const ControlFlowEnforcement        = 1 << 23;
+        const ProtectionKeySupervisor       = 1 << 24;
+    }
+}
+
+bitflags! {
+    /// State components enabled in XCR0, managed by `xsave`/`xrstor`.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct XCr0Flags: u64 {
+        /// Always set.
+        const X87       = 1 << 0;
+        const Sse       = 1 << 1;
+        const Avx       = 1 << 2;
+        const BndReg    = 1 << 3;
+        const BndCsr    = 1 << 4;
+        const OpMask    = 1 << 5;
+        const ZmmHi256  = 1 << 6;
+        const Hi16Zmm   = 1 << 7;
+        const Pkru      = 1 << 9;
+    }
+}
+
+bitflags! {
+    /// Bits of the RFLAGS register.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct RFlags: u64 {
+        const Carry                 = 1 << 0;
+        const Parity                = 1 << 2;
+        const AuxiliaryCarry        = 1 << 4;
+        const Zero                  = 1 << 6;
+        const Sign                  = 1 << 7;
+        /// Single-step mode, raises #DB after every instruction.
+        const Trap                  = 1 << 8;
+        const InterruptEnable       = 1 << 9;
+        const Direction             = 1 << 10;
+        const Overflow              = 1 << 11;
+        const IoPrivilegeLevel      = 3 << 12;
+        const NestedTask            = 1 << 14;
+        const Resume                = 1 << 16;
+        const Virtual8086Mode       = 1 << 17;
+        const AlignmentCheck        = 1 << 18;
+        const VirtualInterrupt      = 1 << 19;
+        const VirtualInterruptPending = 1 << 20;
+        /// Can be toggled if `cpuid` is supported.
+        const Id                    = 1 << 21;
+    }
+}
+
+bitflags! {
+    /// Bits of the extended feature enable register.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct EferFlags: u64 {
+        /// Enables `syscall`/`sysret`.
+        const SystemCallExtensions  = 1 << 0;
+        const LongModeEnable        = 1 << 8;
+        /// Set by the CPU once paging is enabled in long mode.
+        const LongModeActive        = 1 << 10;
+        const NoExecuteEnable       = 1 << 11;
+        const SecureVirtualMachineEnable = 1 << 12;
+        const LongModeSegmentLimitEnable = 1 << 13;
+        const FastFxsaveFxrstor     = 1 << 14;
+        const TranslationCacheExtension = 1 << 15;
+    }
+}
+
+bitflags! {
+    /// Bits of the local APIC base MSR below the address of the APIC registers.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct ApicBaseFlags: u64 {
+        /// Set on the processor that started the system.
+        const BootstrapProcessor    = 1 << 8;
+        const X2ApicEnable          = 1 << 10;
+        const ApicGlobalEnable      = 1 << 11;
+    }
+}
+
+/// The CR0 register, controls the operating mode of the processor.
+#[derive(Debug)]
+pub struct Cr0;
+
+impl Cr0 {
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> Cr0Flags {
+        Cr0Flags::from_bits_truncate(Cr0::read_raw())
+    }
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags)); }
+        value
+    }
+
+    /// Writes `flags` and keeps the reserved bits.
+    ///
+    /// # Safety
+    ///
+    /// Changing CR0 can disable paging or protected mode.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(flags: Cr0Flags) {
+        let reserved = Cr0::read_raw() & !Cr0Flags::all().bits();
+        Cr0::write_raw(reserved | flags.bits());
+    }
+
+    /// # Safety
+    ///
+    /// See `write`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write_raw(value: u64) {
+        asm!("mov cr0, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    /// # Safety
+    ///
+    /// See `write`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn update<F: FnOnce(&mut Cr0Flags)>(f: F) {
+        let mut flags = Cr0::read();
+        f(&mut flags);
+        Cr0::write(flags);
+    }
+}
+
+/// The CR2 register, contains the address that caused the last page fault.
+#[derive(Debug)]
+pub struct Cr2;
+
+impl Cr2 {
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> VAddr {
+        let value: u64;
+        unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)); }
+        VAddr::new_truncate(value)
+    }
+}
+
+/// The CR3 register, points to the active level 4 table.
+#[derive(Debug)]
+pub struct Cr3;
+
+impl Cr3 {
+    /// Bit 63 of a CR3 write, keeps the TLB entries of the new PCID.
+    #[cfg(feature = "instructions")]
+    const NO_FLUSH: u64 = 1 << 63;
+
+    /// Returns the frame of the level 4 table and the caching flags.
+    ///
+    /// Only meaningful if PCIDs are disabled, use `read_pcid` otherwise.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> (Frame, Cr3Flags) {
+        let value = Cr3::read_raw();
+        (Cr3::level_4_frame(value), Cr3Flags::from_bits_truncate(value))
+    }
+
+    /// Returns the frame of the level 4 table and the current process context identifier.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read_pcid() -> (Frame, Pcid) {
+        let value = Cr3::read_raw();
+        let pcid = Pcid::new((value & 0xFFF) as u16).expect("PCIDs are 12 bits wide");
+        (Cr3::level_4_frame(value), pcid)
+    }
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)); }
+        value
+    }
+
+    /// Switches to the page table hierarchy in `frame`, flushes all non-global TLB entries.
+    ///
+    /// # Safety
+    ///
+    /// The new hierarchy has to map the running code, its stack and every accessed data structure.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(frame: Frame, flags: Cr3Flags) {
+        Cr3::write_raw(frame.start_address().as_u64() | flags.bits());
+    }
+
+    /// Switches to the page table hierarchy in `frame` with the given PCID, flushes the non-global
+    /// TLB entries of that PCID.
+    ///
+    /// # Safety
+    ///
+    /// See `write`, CR4.PCIDE has to be set.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write_pcid(frame: Frame, pcid: Pcid) {
+        Cr3::write_raw(frame.start_address().as_u64() | pcid.value() as u64);
+    }
+
+    /// Switches to the page table hierarchy in `frame` with the given PCID and keeps its TLB entries.
+    ///
+    /// # Safety
+    ///
+    /// See `write_pcid`, the cached entries of `pcid` have to match the new hierarchy.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write_pcid_no_flush(frame: Frame, pcid: Pcid) {
+        Cr3::write_raw(frame.start_address().as_u64() | pcid.value() as u64 | Cr3::NO_FLUSH);
+    }
+
+    /// # Safety
+    ///
+    /// See `write`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write_raw(value: u64) {
+        asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    #[cfg(feature = "instructions")]
+    fn level_4_frame(value: u64) -> Frame {
+        Frame::containing_address(PAddr::new_truncate(value & !0xFFF))
+    }
+}
+
+/// The CR4 register, enables architecture extensions.
+#[derive(Debug)]
+pub struct Cr4;
+
+impl Cr4 {
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> Cr4Flags {
+        Cr4Flags::from_bits_truncate(Cr4::read_raw())
+    }
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)); }
+        value
+    }
+
+    /// Writes `flags` and keeps the reserved bits.
+    ///
+    /// # Safety
+    ///
+    /// Features have to be supported by the CPU, otherwise the write raises #GP.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(flags: Cr4Flags) {
+        let reserved = Cr4::read_raw() & !Cr4Flags::all().bits();
+        Cr4::write_raw(reserved | flags.bits());
+    }
+
+    /// # Safety
+    ///
+    /// See `write`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write_raw(value: u64) {
+        asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    /// # Safety
+    ///
+    /// See `write`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn update<F: FnOnce(&mut Cr4Flags)>(f: F) {
+        let mut flags = Cr4::read();
+        f(&mut flags);
+        Cr4::write(flags);
+    }
+}
+
+/// The extended control register XCR0.
+#[derive(Debug)]
+pub struct XCr0;
+
+impl XCr0 {
+    /// Panics if CR4.OSXSAVE is not set, `xgetbv` raises #UD in that case.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> XCr0Flags {
+        XCr0Flags::from_bits_truncate(XCr0::read_raw())
+    }
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read_raw() -> u64 {
+        assert!(Cr4::read().contains(Cr4Flags::OsXsave), "XCR0 can only be accessed with CR4.OSXSAVE set");
+        let (low, high): (u32, u32);
+        unsafe { asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags)); }
+        (high as u64) << 32 | low as u64
+    }
+
+    /// Writes `flags` and keeps the reserved bits.
+    ///
+    /// # Safety
+    ///
+    /// The state components have to be supported and form a valid combination (e.g. AVX requires SSE).
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(flags: XCr0Flags) {
+        let reserved = XCr0::read_raw() & !XCr0Flags::all().bits();
+        XCr0::write_raw(reserved | flags.bits());
+    }
+
+    /// # Safety
+    ///
+    /// See `write`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write_raw(value: u64) {
+        let (low, high) = (value as u32, (value >> 32) as u32);
+        asm!("xsetbv", in("ecx") 0, in("eax") low, in("edx") high, options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns the current value of RFLAGS.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn read_rflags() -> RFlags {
+    RFlags::from_bits_truncate(read_rflags_raw())
+}
+
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn read_rflags_raw() -> u64 {
+    let value: u64;
+    unsafe { asm!("pushfq; pop {}", out(reg) value, options(nomem, preserves_flags)); }
+    value
+}
+
+/// Writes `flags` to RFLAGS and keeps the reserved bits.
+///
+/// # Safety
+///
+/// Can enable interrupts or change the I/O privilege level.
+#[cfg(feature = "instructions")]
+#[inline]
+pub unsafe fn write_rflags(flags: RFlags) {
+    let reserved = read_rflags_raw() & !RFlags::all().bits();
+    write_rflags_raw(reserved | flags.bits());
+}
+
+/// # Safety
+///
+/// See `write_rflags`.
+#[cfg(feature = "instructions")]
+#[inline]
+pub unsafe fn write_rflags_raw(value: u64) {
+    asm!("push {}; popfq", in(reg) value, options(nomem));
+}
+
+/// A model specific register.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Msr(u32);
+
+impl Msr {
+    #[inline]
+    pub const fn new(register: u32) -> Msr {
+        Msr(register)
+    }
+
+    /// # Safety
+    ///
+    /// Reading a register that does not exist raises #GP.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn read(&self) -> u64 {
+        let (low, high): (u32, u32);
+        asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        (high as u64) << 32 | low as u64
+    }
+
+    /// # Safety
+    ///
+    /// Writing to an MSR can change the operating mode of the processor.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(&self, value: u64) {
+        let (low, high) = (value as u32, (value >> 32) as u32);
+        asm!("wrmsr", in("ecx") self.0, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+    }
+}
+
+/// The extended feature enable register (IA32_EFER).
+#[derive(Debug)]
+pub struct Efer;
+
+impl Efer {
+    pub const MSR: Msr = Msr::new(0xC000_0080);
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> EferFlags {
+        EferFlags::from_bits_truncate(unsafe { Efer::MSR.read() })
+    }
+
+    /// Writes `flags` and keeps the reserved bits.
+    ///
+    /// # Safety
+    ///
+    /// Can disable long mode or the NX bit while they are in use.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(flags: EferFlags) {
+        let reserved = Efer::MSR.read() & !EferFlags::all().bits();
+        Efer::MSR.write(reserved | flags.bits());
+    }
+
+    /// # Safety
+    ///
+    /// See `write`.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn update<F: FnOnce(&mut EferFlags)>(f: F) {
+        let mut flags = Efer::read();
+        f(&mut flags);
+        Efer::write(flags);
+    }
+}
+
+/// Segment bases used by `syscall` and `sysret` (IA32_STAR).
+#[derive(Debug)]
+pub struct Star;
+
+impl Star {
+    pub const MSR: Msr = Msr::new(0xC000_0081);
+
+    /// Returns the `sysret` and the `syscall` base selectors.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> (SegmentSelector, SegmentSelector) {
+        Star::decode(unsafe { Star::MSR.read() })
+    }
+
+    /// Sets the base selectors of `sysret` and `syscall`.
+    ///
+    /// `syscall` loads CS with `syscall_base` and SS with `syscall_base + 8`. A 64-bit `sysret` loads
+    /// SS with `sysret_base + 8` and CS with `sysret_base + 16`, so the user data segment has to come
+    /// right before the user code segment.
+    ///
+    /// # Safety
+    ///
+    /// The selectors have to point to matching segments in the loaded GDT.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(sysret_base: SegmentSelector, syscall_base: SegmentSelector) {
+        Star::MSR.write(Star::encode(sysret_base, syscall_base));
+    }
+
+    #[cfg(any(test, feature = "instructions"))]
+    const fn encode(sysret_base: SegmentSelector, syscall_base: SegmentSelector) -> u64 {
+        (sysret_base.0 as u64) << 48 | (syscall_base.0 as u64) << 32
+    }
+
+    #[cfg(any(test, feature = "instructions"))]
+    const fn decode(value: u64) -> (SegmentSelector, SegmentSelector) {
+        (SegmentSelector((value >> 48) as u16), SegmentSelector((value >> 32) as u16))
+    }
+}
+
+/// Defines an MSR that holds a virtual address.
+macro_rules! address_msr {
+    ($(#[$doc:meta])* $name:ident, $register:literal) => {
+        $(#[$doc])*
+        #[derive(Debug)]
+        pub struct $name;
+
+        impl $name {
+            pub const MSR: Msr = Msr::new($register);
+
+            #[cfg(feature = "instructions")]
+            #[inline]
+            pub fn read() -> VAddr {
+                VAddr::new_truncate(unsafe { $name::MSR.read() })
+            }
+
+            /// # Safety
+            ///
+            /// The address is used by the CPU without further checks.
+            #[cfg(feature = "instructions")]
+            #[inline]
+            pub unsafe fn write(address: VAddr) {
+                $name::MSR.write(address.as_u64());
+            }
+        }
+    };
+}
+
+address_msr!(
+    /// Entry point of `syscall` in 64-bit mode (IA32_LSTAR).
+    LStar, 0xC000_0082
+);
+address_msr!(
+    /// Base address of the FS segment (IA32_FS_BASE).
+    FsBase, 0xC000_0100
+);
+address_msr!(
+    /// Base address of the GS segment (IA32_GS_BASE).
+    GsBase, 0xC000_0101
+);
+address_msr!(
+    /// GS base that is swapped in by `swapgs` (IA32_KERNEL_GS_BASE).
+    KernelGsBase, 0xC000_0102
+);
+
+/// RFLAGS bits that are cleared by `syscall` (IA32_FMASK).
+#[derive(Debug)]
+pub struct SFMask;
+
+impl SFMask {
+    pub const MSR: Msr = Msr::new(0xC000_0084);
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> RFlags {
+        RFlags::from_bits_truncate(unsafe { SFMask::MSR.read() })
+    }
+
+    /// # Safety
+    ///
+    /// Not masking `InterruptEnable` lets interrupts hit before the kernel stack is set up.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(flags: RFlags) {
+        SFMask::MSR.write(flags.bits());
+    }
+}
+
+/// Location and state of the local APIC (IA32_APIC_BASE).
+#[derive(Debug)]
+pub struct ApicBase;
+
+impl ApicBase {
+    pub const MSR: Msr = Msr::new(0x1B);
+
+    /// Returns the frame of the APIC registers and the flags.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> (Frame, ApicBaseFlags) {
+        let value = unsafe { ApicBase::MSR.read() };
+        let frame = Frame::containing_address(PAddr::new_truncate(value & !0xFFF));
+        (frame, ApicBaseFlags::from_bits_truncate(value))
+    }
+
+    /// # Safety
+    ///
+    /// Moving or disabling the APIC breaks everything that still uses the old registers.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(frame: Frame, flags: ApicBaseFlags) {
+        ApicBase::MSR.write(frame.start_address().as_u64() | flags.bits());
+    }
+}
+
+/// Memory types that can be stored in the page attribute table.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatMemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    /// Uncacheable, but can be overridden by an MTRR with write-combining.
+    UncachedMinus = 7,
+}
+
+impl PatMemoryType {
+    #[inline]
+    pub const fn from_u8(value: u8) -> Option<PatMemoryType> {
+        match value {
+            0 => Some(PatMemoryType::Uncacheable),
+            1 => Some(PatMemoryType::WriteCombining),
+            4 => Some(PatMemoryType::WriteThrough),
+            5 => Some(PatMemoryType::WriteProtected),
+            6 => Some(PatMemoryType::WriteBack),
+            7 => Some(PatMemoryType::UncachedMinus),
+            _ => None,
+        }
+    }
+}
+
+/// The page attribute table (IA32_PAT).
+///
+/// Entry `i` is selected by the `Pat`, `CacheDisable` and `WriteThrough` bits of a page table entry
+/// (`i = PAT << 2 | PCD << 1 | PWT`).
+#[derive(Debug)]
+pub struct Pat;
+
+impl Pat {
+    pub const MSR: Msr = Msr::new(0x277);
+
+    /// The table after reset.
+    pub const DEFAULT: [PatMemoryType; 8] = [
+        PatMemoryType::WriteBack,
+        PatMemoryType::WriteThrough,
+        PatMemoryType::UncachedMinus,
+        PatMemoryType::Uncacheable,
+        PatMemoryType::WriteBack,
+        PatMemoryType::WriteThrough,
+        PatMemoryType::UncachedMinus,
+        PatMemoryType::Uncacheable,
+    ];
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> [PatMemoryType; 8] {
+        Pat::decode(unsafe { Pat::MSR.read() })
+    }
+
+    /// # Safety
+    ///
+    /// Changing the memory type of pages that are in use can break cache coherency.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(table: [PatMemoryType; 8]) {
+        Pat::MSR.write(Pat::encode(table));
+    }
+
+    #[cfg(any(test, feature = "instructions"))]
+    fn encode(table: [PatMemoryType; 8]) -> u64 {
+        table.iter().enumerate().fold(0, |value, (i, &memory_type)| value | (memory_type as u64) << (i * 8))
+    }
+
+    #[cfg(any(test, feature = "instructions"))]
+    fn decode(value: u64) -> [PatMemoryType; 8] {
+        core::array::from_fn(|i| {
+            PatMemoryType::from_u8((value >> (i * 8)) as u8 & 0x7).expect("Reserved memory type in the PAT")
+        })
+    }
+}
+
+/// Deadline for the local APIC timer in TSC-deadline mode (IA32_TSC_DEADLINE).
+#[derive(Debug)]
+pub struct TscDeadline;
+
+impl TscDeadline {
+    pub const MSR: Msr = Msr::new(0x6E0);
+
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read() -> u64 {
+        unsafe { TscDeadline::MSR.read() }
+    }
+
+    /// Arms the timer, zero disarms it.
+    ///
+    /// # Safety
+    ///
+    /// The local APIC timer has to be in TSC-deadline mode.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn write(deadline: u64) {
+        TscDeadline::MSR.write(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdt::PrivilegeLevel;
+
+    #[test]
+    fn flag_values() {
+        assert_eq!(Cr0Flags::Paging.bits(), 0x8000_0000);
+        assert_eq!(Cr4Flags::PhysicalAddressExtension.bits(), 1 << 5);
+        assert_eq!(EferFlags::LongModeEnable.bits(), 1 << 8);
+        assert_eq!(EferFlags::NoExecuteEnable.bits(), 1 << 11);
+        assert_eq!(RFlags::IoPrivilegeLevel.bits(), 0x3000);
+    }
+
+    #[cfg(feature = "instructions")]
+    #[test]
+    fn rflags() {
+        // user space always runs with interrupts enabled
+        assert!(read_rflags().contains(RFlags::InterruptEnable));
+        assert_eq!(read_rflags_raw() & 0b10, 0b10);
+    }
+
+    #[test]
+    fn star_encoding() {
+        let sysret_base = SegmentSelector::new(2, PrivilegeLevel::Ring3);
+        let syscall_base = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+        let value = Star::encode(sysret_base, syscall_base);
+        assert_eq!(value, 0x0013_0008_0000_0000);
+        assert_eq!(Star::decode(value), (sysret_base, syscall_base));
+    }
+
+    #[test]
+    fn pat_encoding() {
+        assert_eq!(Pat::encode(Pat::DEFAULT), 0x0007_0406_0007_0406);
+        assert_eq!(Pat::decode(0x0007_0406_0007_0406), Pat::DEFAULT);
+        assert_eq!(PatMemoryType::from_u8(2), None);
+    }
+}
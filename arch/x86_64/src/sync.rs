@@ -0,0 +1,382 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[cfg(all(feature = "instructions", not(test)))]
+use crate::interrupts;
+
+/// Disables interrupts and returns whether they were enabled before.
+#[cfg(all(feature = "instructions", not(test)))]
+#[inline]
+fn disable_interrupts() -> bool {
+    let enabled = interrupts::are_enabled();
+    if enabled {
+        interrupts::disable();
+    }
+    enabled
+}
+
+#[cfg(all(feature = "instructions", not(test)))]
+#[inline]
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+// unit tests run in user mode where `cli` faults, so the locks only spin there
+#[cfg(not(all(feature = "instructions", not(test))))]
+#[inline]
+fn disable_interrupts() -> bool {
+    false
+}
+
+#[cfg(not(all(feature = "instructions", not(test))))]
+#[inline]
+fn restore_interrupts(_enabled: bool) {}
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// Interrupt handlers can therefore never deadlock on a lock that the code they interrupted holds.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    #[inline]
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex { locked: AtomicBool::new(false), data: UnsafeCell::new(data) }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Disables interrupts and spins until the lock is acquired.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let interrupts_enabled = disable_interrupts();
+
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+        MutexGuard { mutex: self, interrupts_enabled }
+    }
+
+    /// Acquires the lock if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let interrupts_enabled = disable_interrupts();
+
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            return Some(MutexGuard { mutex: self, interrupts_enabled });
+        }
+
+        restore_interrupts(interrupts_enabled);
+        None
+    }
+
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Releases the lock without a guard, e.g. to print from a panic handler.
+    ///
+    /// # Safety
+    ///
+    /// The current holder of the lock must never access the data again.
+    #[inline]
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+/// Releases the lock and restores the interrupt state when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    interrupts_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is initialized exactly once.
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    #[inline]
+    pub const fn new() -> Once<T> {
+        Once { state: AtomicU8::new(INCOMPLETE), data: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// Runs `f` with interrupts disabled if the value was not initialized yet and returns the value.
+    ///
+    /// Concurrent callers spin until the first one is done. Calling it recursively from `f` deadlocks.
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        // an interrupt between winning the race and disabling interrupts could call `call_once` again
+        let interrupts_enabled = disable_interrupts();
+        if self.state.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire).is_ok() {
+            let value = f();
+            unsafe { (*self.data.get()).write(value) };
+            self.state.store(COMPLETE, Ordering::Release);
+            restore_interrupts(interrupts_enabled);
+        } else {
+            restore_interrupts(interrupts_enabled);
+            while self.state.load(Ordering::Acquire) != COMPLETE {
+                hint::spin_loop();
+            }
+        }
+        unsafe { (*self.data.get()).assume_init_ref() }
+    }
+
+    /// The value, `None` if it was not initialized yet.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.data.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Once::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Once<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("Once").field(value).finish(),
+            None => f.write_str("Once(<uninit>)"),
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.data.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value that is initialized on first access.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: F,
+}
+
+impl<T, F> Lazy<T, F> {
+    #[inline]
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy { once: Once::new(), init }
+    }
+
+    /// The value, `None` if it was not initialized yet or is being initialized right now.
+    ///
+    /// Unlike dereferencing, this never runs the initializer or waits for it.
+    #[inline]
+    pub fn get(this: &Lazy<T, F>) -> Option<&T> {
+        this.once.get()
+    }
+}
+
+impl<T, F: Fn() -> T> Lazy<T, F> {
+    /// Initializes the value if that did not happen yet.
+    #[inline]
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.once.call_once(|| (this.init)())
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Lazy").field(&self.once).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::format;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn mutex() {
+        let mut mutex = Mutex::new(1);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.is_locked());
+            assert!(mutex.try_lock().is_none());
+            assert_eq!(format!("{:?}", mutex), "Mutex { <locked> }");
+        }
+        assert!(!mutex.is_locked());
+        assert_eq!(format!("{:?}", mutex), "Mutex { data: 2 }");
+
+        // a leaked guard keeps the lock forever
+        core::mem::forget(mutex.lock());
+        assert!(mutex.try_lock().is_none());
+        unsafe { mutex.force_unlock() };
+        assert_eq!(*mutex.try_lock().unwrap(), 2);
+
+        *mutex.get_mut() = 3;
+        assert_eq!(mutex.into_inner(), 3);
+    }
+
+    #[test]
+    fn mutex_threads() {
+        let counter = Arc::new(Mutex::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *counter.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*counter.lock(), 4000);
+    }
+
+    #[test]
+    fn once() {
+        let once = Once::new();
+        assert!(!once.is_completed());
+        assert_eq!(once.get(), None);
+        assert_eq!(format!("{:?}", once), "Once(<uninit>)");
+
+        assert_eq!(*once.call_once(|| 1), 1);
+        // later initializers are ignored
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert!(once.is_completed());
+        assert_eq!(format!("{:?}", once), "Once(1)");
+    }
+
+    #[test]
+    fn once_threads() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static ONCE: Once<usize> = Once::new();
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| thread::spawn(move || *ONCE.call_once(|| { CALLS.fetch_add(1, Ordering::Relaxed); i })))
+            .collect();
+
+        let values: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert!(values.iter().all(|&value| value == values[0]));
+    }
+
+    #[test]
+    fn once_drops_value() {
+        let value = Arc::new(());
+        let once = Once::new();
+        once.call_once(|| value.clone());
+        assert_eq!(Arc::strong_count(&value), 2);
+        drop(once);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn lazy() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static LAZY: Lazy<usize> = Lazy::new(|| CALLS.fetch_add(1, Ordering::Relaxed) + 10);
+
+        assert_eq!(format!("{:?}", LAZY), "Lazy(Once(<uninit>))");
+        assert_eq!(Lazy::get(&LAZY), None);
+        assert_eq!(*LAZY, 10);
+        assert_eq!(Lazy::get(&LAZY), Some(&10));
+        assert_eq!(*Lazy::force(&LAZY), 10);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,166 @@
+#[cfg(feature = "instructions")]
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "instructions")]
+use crate::cpuid::{self, CpuInfo};
+#[cfg(feature = "instructions")]
+use crate::port::{Port, PortWriteOnly};
+
+/// Input clock of the programmable interval timer in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Frequency of the TSC in Hz, zero until it was calibrated or handed over by the bootloader.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the time stamp counter.
+///
+/// Not serializing, earlier instructions might not have finished yet.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags)); }
+    (high as u64) << 32 | low as u64
+}
+
+/// Reads the time stamp counter after all earlier instructions finished, also returns IA32_TSC_AUX
+/// (usually the processor id).
+///
+/// Requires `CpuFeatures::RDTSCP`.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn rdtscp() -> (u64, u32) {
+    let (low, high, aux): (u32, u32, u32);
+    unsafe { asm!("rdtscp", out("eax") low, out("edx") high, out("ecx") aux, options(nomem, nostack, preserves_flags)); }
+    ((high as u64) << 32 | low as u64, aux)
+}
+
+/// Sets the TSC frequency used for timestamps.
+#[inline]
+pub fn set_tsc_frequency(frequency: u64) {
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// The TSC frequency in Hz, `None` if it is unknown.
+#[inline]
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Nanoseconds since the TSC was reset (at power on), `None` if the TSC frequency is unknown.
+///
+/// Monotonic as long as the TSC is invariant, which is the case on every CPU with `CpuFeatures::INVARIANT_TSC`.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn nanoseconds() -> Option<u64> {
+    tsc_frequency().map(|frequency| ticks_to_nanoseconds(rdtsc(), frequency))
+}
+
+#[cfg(any(test, feature = "instructions"))]
+fn ticks_to_nanoseconds(ticks: u64, frequency: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / frequency as u128) as u64
+}
+
+/// Determines the TSC frequency in Hz, from CPUID if possible and by measuring it against the PIT otherwise.
+#[cfg(feature = "instructions")]
+pub fn calibrate_tsc(cpu_info: &CpuInfo) -> u64 {
+    tsc_frequency_from_cpuid(cpu_info).unwrap_or_else(|| calibrate_tsc_with_pit(50))
+}
+
+/// Reads the TSC frequency from CPUID leaf 0x15, `None` if the CPU does not enumerate the crystal clock.
+#[cfg(feature = "instructions")]
+pub fn tsc_frequency_from_cpuid(cpu_info: &CpuInfo) -> Option<u64> {
+    if cpu_info.max_leaf < 0x15 {
+        return None;
+    }
+    let result = cpuid::cpuid(0x15, 0);
+    tsc_frequency_from_leaf_15(result.eax, result.ebx, result.ecx)
+}
+
+/// TSC frequency = crystal frequency * numerator / denominator.
+#[cfg(any(test, feature = "instructions"))]
+fn tsc_frequency_from_leaf_15(denominator: u32, numerator: u32, crystal_frequency: u32) -> Option<u64> {
+    if denominator == 0 || numerator == 0 || crystal_frequency == 0 {
+        return None;
+    }
+    Some(crystal_frequency as u64 * numerator as u64 / denominator as u64)
+}
+
+/// Measures the TSC frequency in Hz by counting the ticks during `milliseconds` of PIT channel 2.
+///
+/// Channel 2 is used since it can be polled without interrupts. Panics if `milliseconds` exceeds
+/// the range of the 16-bit counter (54ms).
+#[cfg(feature = "instructions")]
+pub fn calibrate_tsc_with_pit(milliseconds: u64) -> u64 {
+    let count = pit_count(milliseconds);
+
+    // bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is its output
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = PortWriteOnly::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+
+    unsafe {
+        let saved_control = control.read();
+        control.write((saved_control & !0b10) | 0b01);
+
+        // channel 2, low and high byte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        let start = rdtsc();
+        while control.read() & 0x20 == 0 {}
+        let end = rdtsc();
+
+        control.write(saved_control);
+        (end - start) * 1000 / milliseconds
+    }
+}
+
+/// PIT count for a delay of `milliseconds`.
+#[cfg(any(test, feature = "instructions"))]
+fn pit_count(milliseconds: u64) -> u16 {
+    let count = PIT_FREQUENCY * milliseconds / 1000;
+    assert!(milliseconds > 0 && count <= u16::MAX as u64, "PIT delay has to be between 1 and 54ms");
+    count as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(ticks_to_nanoseconds(3_000_000_000, 3_000_000_000), 1_000_000_000);
+        assert_eq!(ticks_to_nanoseconds(u64::MAX, 1_000_000_000), u64::MAX);
+        assert_eq!(pit_count(50), 59659);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pit_delay_too_long() {
+        pit_count(55);
+    }
+
+    #[test]
+    fn cpuid_leaf_15() {
+        // 24MHz crystal with a ratio of 2:250
+        assert_eq!(tsc_frequency_from_leaf_15(2, 250, 24_000_000), Some(3_000_000_000));
+        assert_eq!(tsc_frequency_from_leaf_15(2, 250, 0), None);
+    }
+
+    #[cfg(feature = "instructions")]
+    #[test]
+    fn timestamps() {
+        let first = rdtsc();
+        assert!(rdtsc() >= first);
+
+        set_tsc_frequency(1_000_000_000);
+        assert_eq!(tsc_frequency(), Some(1_000_000_000));
+        assert!(nanoseconds().unwrap() > 0);
+    }
+}
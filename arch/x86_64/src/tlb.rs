@@ -0,0 +1,102 @@
+#[cfg(feature = "instructions")]
+use core::arch::asm;
+
+use crate::addr::VAddr;
+#[cfg(feature = "instructions")]
+use crate::registers::{Cr3, Cr4, Cr4Flags};
+
+/// A process context identifier, tags TLB entries if CR4.PCIDE is set.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Pcid(u16);
+
+impl Pcid {
+    /// Creates a new PCID, fails if it does not fit into 12 bits.
+    #[inline]
+    pub const fn new(pcid: u16) -> Result<Pcid, &'static str> {
+        if pcid < 4096 {
+            Ok(Pcid(pcid))
+        } else {
+            Err("PCIDs are 12 bits wide")
+        }
+    }
+
+    #[inline]
+    pub const fn value(self) -> u16 {
+        self.0
+    }
+}
+
+/// Invalidates the TLB entry of the page that contains `addr` (for the current PCID).
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn flush(addr: VAddr) {
+    unsafe { asm!("invlpg [{}]", in(reg) addr.as_u64(), options(nostack, preserves_flags)); }
+}
+
+/// Invalidates all non-global TLB entries (of the current PCID) by reloading CR3.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn flush_all() {
+    unsafe { Cr3::write_raw(Cr3::read_raw()) };
+}
+
+/// Invalidates all TLB entries, including global ones and the entries of every PCID.
+#[cfg(feature = "instructions")]
+#[inline]
+pub fn flush_global() {
+    // changing CR4.PGE in either direction flushes everything, a CR3 reload only flushes the
+    // current PCID
+    let flags = Cr4::read();
+    unsafe {
+        Cr4::write(flags ^ Cr4Flags::PageGlobal);
+        Cr4::write(flags);
+    }
+}
+
+/// The type of invalidation done by `invpcid`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InvPcidCommand {
+    /// The entry of a single page in the given PCID.
+    Address(VAddr, Pcid),
+    /// All non-global entries of the given PCID.
+    Single(Pcid),
+    /// All entries of every PCID, including global ones.
+    AllIncludingGlobal,
+    /// All non-global entries of every PCID.
+    AllExceptGlobal,
+}
+
+#[cfg(feature = "instructions")]
+#[repr(C)]
+struct InvPcidDescriptor {
+    pcid: u64,
+    address: u64,
+}
+
+/// Invalidates TLB entries with `invpcid`.
+///
+/// # Safety
+///
+/// The CPU has to support `invpcid` (`CpuFeatures::INVPCID`), otherwise it raises #UD.
+#[cfg(feature = "instructions")]
+#[inline]
+pub unsafe fn flush_pcid(command: InvPcidCommand) {
+    let (kind, descriptor) = match command {
+        InvPcidCommand::Address(addr, pcid) => (0_u64, InvPcidDescriptor { pcid: pcid.value() as u64, address: addr.as_u64() }),
+        InvPcidCommand::Single(pcid) => (1, InvPcidDescriptor { pcid: pcid.value() as u64, address: 0 }),
+        InvPcidCommand::AllIncludingGlobal => (2, InvPcidDescriptor { pcid: 0, address: 0 }),
+        InvPcidCommand::AllExceptGlobal => (3, InvPcidDescriptor { pcid: 0, address: 0 }),
+    };
+    asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &descriptor, options(readonly, nostack, preserves_flags));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcid_range() {
+        assert_eq!(Pcid::new(4095).map(Pcid::value), Ok(4095));
+        assert!(Pcid::new(4096).is_err());
+    }
+}
@@ -0,0 +1,27 @@
+// Source of `fixture.elf`, a minimal static executable for the ELF parser tests. Rebuild with:
+//
+// rustc --edition 2021 -O -C panic=abort -C relocation-model=static -C link-arg=-nostartfiles \
+//     -C link-arg=-static -C link-arg=-no-pie -C link-arg=-Wl,--build-id=none fixture.rs -o fixture.elf
+
+#![no_std]
+#![no_main]
+
+static mut COUNTER: u64 = 0;
+static MESSAGE: &str = "bean";
+
+#[inline(never)]
+#[no_mangle]
+pub extern "C" fn add(a: u64, b: u64) -> u64 {
+    a + b
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe { COUNTER = add(COUNTER, MESSAGE.len() as u64); }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -1,4 +1,19 @@
-use std::{env, process::{self, Command}, path::PathBuf};
+use std::{env, fs, process::{self, Command}, path::{Path, PathBuf}};
+
+#[allow(dead_code)]
+#[path = "src/kernel_blob.rs"]
+mod kernel_blob;
+
+use kernel_blob::{Compression, ImageHeader, SlotEntry, FALLBACK_SLOT, HEADER_SIZE, MAX_SLOTS, PRIMARY_SLOT, SLOT_ALIGN};
+
+/// Size of a boot menu entry in the layout expected by `stage2.s`.
+const BOOT_ENTRY_SIZE: usize = 256;
+/// Maximum number of boot menu entries that fit on the screen.
+const MAX_BOOT_ENTRIES: usize = 16;
+/// Maximum length of an entry name (excluding the null terminator).
+const MAX_NAME_LEN: usize = 63;
+/// Maximum length of a kernel command line (excluding the null terminator).
+const MAX_CMDLINE_LEN: usize = 127;
 
 fn main() {
     // find out if this is a debug or release build
@@ -7,6 +22,10 @@ fn main() {
     // rebuild if one of these files was modified
     println!("cargo:rerun-if-changed=linker.ld");
     println!("cargo:rerun-if-changed=../os/target/x86_64-bean_os/{}/bean_os", profile);
+    println!("cargo:rerun-if-changed=src/kernel_blob.rs");
+    println!("cargo:rerun-if-env-changed=BEAN_OS_FALLBACK_KERNEL");
+    println!("cargo:rerun-if-env-changed=BEAN_OS_INITRD");
+    println!("cargo:rerun-if-env-changed=BEAN_OS_BOOT_CONFIG");
 
     // output directory (build script should not modify any files outside this directory)
     let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR undefined"));
@@ -41,43 +60,75 @@ fn main() {
 
     // strip kernel binary
     let kernel_stripped = out_dir.join("bean_os-stripped");
-    let mut cmd = Command::new(&objcopy);
-    cmd.arg("--strip-debug");
-    cmd.arg(&kernel);
-    cmd.arg(&kernel_stripped);
-    let cmd_status = cmd
-        .status()
-        .expect("Failed to run llvm-objcopy to strip debug symbols");
-    if !cmd_status.success() {
-        eprintln!("Failed to strip debug symbols");
-        process::exit(1);
-    }
+    strip_kernel(&objcopy, &kernel, &kernel_stripped);
 
-    // wrap the kernel ELF as a binary blob in a new ELF file
+    // the fallback slot holds a known-good kernel if one was specified, otherwise a second copy of the primary kernel
+    let fallback_kernel_stripped = match env::var("BEAN_OS_FALLBACK_KERNEL") {
+        Ok(fallback_kernel) => {
+            println!("cargo:rerun-if-changed={}", fallback_kernel);
+            let fallback_kernel_stripped = out_dir.join("bean_os-fallback-stripped");
+            strip_kernel(&objcopy, Path::new(&fallback_kernel), &fallback_kernel_stripped);
+            fallback_kernel_stripped
+        }
+        Err(_) => kernel_stripped.clone(),
+    };
+
+    // the boot configuration lists the boot menu entries
+    let boot_config_path = match env::var("BEAN_OS_BOOT_CONFIG") {
+        Ok(boot_config_path) => PathBuf::from(boot_config_path),
+        Err(_) => PathBuf::from(&bootloader_dir).join("boot.cfg"),
+    };
+    println!("cargo:rerun-if-changed={}", boot_config_path.display());
+
+    let boot_config_text = fs::read_to_string(&boot_config_path).expect("Failed to read boot configuration");
+    let boot_config = parse_boot_config(&boot_config_text);
+
+    // every kernel that is not the primary or fallback kernel gets its own slot
+    let mut kernels = vec![kernel_stripped, fallback_kernel_stripped];
+    let entry_slots: Vec<usize> = boot_config.entries.iter().map(|entry| {
+        match entry.kernel.as_str() {
+            "default" => PRIMARY_SLOT,
+            "fallback" => FALLBACK_SLOT,
+            path => {
+                let kernel = PathBuf::from(&bootloader_dir).join(path);
+                println!("cargo:rerun-if-changed={}", kernel.display());
+                let kernel_stripped = out_dir.join(format!("bean_os-slot{}-stripped", kernels.len()));
+                strip_kernel(&objcopy, &kernel, &kernel_stripped);
+                kernels.push(kernel_stripped);
+                kernels.len() - 1
+            }
+        }
+    }).collect();
+
+    let boot_config_bin = out_dir.join("boot_config");
+    write_boot_config(&boot_config, &entry_slots, &boot_config_bin);
+
+    // compress all kernels and put them into slots behind a header with their checksums
+    let kernel_image = out_dir.join("bean_os-image");
+    create_kernel_image(&kernels, &kernel_image);
+
+    // the initial ramdisk is packed by the image builder, use an empty one if none was specified
+    let initrd = match env::var("BEAN_OS_INITRD") {
+        Ok(initrd) => {
+            println!("cargo:rerun-if-changed={}", initrd);
+            PathBuf::from(initrd)
+        }
+        Err(_) => {
+            let empty_initrd = out_dir.join("initrd-empty");
+            fs::write(&empty_initrd, []).expect("Failed to create empty initrd");
+            empty_initrd
+        }
+    };
+
+    // wrap the kernel image, the initrd and the boot configuration as binary blobs in new ELF files
     let kernel_bin = out_dir.join("bean_os.o");
-    let kernel_stripped_only_underscores = kernel_stripped.to_str().expect("Invalid path")
-        .replace('.', "_")
-        .replace('/', "_")
-        .replace('-', "_");
+    wrap_blob(&objcopy, &out_dir, &kernel_image, &kernel_bin, "kernel");
 
-    let mut cmd = Command::new(&objcopy);
-    cmd.current_dir(&out_dir);
-    cmd.arg("-I").arg("binary");
-    cmd.arg("-O").arg("elf64-x86-64");
-    cmd.arg("--binary-architecture=i386:x86-64");
-    cmd.arg("--rename-section").arg(".data=.kernel");
-    cmd.arg("--redefine-sym").arg(format!("_binary_{}_start=_kernel_start_addr", kernel_stripped_only_underscores));
-    cmd.arg("--redefine-sym").arg(format!("_binary_{}_end=_kernel_end_addr", kernel_stripped_only_underscores));
-    cmd.arg("--redefine-sym").arg(format!("_binary_{}_size=_kernel_size", kernel_stripped_only_underscores));
-    cmd.arg(&kernel_stripped);
-    cmd.arg(&kernel_bin);
-    let cmd_status = cmd
-        .status()
-        .expect("Failed to run llvm-objcopy to wrap kernel blob");
-    if !cmd_status.success() {
-        eprintln!("Failed to wrap kernel blob");
-        process::exit(1);
-    }
+    let initrd_bin = out_dir.join("initrd.o");
+    wrap_blob(&objcopy, &out_dir, &initrd, &initrd_bin, "initrd");
+
+    let boot_config_obj = out_dir.join("boot_config.o");
+    wrap_blob(&objcopy, &out_dir, &boot_config_bin, &boot_config_obj, "boot_config");
 
     // create an archive for linking
     let kernel_archive = out_dir.join("libbean_os.a");
@@ -86,6 +137,8 @@ fn main() {
     cmd.arg("crs");
     cmd.arg(&kernel_archive);
     cmd.arg(&kernel_bin);
+    cmd.arg(&initrd_bin);
+    cmd.arg(&boot_config_obj);
     let cmd_status = cmd
         .status()
         .expect("Failed to run llvm-ar to create archive");
@@ -94,7 +147,172 @@ fn main() {
         process::exit(1);
     }
 
-    // link kernel blob with bootloader
+    // link kernel blob, initrd and boot configuration with bootloader
     println!("cargo:rustc-link-search=native={}", out_dir.display());
     println!("cargo:rustc-link-lib=static=bean_os");
 }
+
+/// A boot menu entry.
+struct BootEntry {
+    name: String,
+    kernel: String,
+    cmdline: String,
+}
+
+/// Contents of `boot.cfg`.
+struct BootConfig {
+    timeout: u16,
+    default: usize,
+    entries: Vec<BootEntry>,
+}
+
+/// Parses the boot configuration (see `boot.cfg` for the format).
+fn parse_boot_config(text: &str) -> BootConfig {
+    let mut config = BootConfig { timeout: 0, default: 0, entries: Vec::new() };
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            assert!(name.len() <= MAX_NAME_LEN && name.is_ascii(), "boot.cfg:{}: invalid entry name", i + 1);
+            config.entries.push(BootEntry { name: name.into(), kernel: "default".into(), cmdline: String::new() });
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .unwrap_or_else(|| panic!("boot.cfg:{}: expected 'key = value'", i + 1));
+
+        match (config.entries.last_mut(), key) {
+            (None, "timeout") => {
+                config.timeout = value.parse().unwrap_or_else(|_| panic!("boot.cfg:{}: invalid timeout", i + 1));
+                // the boot menu counts the timeout in BIOS timer ticks (16-bit)
+                assert!(config.timeout <= 3600, "boot.cfg:{}: timeout must not exceed one hour", i + 1);
+            }
+            (None, "default") => {
+                config.default = value.parse().unwrap_or_else(|_| panic!("boot.cfg:{}: invalid default entry", i + 1));
+            }
+            (Some(entry), "kernel") => entry.kernel = value.into(),
+            (Some(entry), "cmdline") => {
+                assert!(value.len() <= MAX_CMDLINE_LEN && value.is_ascii(), "boot.cfg:{}: invalid command line", i + 1);
+                entry.cmdline = value.into();
+            }
+            _ => panic!("boot.cfg:{}: unexpected key '{}'", i + 1, key),
+        }
+    }
+
+    assert!(!config.entries.is_empty(), "boot.cfg: no boot entries");
+    assert!(config.entries.len() <= MAX_BOOT_ENTRIES, "boot.cfg: too many boot entries");
+    assert!(config.default < config.entries.len(), "boot.cfg: default entry does not exist");
+
+    // every kernel besides the default and fallback one needs its own slot in the kernel image
+    let extra_kernels = config
+        .entries
+        .iter()
+        .filter(|entry| !matches!(entry.kernel.as_str(), "default" | "fallback"))
+        .count();
+    assert!(
+        FALLBACK_SLOT + 1 + extra_kernels <= MAX_SLOTS,
+        "boot.cfg: too many kernels, at most {} besides the default and fallback kernel",
+        MAX_SLOTS - FALLBACK_SLOT - 1
+    );
+
+    config
+}
+
+/// Serializes the boot configuration into the layout expected by the boot menu in `stage2.s`.
+fn write_boot_config(config: &BootConfig, entry_slots: &[usize], path: &Path) {
+    let mut bytes = vec![config.entries.len() as u8, config.default as u8];
+    bytes.extend_from_slice(&config.timeout.to_le_bytes());
+
+    for (entry, &slot) in config.entries.iter().zip(entry_slots) {
+        let mut entry_bytes = [0_u8; BOOT_ENTRY_SIZE];
+        entry_bytes[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+        entry_bytes[0x40] = slot as u8;
+        entry_bytes[0x80..0x80 + entry.cmdline.len()].copy_from_slice(entry.cmdline.as_bytes());
+        bytes.extend_from_slice(&entry_bytes);
+    }
+
+    fs::write(path, bytes).expect("Failed to write boot configuration");
+}
+
+/// Removes the debug information from a kernel ELF file.
+///
+/// The symbol table is kept, the bootloader hands it to the kernel to symbolize backtraces.
+fn strip_kernel(objcopy: &Path, kernel: &Path, kernel_stripped: &Path) {
+    let mut cmd = Command::new(objcopy);
+    cmd.arg("--strip-debug");
+    cmd.arg("--keep-section=.symtab");
+    cmd.arg("--keep-section=.strtab");
+    cmd.arg(kernel);
+    cmd.arg(kernel_stripped);
+    let cmd_status = cmd
+        .status()
+        .expect("Failed to run llvm-objcopy to strip debug symbols");
+    if !cmd_status.success() {
+        eprintln!("Failed to strip debug symbols");
+        process::exit(1);
+    }
+}
+
+/// Writes the kernel image header followed by one LZ4 compressed slot per kernel file.
+fn create_kernel_image(kernels: &[PathBuf], kernel_image: &Path) {
+    assert!(
+        kernels.len() > FALLBACK_SLOT && kernels.len() <= MAX_SLOTS,
+        "The kernel image needs between {} and {} kernels",
+        FALLBACK_SLOT + 1,
+        MAX_SLOTS
+    );
+
+    let mut header = ImageHeader { slot_count: kernels.len(), slots: [SlotEntry::default(); MAX_SLOTS] };
+    let mut slot_data = Vec::new();
+
+    for (slot, kernel) in header.slots.iter_mut().zip(kernels) {
+        let kernel_bytes = fs::read(kernel).expect("Failed to read stripped kernel");
+        let compressed_bytes = lz4_flex::block::compress(&kernel_bytes);
+
+        slot.offset = (HEADER_SIZE + slot_data.len()) as u64;
+        slot.size = compressed_bytes.len() as u64;
+        slot.uncompressed_size = kernel_bytes.len() as u64;
+        slot.checksum = kernel_blob::crc32(&compressed_bytes);
+        slot.compression = Compression::Lz4;
+
+        slot_data.extend_from_slice(&compressed_bytes);
+        slot_data.resize(slot_data.len().next_multiple_of(SLOT_ALIGN), 0);
+    }
+
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(&slot_data);
+    fs::write(kernel_image, image).expect("Failed to write kernel image");
+}
+
+/// Wraps a file as a binary blob in the section `.<name>` of a new ELF file.
+///
+/// The blob can be accessed through the symbols `_<name>_start_addr`, `_<name>_end_addr` and `_<name>_size`.
+fn wrap_blob(objcopy: &Path, out_dir: &Path, blob: &Path, blob_bin: &Path, name: &str) {
+    let blob_only_underscores = blob.to_str().expect("Invalid path")
+        .replace(['.', '/', '-'], "_");
+
+    let mut cmd = Command::new(objcopy);
+    cmd.current_dir(out_dir);
+    cmd.arg("-I").arg("binary");
+    cmd.arg("-O").arg("elf64-x86-64");
+    cmd.arg("--binary-architecture=i386:x86-64");
+    cmd.arg("--rename-section").arg(format!(".data=.{}", name));
+    cmd.arg("--redefine-sym").arg(format!("_binary_{}_start=_{}_start_addr", blob_only_underscores, name));
+    cmd.arg("--redefine-sym").arg(format!("_binary_{}_end=_{}_end_addr", blob_only_underscores, name));
+    cmd.arg("--redefine-sym").arg(format!("_binary_{}_size=_{}_size", blob_only_underscores, name));
+    cmd.arg(blob);
+    cmd.arg(blob_bin);
+    let cmd_status = cmd
+        .status()
+        .expect("Failed to run llvm-objcopy to wrap blob");
+    if !cmd_status.success() {
+        eprintln!("Failed to wrap {} blob", name);
+        process::exit(1);
+    }
+}
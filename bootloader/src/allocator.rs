@@ -1,8 +1,7 @@
-use core::slice;
-
-use x86_64::asm_wrappers::get_pml4_base_addr;
-use x86_64::frame::Frame;
-use x86_64::page_table::PageDir;
+use x86_64::addr::{PAddr, VAddr};
+use x86_64::frame::{Frame, FrameAllocator};
+use x86_64::page_table::{MappedPageTable, Mapper, PageDir, PageTableFrameMapping};
+use x86_64::paging::{Page, PageSize, Page1GiB, Page2MiB};
 
 use crate::println;
 use crate::{MemRegion, MemoryMap};
@@ -10,23 +9,23 @@ use crate::{MemRegion, MemoryMap};
 /// A rudimentary page frame allocator.
 /// 
 /// Implemented as a simple bump allocator. Panics if no usable memory is left.
-pub struct FrameAllocator {
+pub struct BumpFrameAllocator {
     memory_map: MemoryMap,
     current_region: &'static MemRegion,
     next_frame: Frame,
 }
 
-impl FrameAllocator {
+impl BumpFrameAllocator {
     /// Creates a new allocator that starts at the specified frame.
-    pub fn starting_at(start_frame: Frame, memory_map: MemoryMap) -> FrameAllocator {
-        let addr = start_frame.start_addr;
+    pub fn starting_at(start_frame: Frame, memory_map: MemoryMap) -> BumpFrameAllocator {
+        let addr = start_frame.start_address().as_u64();
         let current_region = memory_map
             .data
             .iter()
             .filter(|&region| region.usable())
             .find(|&region| addr >= region.address && addr + 4096 <= region.address + region.length)
             .expect("Tried to init allocator in invalid memory region");
-        FrameAllocator {
+        BumpFrameAllocator {
             memory_map,
             current_region,
             next_frame: start_frame,
@@ -36,59 +35,110 @@ impl FrameAllocator {
     /// Identity maps the remaining physical address space.
     ///
     /// This assumes that the first gigabyte was already identity mapped.
-    /// Uses 2MiB hugepages for the mapping.
-    pub fn identity_map_all(&mut self) {
+    /// The end of the address space is rounded up to the next gigabyte.
+    /// Uses 1GiB hugepages if the CPU supports them and falls back to 2MiB hugepages otherwise.
+    pub fn identity_map_all<P: PageTableFrameMapping>(&mut self, page_table: &mut MappedPageTable<P>, use_1gib_pages: bool) {
         // find out how much physical memory is left
         // first GB already identity mapped in stage3.s
         let phy_start_addr = 1_u64 << 30;
         let phy_end_addr = self.memory_map.max_addr;
+        if phy_end_addr < phy_start_addr {
+            println!("Physical address space fits into the first GiB, nothing left to identity map");
+            return;
+        }
         let remaining_size = phy_end_addr - phy_start_addr + 1;
 
-        let page_dir_ptr_table = {
-            let ptr = unsafe { *(get_pml4_base_addr() as *const u64) } & !0xFFF_u64;
-            unsafe { slice::from_raw_parts_mut(ptr as *mut u64, 512) }
-        };
-
-        let needed_pdpes = (remaining_size / 4096 / 512 / 512) as usize;
+        let needed_pdpes = remaining_size / Page1GiB::SIZE;
 
         println!(
-            "Identity mapping remaing physical address space:\n\tStart: 0x{:016X}, End: 0x{:016X}\n\tSize:  0x{:016X}, Required PDPEs: {}", 
-            phy_start_addr, phy_end_addr, remaining_size, needed_pdpes
+            "Identity mapping remaing physical address space:\n\tStart: 0x{:016X}, End: 0x{:016X}\n\tSize:  0x{:016X}, Required PDPEs: {}, 1GiB pages: {}", 
+            phy_start_addr, phy_end_addr, remaining_size, needed_pdpes, use_1gib_pages
         );
 
-        // TODO: support address spaces that are not a multiple of 1GiB
-        assert!((remaining_size / 4096 / 512) % 512 == 0);
+        let (start, end) = (PAddr::new(phy_start_addr), PAddr::new(phy_end_addr));
+        self.map_physical_range(page_table, start, end, VAddr::zero(), PageDir::Write, use_1gib_pages);
+    }
 
-        let mut table_entry =
-            phy_start_addr | (PageDir::Present | PageDir::Write | PageDir::HugePage).bits();
+    /// Maps all physical memory at `offset`.
+    pub fn map_physical_memory<P: PageTableFrameMapping>(
+        &mut self,
+        page_table: &mut MappedPageTable<P>,
+        offset: VAddr,
+        flags: PageDir,
+        use_1gib_pages: bool,
+    ) {
+        let phy_end_addr = self.memory_map.max_addr;
+        println!("Mapping physical memory [0x0, 0x{:X}] at offset 0x{:016X}", phy_end_addr, offset);
 
-        for i in 0..needed_pdpes {
-            self.next_frame.clear();
+        self.map_physical_range(page_table, PAddr::zero(), PAddr::new(phy_end_addr), offset, flags, use_1gib_pages);
+    }
 
-            let page_dir_table = {
-                let page_dir_table_ptr = self.next_frame.start_addr as *mut u64;
-                unsafe { slice::from_raw_parts_mut(page_dir_table_ptr, 512) }
-            };
+    /// Maps the physical address range `[start, end]` (rounded to 1GiB) at `offset` using hugepages.
+    ///
+    /// The pages were not mapped before, so there are no stale TLB entries to flush.
+    fn map_physical_range<P: PageTableFrameMapping>(
+        &mut self,
+        page_table: &mut MappedPageTable<P>,
+        start: PAddr,
+        end: PAddr,
+        offset: VAddr,
+        flags: PageDir,
+        use_1gib_pages: bool,
+    ) {
+        let start_frame = Frame::<Page1GiB>::containing_address(start);
+        let end_frame = Frame::<Page1GiB>::containing_address(end);
+
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            if use_1gib_pages {
+                let page = Page::<Page1GiB>::containing_address(offset + frame.start_address().as_u64());
+                page_table.map_to(page, frame, flags, self).expect("Failed to map 1GiB page").ignore();
+                continue;
+            }
 
-            for entry in page_dir_table.iter_mut() {
-                *entry = table_entry;
-                table_entry += 1 << 21;
+            for frame in frame.sub_frames::<Page2MiB>() {
+                let page = Page::<Page2MiB>::containing_address(offset + frame.start_address().as_u64());
+                page_table.map_to(page, frame, flags, self).expect("Failed to map 2MiB page").ignore();
             }
+        }
+    }
 
-            page_dir_ptr_table[i + 1] =
-                self.next_frame.start_addr | (PageDir::Present | PageDir::Write).bits();
+    /// Physical address of the first frame that was not handed out yet.
+    pub fn next_free_addr(&self) -> u64 {
+        self.next_frame.start_address().as_u64()
+    }
+
+    /// Returns the next free frame.
+    pub fn allocate_frame(&mut self) -> Frame {
+        self.increment()
+    }
 
+    /// Returns the first of `count` physically contiguous free frames.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Frame {
+        // the bump allocator never leaves its memory region, so consecutive frames are always contiguous
+        let frame = self.next_frame;
+        for _ in 0..count {
             self.increment();
         }
+        frame
     }
 
-    fn increment(&mut self) {
-        self.next_frame += 1;
+    /// Hands out the next frame of the current region.
+    fn increment(&mut self) -> Frame {
+        let frame = self.next_frame;
 
         // TODO: move to next usable memory region if current one is full
-
         assert!(
-            self.next_frame.start_addr + 4095 <= self.current_region.address + self.current_region.length - 1
+            frame.start_address().as_u64() + 4096 <= self.current_region.address + self.current_region.length,
+            "Out of memory in the current region"
         );
+
+        self.next_frame += 1;
+        frame
+    }
+}
+
+unsafe impl FrameAllocator for BumpFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        Some(BumpFrameAllocator::allocate_frame(self))
     }
 }
@@ -0,0 +1,174 @@
+/*!
+Layout of the kernel image that is embedded into the bootloader.
+
+The image starts with a single sector header that describes up to [`MAX_SLOTS`] kernel slots,
+each one holding a (usually LZ4 compressed) kernel ELF file together with its CRC32 checksum.
+Slot [`PRIMARY_SLOT`] is booted by default, slot [`FALLBACK_SLOT`] is used if the
+primary kernel fails verification or loading.
+
+This file is shared with `build.rs` (which creates the image), so it must only depend on `core`.
+*/
+
+/// Magic number at the start of the image header.
+pub const IMAGE_MAGIC: [u8; 8] = *b"BEANIMG\0";
+
+/// Size of the image header. The first slot starts right after it.
+pub const HEADER_SIZE: usize = 512;
+
+/// Every slot starts on a sector boundary.
+pub const SLOT_ALIGN: usize = 512;
+
+/// Maximum number of slots that fit into the image header.
+pub const MAX_SLOTS: usize = 8;
+
+/// Slot containing the default kernel.
+pub const PRIMARY_SLOT: usize = 0;
+
+/// Slot that is booted if the primary kernel is corrupt.
+pub const FALLBACK_SLOT: usize = 1;
+
+const SLOT_ENTRY_SIZE: usize = 32;
+const SLOT_TABLE_OFFSET: usize = 16;
+
+/// Compression algorithm used for the kernel stored in a slot.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    /// LZ4 block format (without frame header).
+    Lz4 = 1,
+}
+
+/// Location and checksum of a single kernel inside the image.
+#[derive(Clone, Copy, Default)]
+pub struct SlotEntry {
+    /// Offset of the kernel from the start of the image.
+    pub offset: u64,
+    /// Size of the (compressed) kernel in bytes.
+    pub size: u64,
+    /// Size of the kernel ELF file after decompression.
+    pub uncompressed_size: u64,
+    /// CRC32 of the (compressed) kernel bytes.
+    pub checksum: u32,
+    pub compression: Compression,
+}
+
+/// Header placed in front of the kernel slots.
+pub struct ImageHeader {
+    pub slot_count: usize,
+    pub slots: [SlotEntry; MAX_SLOTS],
+}
+
+impl ImageHeader {
+    /// Parses the header at the start of `image`.
+    pub fn parse(image: &[u8]) -> Result<ImageHeader, &'static str> {
+        if image.len() < HEADER_SIZE {
+            return Err("Kernel image is smaller than its header");
+        }
+        if image[..8] != IMAGE_MAGIC {
+            return Err("Wrong magic number in kernel image header");
+        }
+
+        let slot_count = read_u32(image, 8) as usize;
+        if slot_count > MAX_SLOTS {
+            return Err("Too many slots in kernel image header");
+        }
+
+        let table_end = SLOT_TABLE_OFFSET + slot_count * SLOT_ENTRY_SIZE;
+        if read_u32(image, 12) != crc32(&image[SLOT_TABLE_OFFSET..table_end]) {
+            return Err("Kernel image header is corrupt");
+        }
+
+        let mut slots = [SlotEntry::default(); MAX_SLOTS];
+        for (i, slot) in slots.iter_mut().take(slot_count).enumerate() {
+            let entry = SLOT_TABLE_OFFSET + i * SLOT_ENTRY_SIZE;
+            slot.offset = read_u64(image, entry);
+            slot.size = read_u64(image, entry + 8);
+            slot.uncompressed_size = read_u64(image, entry + 16);
+            slot.checksum = read_u32(image, entry + 24);
+            slot.compression = match read_u32(image, entry + 28) {
+                0 => Compression::None,
+                1 => Compression::Lz4,
+                _ => return Err("Unknown kernel compression in image header"),
+            };
+        }
+
+        Ok(ImageHeader { slot_count, slots })
+    }
+
+    /// Serializes the header into its on-disk representation.
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0_u8; HEADER_SIZE];
+        bytes[..8].copy_from_slice(&IMAGE_MAGIC);
+        bytes[8..12].copy_from_slice(&(self.slot_count as u32).to_le_bytes());
+
+        for (i, slot) in self.slots.iter().take(self.slot_count).enumerate() {
+            let entry = SLOT_TABLE_OFFSET + i * SLOT_ENTRY_SIZE;
+            bytes[entry..entry + 8].copy_from_slice(&slot.offset.to_le_bytes());
+            bytes[entry + 8..entry + 16].copy_from_slice(&slot.size.to_le_bytes());
+            bytes[entry + 16..entry + 24].copy_from_slice(&slot.uncompressed_size.to_le_bytes());
+            bytes[entry + 24..entry + 28].copy_from_slice(&slot.checksum.to_le_bytes());
+            bytes[entry + 28..entry + 32].copy_from_slice(&(slot.compression as u32).to_le_bytes());
+        }
+
+        let table_end = SLOT_TABLE_OFFSET + self.slot_count * SLOT_ENTRY_SIZE;
+        let checksum = crc32(&bytes[SLOT_TABLE_OFFSET..table_end]);
+        bytes[12..16].copy_from_slice(&checksum.to_le_bytes());
+
+        bytes
+    }
+
+    /// Returns the (compressed) kernel stored in the given slot after verifying its checksum.
+    pub fn verified_slot<'a>(&self, image: &'a [u8], index: usize) -> Result<&'a [u8], &'static str> {
+        if index >= self.slot_count {
+            return Err("Kernel slot does not exist");
+        }
+
+        let slot = &self.slots[index];
+        let start = slot.offset as usize;
+        let end = start.checked_add(slot.size as usize).ok_or("Kernel slot size overflows")?;
+        if start < HEADER_SIZE || end > image.len() {
+            return Err("Kernel slot lies outside of the kernel image");
+        }
+
+        let kernel = &image[start..end];
+        if crc32(kernel) != slot.checksum {
+            return Err("Kernel checksum mismatch");
+        }
+
+        Ok(kernel)
+    }
+}
+
+/// Computes the CRC32 (IEEE 802.3) checksum of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in bytes {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -1,12 +1,16 @@
 /*!
 Print messages through the serial port (COM1) or the VGA buffer.
 
-Uses a fixed-size buffer for formatting.
+Uses a fixed-size buffer for formatting. Also acts as the sink for `log` records of the `x86_64` crate.
 */
 
-use core::fmt;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use x86_64::asm_wrappers::{read_io, write_io};
+use x86_64::logger::LogSink;
+use x86_64::port::{Port, PortReadOnly, PortWriteOnly};
+use x86_64::sync::Mutex;
+use x86_64::time;
 
 #[allow(unused)]
 #[derive(PartialEq, Clone, Copy)]
@@ -17,11 +21,13 @@ pub enum LogMode {
     Both,
 }
 
-pub static mut LOG_MODE: LogMode = LogMode::None;
+static LOG_MODE: Mutex<LogMode> = Mutex::new(LogMode::None);
 
 const COM1: u16 = 0x3F8;
 
-static mut VGA_BUFFER_OFFSET: u32 = 0;
+static VGA_BUFFER_OFFSET: Mutex<u32> = Mutex::new(0);
+
+static AT_LINE_START: AtomicBool = AtomicBool::new(true);
 
 #[macro_export]
 macro_rules! print {
@@ -34,6 +40,22 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Forwards `log` records to the selected outputs. The records already carry a timestamp.
+pub struct Sink;
+
+pub static SINK: Sink = Sink;
+
+impl LogSink for Sink {
+    fn write_line(&self, args: fmt::Arguments) {
+        let mut buffer = [0u8; 256];
+
+        let mut writer = FmtBuffer::new(&mut buffer);
+        fmt::write(&mut writer, args).expect("Format buffer is too small");
+
+        print_str(writer.as_str().unwrap());
+    }
+}
+
 pub fn _print(args: fmt::Arguments) {
     let mut buffer = [0u8; 256];
 
@@ -41,6 +63,27 @@ pub fn _print(args: fmt::Arguments) {
     fmt::write(&mut writer, args).expect("Format buffer is too small");
 
     let string = writer.as_str().unwrap();
+    for line in string.split_inclusive('\n') {
+        if AT_LINE_START.load(Ordering::Relaxed) {
+            print_timestamp();
+        }
+        print_str(line);
+        AT_LINE_START.store(line.ends_with('\n'), Ordering::Relaxed);
+    }
+}
+
+/// Prints the time since power on as `[seconds.microseconds]`, once the TSC is calibrated.
+fn print_timestamp() {
+    let Some(nanoseconds) = time::nanoseconds() else { return };
+
+    let mut buffer = [0u8; 32];
+    let mut writer = FmtBuffer::new(&mut buffer);
+    write!(writer, "[{:5}.{:06}] ", nanoseconds / 1_000_000_000, nanoseconds / 1000 % 1_000_000)
+        .expect("Format buffer is too small");
+    print_str(writer.as_str().unwrap());
+}
+
+fn print_str(string: &str) {
     match get_log_mode() {
         LogMode::None => (),
         LogMode::VGA => vga_print(string),
@@ -56,13 +99,20 @@ pub fn init(log_mode: LogMode) {
     set_log_mode(log_mode);
 
     // init serial port
-    write_io(COM1 + 1, 0x00);   // disable all interrupts
-    write_io(COM1 + 3, 0x80);   // enable DLAB
-    write_io(COM1 + 0, 0x03);   // set divisor to 3 (lo byte) (38400 baud)
-    write_io(COM1 + 1, 0x00);   //                  (hi byte)
-    write_io(COM1 + 3, 0x03);   // 8 bits, no parity, one stop bit
-    write_io(COM1 + 2, 0xC7);   // enable and clear FIFOs, 14 bytes
-    write_io(COM1 + 4, 0x0B);   // set OUT2/RTS/DSR
+    let mut data = Port::<u8>::new(COM1);
+    let mut interrupt_enable = Port::<u8>::new(COM1 + 1);
+    let mut fifo_control = PortWriteOnly::<u8>::new(COM1 + 2);
+    let mut line_control = Port::<u8>::new(COM1 + 3);
+    let mut modem_control = Port::<u8>::new(COM1 + 4);
+    unsafe {
+        interrupt_enable.write(0x00);   // disable all interrupts
+        line_control.write(0x80);       // enable DLAB
+        data.write(0x03);               // set divisor to 3 (lo byte) (38400 baud)
+        interrupt_enable.write(0x00);   //                  (hi byte)
+        line_control.write(0x03);       // 8 bits, no parity, one stop bit
+        fifo_control.write(0xC7);       // enable and clear FIFOs, 14 bytes
+        modem_control.write(0x0B);      // set OUT2/RTS/DSR
+    }
 
     // 'init' vga screen
     vga_clear_screen();
@@ -71,25 +121,26 @@ pub fn init(log_mode: LogMode) {
 }
 
 pub fn set_log_mode(log_mode: LogMode) {
-    unsafe { LOG_MODE = log_mode; };
+    *LOG_MODE.lock() = log_mode;
 }
 
 pub fn get_log_mode() -> LogMode {
-    unsafe { LOG_MODE }
+    *LOG_MODE.lock()
 }
 
 fn vga_print(string: &str) {
-    let mut address = unsafe { (0xB8000 + VGA_BUFFER_OFFSET) as *mut u16 };
+    let mut offset = VGA_BUFFER_OFFSET.lock();
+    let mut address = (0xB8000 + *offset) as *mut u16;
     for c in string.chars() {
         if c == '\n' {
-            unsafe { VGA_BUFFER_OFFSET += 160 - VGA_BUFFER_OFFSET % 160; };
+            *offset += 160 - *offset % 160;
         } else {
             let vga_char = (0x0F00 as u16) | (c as u16);
             unsafe { 
                 *address = vga_char;
                 address = address.add(1);
-                VGA_BUFFER_OFFSET += 2;
             }
+            *offset += 2;
         }
     }
 }
@@ -106,14 +157,14 @@ fn vga_clear_screen() {
 }
 
 fn serial_print(string: &str) {
-    fn is_transmit_empty() -> bool {
-        read_io(COM1 + 5) & 0x20 != 0
-    }
+    let mut data = PortWriteOnly::<u8>::new(COM1);
+    let mut line_status = PortReadOnly::<u8>::new(COM1 + 5);
 
     for c in string.chars() {
-        while !is_transmit_empty() {}
+        // wait until the transmit holding register is empty
+        while unsafe { line_status.read() } & 0x20 == 0 {}
 
-        write_io(COM1, c as u8);
+        unsafe { data.write(c as u8); }
     }
 }
 
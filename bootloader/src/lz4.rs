@@ -0,0 +1,72 @@
+/*!
+Decompression of raw LZ4 blocks.
+
+Only the block format is supported (no frame header, no checksums).
+https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md
+*/
+
+/// Decompresses the LZ4 block `input` into `output`.
+///
+/// Returns the number of bytes written to `output`.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, &'static str> {
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+
+    loop {
+        let token = *input.get(in_pos).ok_or("LZ4 block ended unexpectedly")?;
+        in_pos += 1;
+
+        // copy literals
+        let literal_len = read_length(input, &mut in_pos, (token >> 4) as usize)?;
+        let literals = input
+            .get(in_pos..in_pos + literal_len)
+            .ok_or("LZ4 literals exceed input size")?;
+        output
+            .get_mut(out_pos..out_pos + literal_len)
+            .ok_or("LZ4 literals exceed output size")?
+            .copy_from_slice(literals);
+        in_pos += literal_len;
+        out_pos += literal_len;
+
+        // the last sequence only contains literals
+        if in_pos == input.len() {
+            return Ok(out_pos);
+        }
+
+        let offset_bytes = input.get(in_pos..in_pos + 2).ok_or("LZ4 match offset missing")?;
+        let offset = u16::from_le_bytes([offset_bytes[0], offset_bytes[1]]) as usize;
+        in_pos += 2;
+
+        if offset == 0 || offset > out_pos {
+            return Err("Invalid LZ4 match offset");
+        }
+
+        let match_len = read_length(input, &mut in_pos, (token & 0xF) as usize)? + 4;
+        if out_pos + match_len > output.len() {
+            return Err("LZ4 match exceeds output size");
+        }
+
+        // matches may overlap with the bytes they produce, so copy byte by byte
+        let match_start = out_pos - offset;
+        for i in 0..match_len {
+            output[out_pos + i] = output[match_start + i];
+        }
+        out_pos += match_len;
+    }
+}
+
+/// Reads the optional extra length bytes that follow a 4-bit length of 15.
+fn read_length(input: &[u8], in_pos: &mut usize, initial: usize) -> Result<usize, &'static str> {
+    let mut length = initial;
+    if initial == 15 {
+        loop {
+            let byte = *input.get(*in_pos).ok_or("LZ4 length ended unexpectedly")?;
+            *in_pos += 1;
+            length += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
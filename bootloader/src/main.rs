@@ -8,11 +8,21 @@ compile_error!("Wrong target selected for bootloader. Must be 'x86_64-bean_os_bo
 
 use core::panic::PanicInfo;
 use core::arch::{asm, global_asm};
-use core::slice;
+use core::{mem, ptr, slice};
 
-use x86_64::elf::ElfFile;
+use ::log::LevelFilter;
+
+use x86_64::addr::{align_up, PAddr, VAddr};
+use x86_64::cpuid::{CpuFeatures, CpuInfo};
+use x86_64::boot_info::{BootInfo, MemoryRegion, MemoryRegionKind};
+use x86_64::backtrace::{Backtrace, StackBounds};
+use x86_64::elf::{ElfFile, ProgramHeader, SymbolTable};
 use x86_64::frame::Frame;
 use x86_64::asm_wrappers;
+use x86_64::page_table::{IdentityMappedPageTable, IdentityMapping, Mapper, PageDir, PageTable};
+use x86_64::paging::{Page, PageRange};
+use x86_64::registers::{Cr3, Efer, EferFlags};
+use x86_64::time;
 
 mod log;
 use log::LogMode;
@@ -21,23 +31,43 @@ mod memory;
 use memory::{MemRegion, MemoryMap};
 
 mod allocator;
-use allocator::FrameAllocator;
+use allocator::BumpFrameAllocator;
+
+#[allow(dead_code)]
+mod kernel_blob;
+use kernel_blob::{Compression, ImageHeader, SlotEntry, FALLBACK_SLOT};
+
+mod lz4;
 
 // load assembly files
 global_asm!(include_str!("stage1.s"));
 global_asm!(include_str!("stage2.s"));
 global_asm!(include_str!("stage3.s"));
 
+/// Virtual address at which the bootloader maps all physical memory for the kernel.
+const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_C000_0000_0000;
+
+/// Size of the kernel stack in 4KiB frames.
+const KERNEL_STACK_FRAMES: usize = 32;
+
 // linker-supplied symbols
 extern "C" {
     // defined in stage2.s
     static _memory_map_entries: u16;
+    static _boot_entry_slot: u8;
+    static _boot_cmdline: [u8; 128];
 
     // defined in linker script
     static _memory_map: usize;
+    static __bootloader_end: usize;
+    static _stack_start: usize;
+    static _stack_end: usize;
 
-    // defined in kernel binary
+    // defined in kernel image
     static _kernel_size: usize;
+
+    // defined in initrd
+    static _initrd_size: usize;
 }
 
 /// Entry point for the Rust part of the bootloader.
@@ -56,100 +86,375 @@ unsafe extern "C" fn stage_4() -> ! {
     let memory_map_addr = core::ptr::addr_of!(_memory_map) as usize;
     let memory_map_entries = _memory_map_entries as usize;
     let kernel_size = core::ptr::addr_of!(_kernel_size) as usize;
+    let initrd_size = core::ptr::addr_of!(_initrd_size) as usize;
+    let bootloader_end = core::ptr::addr_of!(__bootloader_end) as usize;
+
+    // kernel slot and command line selected in the boot menu
+    let boot_slot = _boot_entry_slot as usize;
+    let cmdline = {
+        let cmdline = &*core::ptr::addr_of!(_boot_cmdline);
+        let len = cmdline.iter().position(|&c| c == 0).unwrap_or(cmdline.len());
+        core::str::from_utf8(&cmdline[..len]).unwrap_or("")
+    };
 
     // sanity check to make sure the stack is aligned properly
     assert!(core::ptr::addr_of!(memory_map_addr).is_aligned_to(8));
 
     // move out of unsafe scope
-    bootloader_start(kernel_size, memory_map_addr, memory_map_entries);
+    bootloader_start(kernel_size, initrd_size, bootloader_end, memory_map_addr, memory_map_entries, boot_slot, cmdline);
 }
 
 /// Main bootloader function.
 /// 
-/// Identity maps the remaining physical address space and loads the kernel ELF executable.
-fn bootloader_start(kernel_size: usize, memory_map_addr: usize, memory_map_entries: usize) -> ! {
-    // give the x86_64 static library a pointer to the print function
-    unsafe { x86_64::PRINT = Some(log::_print); }
-    
+/// Identity maps the remaining physical address space, loads the kernel ELF executable and jumps to it.
+/// Boots the kernel slot selected in the boot menu and falls back to the fallback slot
+/// if that kernel is corrupt or fails to load.
+fn bootloader_start(
+    kernel_size: usize,
+    initrd_size: usize,
+    bootloader_end: usize,
+    memory_map_addr: usize,
+    memory_map_entries: usize,
+    boot_slot: usize,
+    cmdline: &'static str,
+) -> ! {
     // initialize the logger
     log::init(LogMode::Serial);
 
-    // bootloader loads the kernel at the 4MiB mark
+    // the x86_64 library logs through the `log` crate
+    x86_64::logger::init(LevelFilter::Info).expect("Failed to install the logger");
+    x86_64::logger::add_sink(&log::SINK).expect("Failed to register the log sink");
+
+    let cpu_info = CpuInfo::read();
+    println!("{}", cpu_info);
+
+    // from here on every log line is prefixed with a timestamp
+    if cpu_info.features.contains(CpuFeatures::TSC) {
+        let tsc_frequency = time::calibrate_tsc(&cpu_info);
+        time::set_tsc_frequency(tsc_frequency);
+        println!("TSC frequency: {} MHz (invariant: {})", tsc_frequency / 1_000_000, cpu_info.features.contains(CpuFeatures::INVARIANT_TSC));
+    }
+
+    let nx_enabled = cpu_info.features.contains(CpuFeatures::NX);
+    if nx_enabled {
+        enable_no_execute();
+    }
+
+    // bootloader loads the kernel image at the 4MiB mark
     let kernel_start: usize = 0x400000;
     let kernel_end = kernel_start + kernel_size - 1;
-    println!("Kernel blob loaded at: [start=0x{:X}, end=0x{:X}, size={}]", kernel_start, kernel_end, kernel_size);
+    println!("Kernel image loaded at: [start=0x{:X}, end=0x{:X}, size={}]", kernel_start, kernel_end, kernel_size);
 
-    let memory_map = {
-        let start_addr = memory_map_addr as *const MemRegion;
-        MemoryMap::from(start_addr, memory_map_entries)
-    };
+    // followed by the initrd
+    let initrd_start = align_up((kernel_start + kernel_size) as u64, 4096) as usize;
+    println!("Initrd loaded at: [start=0x{:X}, size={}]", initrd_start, initrd_size);
+
+    let memory_map = MemoryMap::from(&IdentityMapping, PAddr::new(memory_map_addr as u64), memory_map_entries);
 
     println!("{}", memory_map);
 
-    let free_frames_start_addr = (kernel_start + kernel_size + 4095) & !4095;
+    let free_frames_start_addr = align_up((initrd_start + initrd_size) as u64, 4096) as usize;
     println!("Start of available frame range: 0x{:X}", free_frames_start_addr);
 
     let mut allocator = {
-        let starting_frame = Frame::containing_address(free_frames_start_addr as u64);
-        FrameAllocator::starting_at(starting_frame, memory_map)
+        let starting_frame = Frame::containing_address(PAddr::new(free_frames_start_addr as u64));
+        BumpFrameAllocator::starting_at(starting_frame, memory_map)
+    };
+
+    // all page tables are identity mapped, including the ones created by the mapper
+    let mut page_table = {
+        let (level_4_frame, _) = Cr3::read();
+        let level_4_table = unsafe { &mut *(level_4_frame.start_address().as_u64() as *mut PageTable) };
+        unsafe { IdentityMappedPageTable::new(level_4_table, IdentityMapping) }
     };
 
-    allocator.identity_map_all();
+    let use_1gib_pages = cpu_info.features.contains(CpuFeatures::PDPE1GB);
+    allocator.identity_map_all(&mut page_table, use_1gib_pages);
 
-    let kernel_blob = {
+    // the kernel accesses physical memory (and everything the boot info points to) through this mapping
+    let physical_memory_offset = VAddr::new(PHYSICAL_MEMORY_OFFSET);
+    let physical_memory_flags = if nx_enabled { PageDir::Write | PageDir::NoExecute } else { PageDir::Write };
+    allocator.map_physical_memory(&mut page_table, physical_memory_offset, physical_memory_flags, use_1gib_pages);
+    let phys_to_virt = |addr: u64| (physical_memory_offset + addr).as_u64();
+
+    let kernel_image = {
         let start_addr = kernel_start as *const u8;
         unsafe { slice::from_raw_parts(start_addr, kernel_size) }
     };
 
-    load_kernel(kernel_blob, &mut allocator).unwrap();
+    let image_header = ImageHeader::parse(kernel_image).expect("Failed to parse kernel image");
 
-    // spin forever
-    println!("HLT LOOP");
-    asm_wrappers::halt_loop();
+    println!("Boot menu selected kernel slot {} with command line \"{}\"", boot_slot, cmdline);
+
+    let fallback_slot = (boot_slot != FALLBACK_SLOT).then_some(FALLBACK_SLOT);
+
+    let (entry_point, kernel_symbols) = core::iter::once(boot_slot).chain(fallback_slot).find_map(|slot| {
+        let result = image_header
+            .verified_slot(kernel_image, slot)
+            .and_then(|slot_data| decompress_kernel(&image_header.slots[slot], slot_data, &mut allocator))
+            .and_then(|kernel_blob| load_kernel(kernel_blob, &mut page_table, &mut allocator, nx_enabled));
+
+        match result {
+            Ok(kernel) => {
+                println!("Loaded kernel from slot {}", slot);
+                Some(kernel)
+            }
+            Err(err) => {
+                println!("Failed to load kernel from slot {}: {}", slot, err);
+                None
+            }
+        }
+    }).expect("No bootable kernel found");
+
+    let boot_info_frame = allocator.allocate_frame();
+    let memory_regions_frame = allocator.allocate_frame();
+    let kernel_stack_start = allocator.allocate_contiguous(KERNEL_STACK_FRAMES);
+    let kernel_stack_top = phys_to_virt((kernel_stack_start + KERNEL_STACK_FRAMES as u64).start_address().as_u64());
+
+    // the symbol table stays where it is in the decompressed kernel, which the kernel must not overwrite anyway
+    let (kernel_symtab, kernel_strtab) = match kernel_symbols {
+        Some(symbols) => (symbols.symtab(), symbols.strtab()),
+        None => {
+            println!("Kernel has no symbol table, backtraces will not be symbolized");
+            (&[][..], &[][..])
+        }
+    };
+
+    // everything the kernel must not overwrite
+    let initrd_end = (initrd_start + initrd_size) as u64;
+    let in_use = [
+        // bootloader code, stack, the initial page tables and stage 4 at the 1MiB mark
+        MemoryRegion { start: 0, end: bootloader_end as u64, kind: MemoryRegionKind::Bootloader },
+        // kernel image
+        MemoryRegion { start: kernel_start as u64, end: initrd_start as u64, kind: MemoryRegionKind::Bootloader },
+        MemoryRegion { start: initrd_start as u64, end: initrd_end, kind: MemoryRegionKind::Initrd },
+        // frames allocated by the bootloader, including the boot info and the kernel stack
+        MemoryRegion {
+            start: free_frames_start_addr as u64,
+            end: allocator.next_free_addr(),
+            kind: MemoryRegionKind::Bootloader,
+        },
+    ];
+
+    let memory_regions = {
+        let ptr = memory_regions_frame.start_address().as_u64() as *mut MemoryRegion;
+        unsafe { slice::from_raw_parts_mut(ptr, 4096 / mem::size_of::<MemoryRegion>()) }
+    };
+    let memory_regions_len = memory_map.boot_memory_regions(&in_use, memory_regions);
+
+    let boot_info = {
+        let ptr = boot_info_frame.start_address().as_u64() as *mut BootInfo;
+        unsafe {
+            ptr::write(ptr, BootInfo {
+                physical_memory_offset: physical_memory_offset.as_u64(),
+                memory_regions_addr: phys_to_virt(memory_regions_frame.start_address().as_u64()),
+                memory_regions_len: memory_regions_len as u64,
+                initrd_addr: phys_to_virt(initrd_start as u64),
+                initrd_len: initrd_size as u64,
+                cmdline_addr: phys_to_virt(cmdline.as_ptr() as u64),
+                cmdline_len: cmdline.len() as u64,
+                cpu_features: cpu_info.features,
+                nx_enabled,
+                tsc_frequency: time::tsc_frequency().unwrap_or(0),
+                kernel_stack_addr: phys_to_virt(kernel_stack_start.start_address().as_u64()),
+                kernel_stack_len: KERNEL_STACK_FRAMES as u64 * 4096,
+                kernel_symtab_addr: phys_to_virt(kernel_symtab.as_ptr() as u64),
+                kernel_symtab_len: kernel_symtab.len() as u64,
+                kernel_strtab_addr: phys_to_virt(kernel_strtab.as_ptr() as u64),
+                kernel_strtab_len: kernel_strtab.len() as u64,
+            });
+        }
+        phys_to_virt(boot_info_frame.start_address().as_u64())
+    };
+
+    println!("Jumping to kernel entry point 0x{:016X} with stack at 0x{:016X}", entry_point, kernel_stack_top);
+    jump_to_kernel(entry_point, boot_info, kernel_stack_top);
+}
+
+/// Decompresses the kernel ELF file stored in a slot into freshly allocated frames.
+fn decompress_kernel(
+    slot: &SlotEntry,
+    slot_data: &'static [u8],
+    allocator: &mut BumpFrameAllocator,
+) -> Result<&'static [u8], &'static str> {
+    if slot.compression == Compression::None {
+        return Ok(slot_data);
+    }
+
+    let kernel_size = slot.uncompressed_size as usize;
+    let frame_count = kernel_size.div_ceil(4096);
+    let start_frame = allocator.allocate_contiguous(frame_count);
+
+    let kernel_blob = {
+        let start_addr = start_frame.start_address().as_u64() as *mut u8;
+        unsafe { slice::from_raw_parts_mut(start_addr, kernel_size) }
+    };
+
+    let decompressed_size = lz4::decompress(slot_data, kernel_blob)?;
+    if decompressed_size != kernel_size {
+        return Err("Decompressed kernel has the wrong size");
+    }
+
+    println!(
+        "Decompressed kernel into frames: [start=0x{:X}, compressed size={}, size={}]",
+        start_frame.start_address(), slot_data.len(), kernel_size
+    );
+
+    Ok(kernel_blob)
 }
 
-fn load_kernel(kernel_blob: &'static [u8], allocator: &mut FrameAllocator) -> Result<(), &'static str> {
-    let elf = ElfFile::from(kernel_blob);
+/// Copies the LOAD segments of the kernel into freshly allocated frames and maps them.
+///
+/// A kernel that fails to load leaves no mappings behind, so the next slot starts from a clean
+/// address space. Returns the entry point and the symbol table of the kernel.
+fn load_kernel(
+    kernel_blob: &'static [u8],
+    page_table: &mut IdentityMappedPageTable,
+    allocator: &mut BumpFrameAllocator,
+    nx_enabled: bool,
+) -> Result<(u64, Option<SymbolTable<'static>>), &'static str> {
+    let elf = ElfFile::from(kernel_blob)?;
     println!("Kernel entry point: 0x{:016X}", elf.entry_point);
     
     elf.print_prog_header();
 
-    for segment in elf.prog_headers {
-        if segment.prog_type != 1 { continue; }
+    let mut mapped_pages = 0;
+    if let Err(err) = map_segments(&elf, kernel_blob, page_table, allocator, nx_enabled, &mut mapped_pages) {
+        unmap_segments(&elf, page_table, mapped_pages);
+        return Err(err);
+    }
+
+    Ok((elf.entry_point, elf.symbol_table()))
+}
 
-        if segment.filesz == 0 && segment.memsz > 0 {
-            // TODO: load .bss segment
-            continue;
+/// Maps the LOAD segments in order and counts the mapped pages in `mapped_pages`.
+///
+/// Segments of the same kernel must not overlap.
+fn map_segments(
+    elf: &ElfFile,
+    kernel_blob: &'static [u8],
+    page_table: &mut IdentityMappedPageTable,
+    allocator: &mut BumpFrameAllocator,
+    nx_enabled: bool,
+    mapped_pages: &mut usize,
+) -> Result<(), &'static str> {
+    for segment in load_segments(elf) {
+        let (offset, filesz, memsz) = (segment.offset, segment.filesz, segment.memsz);
+
+        if filesz > memsz || offset.checked_add(filesz).is_none_or(|end| end > kernel_blob.len() as u64) {
+            return Err("LOAD segment lies outside of the kernel file");
         }
 
-        assert!(segment.filesz == segment.memsz);
+        let vaddr = VAddr::try_new(segment.vaddr)?;
+        let file_end = segment.vaddr.checked_add(filesz).map(VAddr::try_new).ok_or("LOAD segment is too large")??;
+        let pages = segment_pages(segment)?;
 
         debug_assert!(segment.align == 4096);
 
-        // start at offset.align_down
-        // go until (offset + filesz).align_up
         println!(
-            "LOAD segment: mapping file from 4MiB + 0x{:X} until 0x{:X}",
-            segment.offset & !4095,
-            (segment.offset + segment.filesz + 4095) & !4095
+            "LOAD segment: mapping file offset 0x{:X} (0x{:X} bytes) to 0x{:016X} (0x{:X} bytes)",
+            offset, filesz, vaddr, memsz
         );
 
-        let pml4_offset = (segment.vaddr >> 39) % 512;
-        let pdpt_offset = (segment.vaddr >> 30) % 512;
-        let pd_offset = (segment.vaddr >> 21) % 512;
-        let page_offset = (segment.vaddr >> 12) % 512;
         println!(
-            "PML4 Index: {}, PDPT Index: {}, PD Index: {}, Page Offset: {}",
-            pml4_offset, pdpt_offset, pd_offset, page_offset
+            "PML4 Index: {}, PDPT Index: {}, PD Index: {}, PT Index: {}",
+            vaddr.p4_index(), vaddr.p3_index(), vaddr.p2_index(), vaddr.p1_index()
         );
+
+        // PF_W
+        let mut flags = if segment.flags & 2 != 0 { PageDir::Write } else { PageDir::empty() };
+        // PF_X
+        if nx_enabled && segment.flags & 1 == 0 {
+            flags |= PageDir::NoExecute;
+        }
+
+        for page in pages {
+            let mut frame = allocator.allocate_frame();
+            frame.clear();
+
+            // copy the part of the file that overlaps with this page, the rest (.bss) stays zeroed
+            let copy_start = page.start_address().max(vaddr);
+            let copy_end = (page + 1).start_address().min(file_end);
+            if copy_start < copy_end {
+                let src = &kernel_blob[(offset + (copy_start - vaddr)) as usize..];
+                let dst = (frame.start_address() + (copy_start - page.start_address())).as_u64() as *mut u8;
+                unsafe { ptr::copy_nonoverlapping(src.as_ptr(), dst, (copy_end - copy_start) as usize); }
+            }
+
+            page_table.map_to(page, frame, flags, allocator)?.flush();
+            *mapped_pages += 1;
+        }
     }
 
     Ok(())
 }
 
+/// Unmaps the first `count` pages mapped by `map_segments`.
+fn unmap_segments(elf: &ElfFile, page_table: &mut IdentityMappedPageTable, mut count: usize) {
+    for segment in load_segments(elf) {
+        if count == 0 {
+            break;
+        }
+
+        // segments with mapped pages passed the checks in `map_segments`
+        let pages = segment_pages(segment).expect("Mapped LOAD segment became invalid");
+        for page in pages.take(count) {
+            let (_, flush) = page_table.unmap(page).expect("Failed to unmap a page of the broken kernel");
+            flush.flush();
+            count -= 1;
+        }
+    }
+}
+
+/// The program headers of the segments that are loaded into memory.
+fn load_segments(elf: &ElfFile) -> impl Iterator<Item = &'static ProgramHeader> {
+    elf.prog_headers.iter().filter(|segment| segment.prog_type == 1)
+}
+
+/// Pages covered by the memory image of a LOAD segment.
+fn segment_pages(segment: &ProgramHeader) -> Result<PageRange, &'static str> {
+    let vaddr = VAddr::try_new(segment.vaddr)?;
+    let segment_end = segment.vaddr.checked_add(segment.memsz).map(VAddr::try_new).ok_or("LOAD segment is too large")??;
+    Ok(Page::range(Page::containing_address(vaddr), Page::containing_address(segment_end.align_up(4096))))
+}
+
+/// Sets EFER.NXE so that pages can be marked as non-executable.
+fn enable_no_execute() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NoExecuteEnable)); }
+}
+
+/// Switches to the kernel stack and calls the kernel entry point with a pointer to the boot info
+/// as its first argument.
+///
+/// Both addresses lie in the physical memory mapping, so the kernel does not depend on the identity mapping.
+fn jump_to_kernel(entry_point: u64, boot_info: u64, stack_top: u64) -> ! {
+    unsafe {
+        asm!(
+            // the kernel expects a 16-byte aligned stack before the call
+            "mov rsp, {stack_top}",
+            "and rsp, -16",
+            // a null frame pointer ends backtraces at the kernel entry point
+            "xor ebp, ebp",
+            "call {entry_point}",
+            stack_top = in(reg) stack_top,
+            entry_point = in(reg) entry_point,
+            in("rdi") boot_info,
+            options(noreturn)
+        );
+    }
+}
+
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     println!("BOOTLOADER PANIC: {}", _info);
 
+    // stage 4 runs on the stack set up by stage 1, below the boot sector
+    let stack = StackBounds::new(core::ptr::addr_of!(_stack_start) as u64, core::ptr::addr_of!(_stack_end) as u64);
+    // stage 4 is a flat binary without symbols, 'image_builder --symbolize bootloader.sym' resolves the addresses
+    println!("Backtrace:");
+    for line in Backtrace::capture(stack).lines(None) {
+        println!("{}", line);
+    }
+
     asm_wrappers::halt_loop();
 }
@@ -1,9 +1,32 @@
-use core::slice;
-use core::fmt;
+use core::{fmt, mem, slice};
 
+use x86_64::addr::PAddr;
+use x86_64::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::page_table::IdentityMapping;
 use x86_64::read_from_packed;
 use crate::{print, println};
 
+/// Access to physical memory.
+///
+/// The bootloader reads and writes physical memory through the identity mapping, host tests
+/// back a synthetic physical address space with heap memory instead.
+///
+/// # Safety
+///
+/// The returned pointer must be valid for reads and writes of `len` bytes at `addr` for as long as
+/// the implementation lives.
+pub unsafe trait PhysicalMemory {
+    fn phys_to_ptr(&self, addr: PAddr, len: usize) -> *mut u8;
+}
+
+/// Stage 3 only identity maps the first GiB, `identity_map_all` has to map the rest before it is
+/// accessed.
+unsafe impl PhysicalMemory for IdentityMapping {
+    fn phys_to_ptr(&self, addr: PAddr, _len: usize) -> *mut u8 {
+        addr.as_u64() as *mut u8
+    }
+}
+
 #[repr(C)]
 pub struct MemRegion {
     pub address: u64,
@@ -13,6 +36,7 @@ pub struct MemRegion {
 }
 
 /// Rust representation of an e802 memory map.
+#[derive(Clone, Copy)]
 pub struct MemoryMap {
     pub data: &'static [MemRegion],
     pub max_addr: u64,
@@ -25,20 +49,72 @@ impl MemRegion {
 }
 
 impl MemoryMap {
-    pub fn from(data_ptr: *const MemRegion, len: usize) -> MemoryMap {
+    /// Reads the `len` entries of the e820 map at the physical address `addr`.
+    pub fn from<M: PhysicalMemory>(memory: &'static M, addr: PAddr, len: usize) -> MemoryMap {
+        let data_ptr = memory.phys_to_ptr(addr, len * mem::size_of::<MemRegion>()) as *const MemRegion;
         let data = unsafe { slice::from_raw_parts(data_ptr, len) };
 
         // HACK: we ignore regions starting above 4GiB because the current allocator cannot handle too many frames
         // TODO: improve the allocator so that it can identity map large address spaces (maybe use 1GiB hugepages?)
         let max_addr = data
             .iter()
-            .filter(|&region| region.address <= (1_u64 << 32))
+            .filter(|&region| region.address <= (1_u64 << 32) && region.length > 0)
             .map(|region| region.address + region.length - 1)
             .max()
             .expect("no regions in memory map");
 
         MemoryMap { data, max_addr }
     }
+
+    /// Converts the e820 map into the memory regions reported to the kernel.
+    ///
+    /// Usable e820 regions are split around the `in_use` ranges, which are reported with their own kind.
+    /// Returns the number of regions written to `regions`.
+    pub fn boot_memory_regions(&self, in_use: &[MemoryRegion], regions: &mut [MemoryRegion]) -> usize {
+        let mut count = 0;
+        let mut push = |region: MemoryRegion| {
+            if region.start < region.end {
+                assert!(count < regions.len(), "Too many memory regions for the boot info");
+                regions[count] = region;
+                count += 1;
+            }
+        };
+
+        for e820_region in self.data.iter() {
+            let start = read_from_packed!(e820_region.address);
+            let end = start + read_from_packed!(e820_region.length);
+
+            if !e820_region.usable() {
+                push(MemoryRegion { start, end, kind: MemoryRegionKind::Reserved });
+                continue;
+            }
+
+            // walk through the usable region and cut out every in-use range (in ascending order)
+            let mut current = start;
+            while current < end {
+                let next_in_use = in_use
+                    .iter()
+                    .filter(|used| used.end > current && used.start < end)
+                    .min_by_key(|used| used.start);
+
+                match next_in_use {
+                    Some(used) => {
+                        let used_start = used.start.max(current);
+                        let used_end = used.end.min(end);
+                        push(MemoryRegion { start: current, end: used_start, kind: MemoryRegionKind::Usable });
+                        push(MemoryRegion { start: used_start, end: used_end, kind: used.kind });
+                        current = used_end;
+                    }
+                    None => {
+                        push(MemoryRegion { start: current, end, kind: MemoryRegionKind::Usable });
+                        current = end;
+                    }
+                }
+            }
+        }
+
+        count
+    }
 }
 
 impl fmt::Display for MemoryMap {
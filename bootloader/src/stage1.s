@@ -3,7 +3,7 @@
 .code16
 
 # Stage 1 of the BIOS bootloader
-# Enable A20 line, initialize GDT and load rest of bootloader from disk
+# Switch to unreal mode and load rest of bootloader from disk
 
 _start:
 	# reset segment registers
@@ -23,15 +23,8 @@ _start:
 	mov si, offset stage1_start
 	call rm_println
 
-	# enable A20 line (fast method)
-	# https://wiki.osdev.org/A20_Line#Fast_A20_Gate
-	in al, 0x92
-	test al, 2
-	jnz a20_enabled
-	or al, 2
-	and al, 0xFE
-	out 0x92, al
-a20_enabled:
+	# the A20 line is enabled in stage 2 (stage 1 only needs the first MiB of memory)
+
 	#
 	# Enable protected mode
 	#
@@ -2,12 +2,52 @@
 .code16
 
 # Stage 2 of the BIOS bootloader
-# Load the kernel, create an e820 memory map and switch to protected mode
+# Enable the A20 line, show the boot menu, load the kernel and initrd, create an e820 memory map and switch to protected mode
 
 stage_2:
 	mov si, offset stage2_start
 	call rm_println
 
+	#
+	# Enable the A20 line
+	#
+	# required before anything is loaded above 1MiB, tries every known method from the least to the most
+	# dangerous one and verifies the result after each attempt
+	# https://wiki.osdev.org/A20_Line
+	#
+
+	call check_a20
+	jnz a20_enabled
+
+	# BIOS function INT15h, ax=2401h
+	mov ax, 0x2401
+	int 0x15
+	call check_a20
+	jnz a20_enabled
+
+	# keyboard controller output port
+	call enable_a20_keyboard_controller
+	call check_a20_repeatedly
+	jnz a20_enabled
+
+	# fast A20 gate
+	# https://wiki.osdev.org/A20_Line#Fast_A20_Gate
+	in al, 0x92
+	test al, 2
+	jnz a20_fast_gate_done
+	or al, 2
+	and al, 0xFE			# bit 0 would trigger a fast reset
+	out 0x92, al
+a20_fast_gate_done:
+	call check_a20_repeatedly
+	jnz a20_enabled
+
+	mov si, offset a20_failed_msg
+	call rm_println
+	jmp spin
+
+a20_enabled:
+
 	# declare the target mode to the BIOS (0x02 - Long Mode Target Only)
 	# should be done only once and before the first transition into long mode
 	# save flags because CF is set if this callback is not supported (which seems to be common)
@@ -20,6 +60,154 @@ stage_2:
 	popf
 
 
+	#
+	# Boot menu
+	#
+	# lists the entries of the boot configuration (generated by build.rs) and lets the user
+	# select the kernel slot and edit the kernel command line
+	#
+	# boot configuration layout:
+	#   0x0: entry count (byte), 0x1: default entry (byte), 0x2: timeout in seconds (word)
+	#   0x4: entries, 256 bytes each
+	#        0x00: name (null-terminated), 0x40: kernel slot (byte), 0x80: command line (null-terminated)
+	#
+
+boot_menu:
+	mov al, [_boot_config_start_addr + 1]
+	mov [menu_selected], al
+
+	# a timeout of zero boots the default entry without showing the menu
+	mov ax, [_boot_config_start_addr + 2]
+	test ax, ax
+	jz menu_boot_selected
+
+	# convert the timeout into ticks of the BIOS timer (~18.2 per second)
+	mov cx, 18
+	mul cx
+	mov [menu_timeout_ticks], ax
+
+	# remember when the menu was opened (low word of the tick count is sufficient)
+	xor ah, ah
+	int 0x1A
+	mov [menu_start_ticks], dx
+
+menu_redraw:
+	call menu_draw
+
+menu_wait_for_key:
+	# check if a key was pressed (ZF clear if a key is available)
+	mov ah, 0x01
+	int 0x16
+	jnz menu_key_pressed
+
+	# otherwise boot the selected entry once the timeout expired
+	cmp word ptr [menu_timeout_ticks], 0
+	je menu_wait_for_key
+	xor ah, ah
+	int 0x1A
+	sub dx, [menu_start_ticks]
+	cmp dx, [menu_timeout_ticks]
+	jb menu_wait_for_key
+	jmp menu_boot_selected
+
+menu_key_pressed:
+	# any key press cancels the timeout
+	mov word ptr [menu_timeout_ticks], 0
+
+	# read the key, al: ASCII character, ah: scan code
+	xor ah, ah
+	int 0x16
+
+	cmp al, 13				# enter
+	je menu_boot_selected
+	cmp al, 'e'
+	je menu_edit_cmdline
+	cmp ah, 0x48			# arrow up
+	je menu_select_previous
+	cmp ah, 0x50			# arrow down
+	je menu_select_next
+	jmp menu_wait_for_key
+
+menu_select_previous:
+	mov al, [menu_selected]
+	test al, al
+	jnz menu_select_previous_no_wrap
+	mov al, [_boot_config_start_addr]
+menu_select_previous_no_wrap:
+	dec al
+	mov [menu_selected], al
+	jmp menu_redraw
+
+menu_select_next:
+	mov al, [menu_selected]
+	inc al
+	cmp al, [_boot_config_start_addr]
+	jb menu_select_next_no_wrap
+	xor al, al
+menu_select_next_no_wrap:
+	mov [menu_selected], al
+	jmp menu_redraw
+
+menu_edit_cmdline:
+	call menu_copy_cmdline
+
+menu_edit_redraw:
+	call menu_draw
+	mov si, offset menu_edit_msg
+	call rm_println
+	mov si, offset _boot_cmdline
+	call rm_print
+
+menu_edit_wait_for_key:
+	xor ah, ah
+	int 0x16
+
+	cmp al, 13				# enter boots with the edited command line
+	je menu_boot_edited
+	cmp al, 27				# escape discards the changes
+	je menu_redraw
+	cmp al, 8				# backspace
+	je menu_edit_backspace
+
+	# ignore everything that is not printable ASCII
+	cmp al, 0x20
+	jb menu_edit_wait_for_key
+	cmp al, 0x7E
+	ja menu_edit_wait_for_key
+
+	# append the character if there is space left for it and the null terminator
+	mov bx, [menu_cmdline_len]
+	cmp bx, 127
+	jae menu_edit_wait_for_key
+	mov [_boot_cmdline + bx], al
+	mov byte ptr [_boot_cmdline + bx + 1], 0
+	inc bx
+	mov [menu_cmdline_len], bx
+
+	xor bx, bx				# page 0
+	call rm_print_char
+	jmp menu_edit_wait_for_key
+
+menu_edit_backspace:
+	mov bx, [menu_cmdline_len]
+	test bx, bx
+	jz menu_edit_wait_for_key
+	dec bx
+	mov [menu_cmdline_len], bx
+	mov byte ptr [_boot_cmdline + bx], 0
+	jmp menu_edit_redraw
+
+menu_boot_selected:
+	call menu_copy_cmdline
+
+menu_boot_edited:
+	# pass the kernel slot of the selected entry to stage 4
+	movzx bx, byte ptr [menu_selected]
+	shl bx, 8
+	mov al, [bx + _boot_config_start_addr + 4 + 0x40]
+	mov [_boot_entry_slot], al
+
+
 	#
 	# Create a memory map
 	# 
@@ -78,82 +266,309 @@ e820_init:
 
 
 	#
-	# Load the kernel
+	# Load stage 4, the kernel image and the initrd
 	#
 
-load_kernel:
-	# drive number
-	mov dl, 0x80
-	# load kernel into the transfer buffer located at 0:500h
-	mov word ptr [dap_buffer_segment], 0
-	mov eax, offset _kernel_buffer
-	mov [dap_buffer_offset], ax
+	# stage 4 (Rust part of the bootloader) runs from the 1MiB mark
+	mov eax, offset _stage_four_load_addr
+	mov ecx, offset _stage_four_size
+	mov edi, offset _stage_four_start_addr
+	call load_blob
 
-	# only 1 sector can be transferred at once
-	mov word ptr [dap_num_sectors], 1
+	# load the kernel image at the 4MiB mark
+	mov eax, offset _kernel_start_addr
+	mov ecx, offset _kernel_size
+	mov edi, 0x400000
+	call load_blob
+
+	# load the initrd at the first 4KiB aligned address after the kernel image
+	mov eax, offset _initrd_start_addr
+	mov ecx, offset _initrd_size
+	mov edi, offset _kernel_size
+	add edi, 0x400000 + 0xFFF
+	and edi, 0xFFFFF000
+	call load_blob
+
+
+	mov si, offset stage2_done
+	call rm_println
+
+
+	#
+	# Reenable protected mode and jump to stage 3
+	#
+
+	cli
+	lgdt [gdt_pointer]
+
+	mov eax, cr0
+	or al, 1
+	mov cr0, eax
+
+	# far jump, set cs descriptor and flush instruction queue
+	push 0x8
+	mov eax, offset stage_3
+	push eax
+	retf
+
+
+#
+# Copy a blob that was linked into the image from disk to its destination in memory
+# eax: start address of the blob in the image, ecx: size of the blob in bytes, edi: destination address
+# The BIOS can only write to conventional memory, so the sectors are read in chunks into the buffer at
+# _kernel_buffer and copied from there (requires unreal mode if the destination is above 1MiB)
+#
+
+load_blob:
+	# sector count
+	add ecx, 511		# align the blob to 512 byte
+	shr ecx, 9			# div 512
+	jz load_blob_done
 
 	# calc the start block index
-	mov eax, offset _kernel_start_addr
-	mov ebx, offset _start	# kernel_start - 0x7C00
+	mov ebx, offset _start	# blob_start - 0x7C00
 	sub eax, ebx
-	shr eax, 9				# div 9
+	shr eax, 9				# div 512
 	mov [dap_lba], eax
 
-	# load the kernel at the 4MiB mark
-	mov edi, 0x400000
+	# calc segment and offset of the transfer buffer
+	mov eax, offset _kernel_buffer
+	mov ebx, eax
+	shr ebx, 4				# div 16
+	mov [dap_buffer_segment], bx
+	and eax, 0xF			# mod 16
+	mov [dap_buffer_offset], ax
 
-	# sector count
-	mov ecx, offset _kernel_size
-	add ecx, 511		# align the kernel blob to 512 byte
-	shr ecx, 9			# div 9
+load_next_blob_chunk:
+	# load as many sectors as fit into the transfer buffer
+	mov ebx, ecx
+	cmp ebx, offset _kernel_buffer_sectors
+	jbe load_blob_chunk
+	mov ebx, offset _kernel_buffer_sectors
+load_blob_chunk:
+	mov [dap_num_sectors], bx
 
-load_next_kernel_sector:
-	# load the sector
+	mov dl, 0x80		# drive number
 	mov si, offset dap
 	mov ah, 0x42
 	int 0x13
-	jc kernel_load_failed
+	jc blob_load_failed
 
-	# copy sector from transfer buffer to the destination address
+	# the BIOS might not preserve the upper half of ebx, the packet holds the sector count
+	movzx ebx, word ptr [dap_num_sectors]
+
+	# copy the chunk from the transfer buffer to the destination address
 	push ecx
 	push esi
-	mov ecx, 512 / 4	# copy 4 byte at a time -> 128 iterations
-	# mov esi, offset _kernel_buffer
+	mov ecx, ebx
+	shl ecx, 7			# copy 4 byte at a time -> 128 iterations per sector
 	mov esi, offset _kernel_buffer
 	# move from esi to edi ecx times, increments esi and edi
 	rep movsd [edi], [esi]
 	pop esi
 	pop ecx
 
-	# next sector
-	mov eax, [dap_lba]
-	inc eax
-	mov [dap_lba], eax
+	# next chunk
+	add [dap_lba], ebx
+
+	sub ecx, ebx
+	jnz load_next_blob_chunk
+
+load_blob_done:
+	ret
+
+
+#
+# Check if the A20 line is enabled (ZF clear if enabled)
+#
+# with a disabled A20 line FFFF:0510 wraps around to 0000:0500, so writing
+# different values to both addresses will overwrite the first one
+#
+
+check_a20:
+	push ds
+	push es
+	push si
+	push di
+
+	xor ax, ax
+	mov es, ax
+	not ax
+	mov ds, ax
+	mov di, 0x0500
+	mov si, 0x0510
+
+	# save the original values
+	mov al, es:[di]
+	push ax
+	mov al, ds:[si]
+	push ax
+
+	mov byte ptr es:[di], 0x00
+	mov byte ptr ds:[si], 0xFF
+	cmp byte ptr es:[di], 0xFF
+
+	# restore the original values (does not modify the flags)
+	pop ax
+	mov ds:[si], al
+	pop ax
+	mov es:[di], al
+
+	mov ax, 0
+	je check_a20_done
+	mov ax, 1
+check_a20_done:
+	pop di
+	pop si
+	pop es
+	pop ds
+
+	test ax, ax
+	ret
+
+
+# some methods only take effect after a short delay
+check_a20_repeatedly:
+	mov cx, 0x1000
+check_a20_repeatedly_loop:
+	call check_a20
+	jnz check_a20_repeatedly_done
+	loop check_a20_repeatedly_loop
+check_a20_repeatedly_done:
+	ret
+
+
+#
+# Enable the A20 line through the output port of the 8042 keyboard controller
+# https://wiki.osdev.org/A20_Line#Enabling
+#
+
+enable_a20_keyboard_controller:
+	cli
+
+	call a20_wait_input
+	mov al, 0xAD			# disable keyboard
+	out 0x64, al
+
+	call a20_wait_input
+	mov al, 0xD0			# read from output port
+	out 0x64, al
+
+	call a20_wait_output
+	in al, 0x60
+	push ax
+
+	call a20_wait_input
+	mov al, 0xD1			# write to output port
+	out 0x64, al
+
+	call a20_wait_input
+	pop ax
+	or al, 2				# A20 bit
+	out 0x60, al
+
+	call a20_wait_input
+	mov al, 0xAE			# enable keyboard
+	out 0x64, al
+
+	call a20_wait_input
+	sti
+	ret
+
+# wait until the controller input buffer is empty
+# gives up after a while, some systems do not have a keyboard controller at all
+a20_wait_input:
+	mov cx, 0xFFFF
+a20_wait_input_loop:
+	in al, 0x64
+	test al, 2
+	jz a20_wait_done
+	loop a20_wait_input_loop
+	ret
+
+# wait until the controller output buffer is full
+a20_wait_output:
+	mov cx, 0xFFFF
+a20_wait_output_loop:
+	in al, 0x64
+	test al, 1
+	jnz a20_wait_done
+	loop a20_wait_output_loop
+a20_wait_done:
+	ret
+
+
+#
+# Draw the boot menu with the selected entry highlighted
+#
+
+menu_draw:
+	# setting the 80x25 text mode clears the screen
+	mov ax, 0x0003
+	int 0x10
+	xor bx, bx				# page 0 for the teletype output
+
+	mov si, offset menu_title_msg
+	call rm_println
 
-	sub ecx, 1
-	jnz load_next_kernel_sector
+	xor cx, cx
+menu_draw_entry:
+	cmp cl, [_boot_config_start_addr]
+	jae menu_draw_entries_done
+
+	mov si, offset menu_entry_prefix
+	cmp cl, [menu_selected]
+	jne menu_draw_entry_prefix
+	mov si, offset menu_selected_prefix
+menu_draw_entry_prefix:
+	push cx
+	call rm_print
+
+	# entry name
+	pop cx
+	mov si, cx
+	shl si, 8
+	add si, offset _boot_config_start_addr + 4
+	push cx
+	call rm_println
+	pop cx
 
+	inc cx
+	jmp menu_draw_entry
 
-	mov si, offset stage2_done
+menu_draw_entries_done:
+	mov si, offset menu_help_msg
 	call rm_println
 
+	cmp word ptr [menu_timeout_ticks], 0
+	je menu_draw_done
+	mov si, offset menu_timeout_msg
+	call rm_println
+menu_draw_done:
+	ret
 
-	#
-	# Reenable protected mode and jump to stage 3
-	#
 
-	cli
-	lgdt [gdt_pointer]
+#
+# Copy the command line of the selected entry into _boot_cmdline and store its length
+#
 
-	mov eax, cr0
-	or al, 1
-	mov cr0, eax
+menu_copy_cmdline:
+	movzx si, byte ptr [menu_selected]
+	shl si, 8
+	add si, offset _boot_config_start_addr + 4 + 0x80
+	mov di, offset _boot_cmdline
+	mov cx, 128
+	rep movsb
 
-	# far jump, set cs descriptor and flush instruction queue
-	push 0x8
-	mov eax, offset stage_3
-	push eax
-	retf
+	xor bx, bx
+menu_copy_cmdline_len:
+	cmp byte ptr [_boot_cmdline + bx], 0
+	je menu_copy_cmdline_done
+	inc bx
+	jmp menu_copy_cmdline_len
+menu_copy_cmdline_done:
+	mov [menu_cmdline_len], bx
+	ret
 
 
 .int15h_failed:
@@ -161,8 +576,8 @@ load_next_kernel_sector:
 	call rm_println
 	jmp spin
 
-kernel_load_failed:
-	mov si, offset kernel_load_failed_msg
+blob_load_failed:
+	mov si, offset blob_load_failed_msg
 	call rm_println
 	jmp spin
 
@@ -171,7 +586,26 @@ kernel_load_failed:
 stage2_start: .asciz "Starting stage two..."
 stage2_done: .asciz "Finished stage two"
 int15h_failed_msg: .asciz "Failed to load e820 memory map"
-kernel_load_failed_msg: .asciz "Failed to load the kernel"
+a20_failed_msg: .asciz "Failed to enable the A20 line (tried BIOS, keyboard controller and fast A20 gate)"
+blob_load_failed_msg: .asciz "Failed to load stage 4, the kernel or the initrd"
+
+menu_title_msg: .asciz "BeanOS boot menu\r\n"
+menu_entry_prefix: .asciz "    "
+menu_selected_prefix: .asciz "  > "
+menu_help_msg: .asciz "\r\nUp/Down: select, Enter: boot, e: edit command line"
+menu_timeout_msg: .asciz "The highlighted entry will be booted automatically."
+menu_edit_msg: .asciz "\r\nCommand line (Enter: boot, Esc: cancel):"
+
+menu_selected: .byte 0
+menu_timeout_ticks: .word 0
+menu_start_ticks: .word 0
+menu_cmdline_len: .word 0
 
 # number of available memory regions
 _memory_map_entries: .word 0
+
+# kernel slot of the selected boot menu entry
+_boot_entry_slot: .byte 0
+
+# (edited) command line of the selected boot menu entry, null-terminated
+_boot_cmdline: .space 128
@@ -137,6 +137,9 @@ map_p2_table_entry:
     mov esi, offset stage3_done_msg
     call vga_println
 
+    # a null frame pointer ends backtraces at stage 4
+    xor ebp, ebp
+
     # finally jump to stage 4 in long mode
     push 0x08       # 64-bit CS descriptor
     mov eax, offset stage_4
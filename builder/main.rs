@@ -3,12 +3,30 @@ A glorified build script for the OS and bootloader.
 
 */
 
-use std::{env, path::PathBuf, process::Command};
+use std::{env, fs, io, path::{Path, PathBuf}, process::Command};
+
+use x86_64::backtrace::BacktraceLine;
+use x86_64::elf::ElfFile;
 
 fn main() {
     let is_release_build = !cfg!(debug_assertions);
     let build_type = if is_release_build { "release" } else { "debug" };
 
+    // optional directory that gets packed into the initial ramdisk
+    let mut initrd_dir: Option<PathBuf> = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--initrd" => initrd_dir = Some(args.next().expect("Missing directory after '--initrd'").into()),
+            "--symbolize" => {
+                let elf_path = args.next().expect("Missing ELF file after '--symbolize'");
+                symbolize(Path::new(&elf_path));
+                return;
+            }
+            _ => panic!("Unknown argument '{}'. Usage: image_builder [--initrd <dir>] [--symbolize <elf>]", arg),
+        }
+    }
+
     // get llvm-objcopy through llvm_tools (requires llvm-tools-preview)
     let llvm_tools = match llvm_tools_build::LlvmTools::new() {
         Ok(tools) => tools,
@@ -70,6 +88,12 @@ fn main() {
     if is_release_build {
         cmd.arg("--release");
     }
+    // the bootloader build script links the initrd into the image
+    if let Some(initrd_dir) = &initrd_dir {
+        let initrd_archive = PathBuf::from(&project_root_dir).join("target/initrd.cpio");
+        create_initrd(initrd_dir, &initrd_archive);
+        cmd.env("BEAN_OS_INITRD", &initrd_archive);
+    }
     let cmd_status = cmd
         .status()
         .expect("Failed to run cargo to build bootloader");
@@ -129,7 +153,7 @@ fn main() {
 fn check_filesize(elf_path: &PathBuf) {
     let mut cmd = Command::new("readelf");
     cmd.arg("-t");
-    cmd.arg(&elf_path);
+    cmd.arg(elf_path);
     let output = cmd.output().expect("Failed to run readelf");
     assert!(output.status.success(), "Readelf command failed");
 
@@ -139,7 +163,9 @@ fn check_filesize(elf_path: &PathBuf) {
         .collect();
 
     let mut bootloader_size: u64 = 0;
+    let mut stage_four_size: u64 = 0;
     let mut kernel_size: u64 = 0;
+    let mut initrd_size: u64 = 0;
 
     // yikes
     for (i, &line) in seg_info.iter().enumerate() {
@@ -147,19 +173,114 @@ fn check_filesize(elf_path: &PathBuf) {
             let size_str = seg_info[i + 2].trim().split_once(' ').unwrap().0;
             bootloader_size = u64::from_str_radix(size_str, 16).unwrap();
         }
+        if line.contains(".stage_four") {
+            let size_str = seg_info[i + 2].trim().split_once(' ').unwrap().0;
+            stage_four_size = u64::from_str_radix(size_str, 16).unwrap();
+        }
         if line.contains(".kernel") {
             let size_str = seg_info[i + 2].trim().split_once(' ').unwrap().0;
             kernel_size = u64::from_str_radix(size_str, 16).unwrap();
         }
+        if line.contains(".initrd") {
+            let size_str = seg_info[i + 2].trim().split_once(' ').unwrap().0;
+            initrd_size = u64::from_str_radix(size_str, 16).unwrap();
+        }
     }
 
     println!("Bootloader segment size: 0x{:x} ({} out of 480 KiB used)", bootloader_size, bootloader_size / 1024);
+    println!("Stage 4 segment size:    0x{:x} ({} out of 3072 KiB used)", stage_four_size, stage_four_size / 1024);
     println!("Kernel segment size:     0x{:x} ({} KiB)", kernel_size, kernel_size / 1024);
+    println!("Initrd segment size:     0x{:x} ({} KiB)", initrd_size, initrd_size / 1024);
 
     if bootloader_size > 480 * 1024 {
         eprintln!("\x1b[93mWARNING: Bootloader might be overflowing usable memory region!\x1b[0m");
     }
 
+    // stage 4 runs between the 1MiB mark and the kernel image at 4MiB
+    if stage_four_size > 3 * 1024 * 1024 {
+        eprintln!("\x1b[93mWARNING: Bootloader stage 4 might be overlapping with the kernel image!\x1b[0m");
+    }
+
     println!();
 
 }
+
+/// Copies stdin to stdout and resolves the addresses of backtrace lines (`#N 0xADDR`) against
+/// the symbol table of `elf_path`.
+///
+/// Meant for the serial output of the bootloader, which has no symbols at runtime:
+/// `image_builder --symbolize bootloader/target/x86_64-bean_os_bootloader/debug/bootloader.sym < serial.log`
+fn symbolize(elf_path: &Path) {
+    let bytes = fs::read(elf_path).expect("Failed to read ELF file");
+    let elf = ElfFile::from(bytes.leak()).expect("Failed to parse ELF file");
+    let symbols = elf.symbol_table().expect("ELF file has no symbol table");
+
+    for line in io::stdin().lines() {
+        let line = line.expect("Failed to read from stdin");
+
+        // keep everything in front of the frame (e.g. timestamps), lines that already contain a symbol stay untouched
+        let frame = line.rsplit_once('#').and_then(|(prefix, frame)| {
+            let (index, addr) = frame.split_once(' ')?;
+            let index = index.parse().ok()?;
+            let addr = u64::from_str_radix(addr.trim_start().strip_prefix("0x")?, 16).ok()?;
+            Some((prefix, index, addr))
+        });
+
+        match frame {
+            Some((prefix, index, addr)) => println!("{}{}", prefix, BacktraceLine::new(index, addr, Some(&symbols))),
+            None => println!("{}", line),
+        }
+    }
+}
+
+/// Packs the contents of `dir` into a cpio archive in the "newc" format.
+///
+/// https://man.archlinux.org/man/cpio.5#New_ASCII_Format
+fn create_initrd(dir: &Path, archive_path: &Path) {
+    fn add_entry(archive: &mut Vec<u8>, ino: usize, name: &str, mode: u32, data: &[u8]) {
+        let header = format!(
+            "070701{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}",
+            ino, mode, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        // header and name as well as the file data are padded to a multiple of four bytes
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    fn add_dir(archive: &mut Vec<u8>, root: &Path, dir: &Path, ino: &mut usize) {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+            .expect("Failed to read initrd directory")
+            .map(|entry| entry.expect("Failed to read initrd directory entry").path())
+            .collect();
+        // sort for reproducible archives
+        entries.sort();
+
+        for path in entries {
+            let name = path.strip_prefix(root).unwrap().to_str().expect("Invalid initrd file name");
+            *ino += 1;
+            if path.is_dir() {
+                add_entry(archive, *ino, name, 0o040755, &[]);
+                add_dir(archive, root, &path, ino);
+            } else {
+                let data = fs::read(&path).expect("Failed to read initrd file");
+                add_entry(archive, *ino, name, 0o100644, &data);
+            }
+        }
+    }
+
+    assert!(dir.is_dir(), "Initrd directory '{}' does not exist", dir.display());
+
+    let mut archive = Vec::new();
+    let mut ino = 0;
+    add_dir(&mut archive, dir, dir, &mut ino);
+    add_entry(&mut archive, 0, "TRAILER!!!", 0, &[]);
+
+    fs::create_dir_all(archive_path.parent().unwrap()).expect("Failed to create initrd output directory");
+    fs::write(archive_path, &archive).expect("Failed to write initrd archive");
+
+    println!("Packed initrd '{}' into {} bytes", dir.display(), archive.len());
+}
@@ -0,0 +1,59 @@
+use core::ptr::addr_of;
+
+use x86_64::gdt::{self, Descriptor, GlobalDescriptorTable, SegmentSelector, TaskStateSegment};
+use x86_64::sync::Lazy;
+
+/// IST slot of the stack used by the double fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// Size of every interrupt and privilege level stack.
+const STACK_SIZE: usize = 4096 * 5;
+
+// only ever accessed through raw pointers by the CPU
+static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+static mut PRIVILEGE_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_top(addr_of!(DOUBLE_FAULT_STACK));
+    tss.privilege_stack_table[0] = stack_top(addr_of!(PRIVILEGE_STACK));
+    tss
+});
+
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+    // user data has to come right before user code for sysret
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    gdt.add_entry(Descriptor::user_data_segment());
+    gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+    (gdt, Selectors { kernel_code, kernel_data, tss })
+});
+
+struct Selectors {
+    kernel_code: SegmentSelector,
+    kernel_data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// Replaces the GDT of the bootloader with the kernel GDT and loads the TSS.
+///
+/// Must only be called once.
+pub fn init() {
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
+
+    unsafe {
+        gdt::set_cs(selectors.kernel_code);
+        gdt::load_ss(selectors.kernel_data);
+        gdt::load_ds(selectors.kernel_data);
+        gdt::load_es(selectors.kernel_data);
+        gdt::load_tss(selectors.tss);
+    }
+}
+
+fn stack_top(stack: *const [u8; STACK_SIZE]) -> u64 {
+    // the stack grows downwards and has to be 16-byte aligned
+    (stack as u64 + STACK_SIZE as u64) & !0xF
+}
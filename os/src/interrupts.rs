@@ -0,0 +1,86 @@
+use x86_64::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::pic::ChainedPics;
+use x86_64::registers::Cr2;
+use x86_64::sync::{Lazy, Mutex};
+
+use crate::gdt::DOUBLE_FAULT_IST_INDEX;
+use crate::serial::{self, COM1_COM3_IRQ, COM2_COM4_IRQ};
+
+/// IRQs 0-15 are delivered on the vectors right after the CPU exceptions.
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Spurious interrupts show up as the lowest priority IRQ of either PIC, even if it is masked.
+const PRIMARY_SPURIOUS_IRQ: u8 = 7;
+const SECONDARY_SPURIOUS_IRQ: u8 = 15;
+
+static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    unsafe {
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
+    idt[PIC_1_OFFSET + COM2_COM4_IRQ].set_handler_fn(com2_com4_handler);
+    idt[PIC_1_OFFSET + COM1_COM3_IRQ].set_handler_fn(com1_com3_handler);
+    idt[PIC_1_OFFSET + PRIMARY_SPURIOUS_IRQ].set_handler_fn(primary_spurious_handler);
+    idt[PIC_2_OFFSET + (SECONDARY_SPURIOUS_IRQ - 8)].set_handler_fn(secondary_spurious_handler);
+    idt
+});
+
+/// Registers the exception and IRQ handlers, loads the IDT and remaps the PICs with every IRQ masked.
+///
+/// Has to be called after `gdt::init`, the double fault handler uses a stack from the TSS.
+pub fn init() {
+    IDT.load();
+    unsafe { PICS.lock().initialize() };
+}
+
+/// Unmasks the IRQ line `irq`, its handler has to be registered in `init`.
+pub fn enable_irq(irq: u8) {
+    assert!([COM2_COM4_IRQ, COM1_COM3_IRQ].contains(&irq), "No handler for IRQ {}", irq);
+    unsafe { PICS.lock().set_masked(irq, false) };
+}
+
+fn end_of_interrupt(irq: u8) {
+    let mut pics = PICS.lock();
+    let vector = pics.vector(irq);
+    unsafe { pics.notify_end_of_interrupt(vector) };
+}
+
+extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: BREAKPOINT");
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT (error code 0x{:X})\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    panic!("EXCEPTION: PAGE FAULT at {:?} ({:?})\n{:#?}", Cr2::read(), error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn com2_com4_handler(_stack_frame: InterruptStackFrame) {
+    serial::handle_irq(COM2_COM4_IRQ);
+    end_of_interrupt(COM2_COM4_IRQ);
+}
+
+extern "x86-interrupt" fn com1_com3_handler(_stack_frame: InterruptStackFrame) {
+    serial::handle_irq(COM1_COM3_IRQ);
+    end_of_interrupt(COM1_COM3_IRQ);
+}
+
+extern "x86-interrupt" fn primary_spurious_handler(_stack_frame: InterruptStackFrame) {
+    end_of_interrupt(PRIMARY_SPURIOUS_IRQ);
+}
+
+extern "x86-interrupt" fn secondary_spurious_handler(_stack_frame: InterruptStackFrame) {
+    end_of_interrupt(SECONDARY_SPURIOUS_IRQ);
+}
@@ -1,37 +1,88 @@
 #![no_std]
 #![no_main]
+#![feature(abi_x86_interrupt)]
 
-use core::panic::PanicInfo;
-use core::ptr;
+use core::fmt::{self, Write};
+use core::slice;
 
-use x86_64::asm_wrappers::halt_loop;
+use log::{info, LevelFilter};
+
+use x86_64::boot_info::BootInfo;
+use x86_64::logger::{self, LogSink};
+use x86_64::sync::Once;
+use x86_64::time;
+
+#[macro_use]
+mod vga;
+mod gdt;
+mod interrupts;
+mod memory;
+mod panic;
+mod serial;
+use serial::LineConfig;
+
+/// Kept for the panic handler, which needs the stack bounds and symbol table for backtraces.
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
 
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
-    vga_println("Hello World!");
-    
-    halt_loop();
-}
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    BOOT_INFO.call_once(|| boot_info);
+    memory::init(boot_info);
+
+    println!("Hello World!");
 
-fn vga_println(string: &str) {
-    #[allow(non_upper_case_globals)]
-    static mut g_vga_buffer_offset: u32 = 0;
-
-    let mut address = unsafe { (0xB8000 + g_vga_buffer_offset) as *mut u16 };
-    for c in string.chars() {
-        let vga_char = (0x0F00 as u16) | (c as u16);
-        unsafe { 
-            ptr::write(address, vga_char);
-            address = address.add(1);
-            g_vga_buffer_offset += 2;
+    // the TSC keeps counting from where the bootloader left off
+    time::set_tsc_frequency(boot_info.tsc_frequency);
+
+    logger::init(LevelFilter::Info).expect("Failed to install the logger");
+    logger::add_sink(&VgaSink).expect("Failed to register the VGA log sink");
+
+    gdt::init();
+    interrupts::init();
+
+    for irq in serial::init(&LineConfig::default()).into_iter().flatten() {
+        interrupts::enable_irq(irq);
+    }
+    logger::add_sink(&SerialSink).expect("Failed to register the serial log sink");
+    x86_64::interrupts::enable();
+
+    if let Some(initrd) = boot_info.initrd() {
+        info!("Found initial ramdisk ({} bytes)", initrd.len());
+    }
+
+    // echo everything typed into COM1 on the screen and back
+    let mut buffer = [0; 64];
+    loop {
+        let count = serial::read_blocking(&serial::PORTS[0], &mut buffer);
+        for &byte in &buffer[..count] {
+            let echo: &[u8] = match byte {
+                b'\r' => b"\r\n",
+                // backspace or delete
+                0x08 | 0x7F => b"\x08 \x08",
+                _ => slice::from_ref(&byte),
+            };
+            echo.iter()
+                .try_for_each(|&byte| vga::WRITER.lock().write_char(byte as char))
+                .expect("Writing to the VGA buffer failed");
+            serial::PORTS[0].lock().write_all(echo);
         }
     }
-    unsafe { g_vga_buffer_offset += 160 - g_vga_buffer_offset % 160; };
 }
 
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    vga_println("Kernel panicked!");    
+/// Prints `log` records to the VGA buffer.
+struct VgaSink;
+
+impl LogSink for VgaSink {
+    fn write_line(&self, args: fmt::Arguments) {
+        vga::_print(args);
+    }
+}
+
+/// Writes `log` records to COM1, e.g. for QEMU's `-serial stdio`.
+struct SerialSink;
 
-    halt_loop();
+impl LogSink for SerialSink {
+    fn write_line(&self, args: fmt::Arguments) {
+        let _ = serial::PORTS[0].lock().write_fmt(args);
+    }
 }
@@ -0,0 +1,40 @@
+/*!
+Access to physical memory and the kernel page tables.
+
+The bootloader maps all of physical memory at `BootInfo::physical_memory_offset`. The identity
+mapping it used itself is not guaranteed to stay around, so physical addresses are always
+translated through that offset.
+*/
+
+use x86_64::addr::{PAddr, VAddr};
+use x86_64::boot_info::BootInfo;
+use x86_64::page_table::{OffsetMapping, OffsetPageTable, Translate};
+use x86_64::sync::{Mutex, Once};
+
+static PHYSICAL_MEMORY: Once<OffsetMapping> = Once::new();
+
+/// The active page table hierarchy.
+pub static PAGE_TABLE: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+
+/// Sets up the physical memory mapping and the mapper for the active page tables.
+///
+/// Has to be called before anything accesses physical memory, including the VGA console.
+pub fn init(boot_info: &BootInfo) {
+    let offset = boot_info.physical_memory_offset();
+    PHYSICAL_MEMORY.call_once(|| OffsetMapping::new(offset));
+
+    PAGE_TABLE.call_once(|| {
+        let page_table = unsafe { OffsetPageTable::active(offset) };
+        assert_eq!(
+            page_table.translate_addr(offset),
+            Some(PAddr::zero()),
+            "Physical memory is not mapped at the offset from the boot info"
+        );
+        Mutex::new(page_table)
+    });
+}
+
+/// Virtual address at which the physical address `addr` is mapped.
+pub fn phys_to_virt(addr: PAddr) -> VAddr {
+    PHYSICAL_MEMORY.get().expect("Physical memory is not mapped yet").phys_to_virt(addr)
+}
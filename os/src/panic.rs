@@ -0,0 +1,232 @@
+/*!
+Kernel panic handler.
+
+Prints the message, location, current CPU, a register snapshot and a backtrace to every console and
+then stops the kernel. What happens afterwards is selected with the `panic=` kernel command line
+option:
+
+- `panic=halt` (default) halts the CPU
+- `panic=reboot` resets the machine
+- `panic=qemu-exit` exits QEMU with a failure code (needs `-device isa-debug-exit,iobase=0xf4,iosize=0x04`)
+*/
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::asm_wrappers::halt_loop;
+use x86_64::backtrace::Backtrace;
+use x86_64::cpuid;
+use x86_64::gdt::DescriptorTablePointer;
+use x86_64::idt;
+use x86_64::interrupts;
+use x86_64::logger;
+use x86_64::port::Port;
+use x86_64::registers::{self, Cr0, Cr2, Cr3, Cr4};
+use x86_64::sync::Lazy;
+
+use crate::{serial, vga, BOOT_INFO};
+
+/// Keyboard controller command port, writing `0xFE` pulses the CPU reset line.
+const KEYBOARD_CONTROLLER: u16 = 0x64;
+const RESET_COMMAND: u8 = 0xFE;
+
+/// Port of QEMU's `isa-debug-exit` device, QEMU exits with the status `(value << 1) | 1`.
+const QEMU_EXIT_PORT: u16 = 0xF4;
+const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+/// Number of panics so far, the handler itself might panic while printing.
+static PANIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Written by `Registers::capture`, storing to a static does not need a register for the address.
+static mut GENERAL_PURPOSE_REGISTERS: [u64; 16] = [0; 16];
+
+/// Names of the general purpose registers in the order in which `Registers::capture` stores them.
+const GENERAL_PURPOSE_NAMES: [&str; 16] = [
+    "RAX", "RBX", "RCX", "RDX", "RSI", "RDI", "RBP", "RSP",
+    "R8", "R9", "R10", "R11", "R12", "R13", "R14", "R15",
+];
+
+/// What to do after the panic was reported.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PanicAction {
+    Halt,
+    Reboot,
+    QemuExit,
+}
+
+impl PanicAction {
+    /// Reads the `panic=` option from the kernel command line.
+    fn from_cmdline(cmdline: &str) -> PanicAction {
+        match cmdline.split_whitespace().find_map(|option| option.strip_prefix("panic=")) {
+            Some("reboot") => PanicAction::Reboot,
+            Some("qemu-exit") => PanicAction::QemuExit,
+            _ => PanicAction::Halt,
+        }
+    }
+}
+
+/// Registers on entry to the panic handler.
+///
+/// `core` formats the panic before the handler runs, so they show the state of the panic path and
+/// not of the code that panicked. CPU exceptions put the interrupt stack frame of the faulting code
+/// into the panic message instead.
+struct Registers {
+    general_purpose: [u64; 16],
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl Registers {
+    /// Has to be the first thing the handler does, before the compiler reuses the registers.
+    #[inline(always)]
+    fn capture() -> Registers {
+        unsafe {
+            asm!(
+                "mov [rip + {registers} + 0x00], rax",
+                "mov [rip + {registers} + 0x08], rbx",
+                "mov [rip + {registers} + 0x10], rcx",
+                "mov [rip + {registers} + 0x18], rdx",
+                "mov [rip + {registers} + 0x20], rsi",
+                "mov [rip + {registers} + 0x28], rdi",
+                "mov [rip + {registers} + 0x30], rbp",
+                "mov [rip + {registers} + 0x38], rsp",
+                "mov [rip + {registers} + 0x40], r8",
+                "mov [rip + {registers} + 0x48], r9",
+                "mov [rip + {registers} + 0x50], r10",
+                "mov [rip + {registers} + 0x58], r11",
+                "mov [rip + {registers} + 0x60], r12",
+                "mov [rip + {registers} + 0x68], r13",
+                "mov [rip + {registers} + 0x70], r14",
+                "mov [rip + {registers} + 0x78], r15",
+                registers = sym GENERAL_PURPOSE_REGISTERS,
+                options(nostack, preserves_flags),
+            );
+        }
+        Registers {
+            general_purpose: unsafe { *addr_of!(GENERAL_PURPOSE_REGISTERS) },
+            rflags: registers::read_rflags_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read_raw(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, value)) in GENERAL_PURPOSE_NAMES.iter().zip(self.general_purpose).enumerate() {
+            let separator = if i % 4 == 3 { "\n" } else { " " };
+            write!(f, "{:>3}=0x{:016X}{}", name, value, separator)?;
+        }
+        writeln!(f, "RFLAGS=0x{:016X}", self.rflags)?;
+        write!(f, "CR0=0x{:016X} CR2=0x{:016X} CR3=0x{:016X} CR4=0x{:016X}", self.cr0, self.cr2, self.cr3, self.cr4)
+    }
+}
+
+/// Writes a line to every registered console, or to the screen if none is registered yet and the screen is set up.
+fn print_line(args: fmt::Arguments) {
+    if logger::write_to_all_sinks(format_args!("{}\n", args)) == 0 {
+        // the panic might come from setting up the screen, which must not be started again
+        if let Some(writer) = Lazy::get(&vga::WRITER) {
+            let _ = writer.lock().write_fmt(format_args!("{}\n", args));
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    interrupts::disable();
+    freeze_other_cpus();
+
+    match PANIC_COUNT.fetch_add(1, Ordering::SeqCst) {
+        0 => (),
+        // printing the first panic failed, the consoles might be broken, so only try a plain message
+        1 => {
+            unlock_consoles();
+            print_line(format_args!("Kernel panicked while panicking"));
+            finish();
+        }
+        _ => halt_loop(),
+    }
+
+    unlock_consoles();
+
+    print_line(format_args!("\x1B[1;31mKernel panic\x1B[0m on CPU {}", cpuid::apic_id()));
+    match info.location() {
+        Some(location) => print_line(format_args!("at {}:{}:{}", location.file(), location.line(), location.column())),
+        None => print_line(format_args!("at an unknown location")),
+    }
+    print_line(format_args!("{}", info.message()));
+
+    print_line(format_args!("Registers on entry to the panic handler:"));
+    print_line(format_args!("{}", registers));
+
+    if let Some(boot_info) = BOOT_INFO.get() {
+        let symbols = boot_info.kernel_symbols();
+        print_line(format_args!("Backtrace:"));
+        for line in Backtrace::capture(boot_info.kernel_stack()).lines(symbols.as_ref()) {
+            print_line(format_args!("{}", line));
+        }
+    }
+
+    finish();
+}
+
+/// Releases the console locks, the panic might have interrupted a console in the middle of a write.
+fn unlock_consoles() {
+    unsafe {
+        if let Some(writer) = Lazy::get(&vga::WRITER) {
+            writer.force_unlock();
+        }
+        serial::PORTS.iter().for_each(|port| port.force_unlock());
+    }
+}
+
+/// Stops the other processors so that they can not interfere with the panic output.
+///
+/// The kernel only runs on the bootstrap processor so far. Once application processors are
+/// started, they have to be stopped here with an NMI.
+fn freeze_other_cpus() {}
+
+/// Halts, reboots or exits QEMU, depending on the `panic=` option.
+fn finish() -> ! {
+    let action = BOOT_INFO
+        .get()
+        .map_or(PanicAction::Halt, |boot_info| PanicAction::from_cmdline(boot_info.cmdline()));
+
+    // interrupts stay disabled, so everything that is still queued has to be sent right away, a port
+    // that is still locked is skipped instead of deadlocking
+    for port in serial::PORTS.iter() {
+        if let Some(mut port) = port.try_lock() {
+            port.flush();
+        }
+    }
+
+    match action {
+        PanicAction::Halt => halt_loop(),
+        PanicAction::Reboot => reboot(),
+        PanicAction::QemuExit => {
+            unsafe { Port::<u32>::new(QEMU_EXIT_PORT).write(QEMU_EXIT_FAILURE) };
+            // without the device QEMU keeps running
+            halt_loop();
+        }
+    }
+}
+
+fn reboot() -> ! {
+    unsafe { Port::<u8>::new(KEYBOARD_CONTROLLER).write(RESET_COMMAND) };
+
+    // without a keyboard controller, an empty IDT turns the next exception into a triple fault
+    let empty_idt = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { idt::lidt(&empty_idt) };
+    interrupts::int3();
+    halt_loop();
+}
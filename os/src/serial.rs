@@ -0,0 +1,366 @@
+/*!
+Driver for the 16550 UARTs of the serial ports COM1-COM4.
+
+Transmitting is buffered: bytes are queued and moved into the 16 byte transmit FIFO whenever it
+runs empty, either right away or from the transmitter interrupt. Received bytes are collected by
+the interrupt handler into a ring buffer until they are read.
+*/
+
+use core::fmt;
+
+use log::info;
+
+use x86_64::interrupts;
+use x86_64::port::Port;
+use x86_64::sync::Mutex;
+
+/// Base I/O ports of COM1-COM4.
+pub const COM_PORTS: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+
+/// IRQ lines of the ports, COM1 and COM3 share one line, COM2 and COM4 the other.
+pub const COM1_COM3_IRQ: u8 = 4;
+pub const COM2_COM4_IRQ: u8 = 3;
+
+pub static PORTS: [Mutex<SerialPort>; 4] = [
+    Mutex::new(SerialPort::new(COM_PORTS[0])),
+    Mutex::new(SerialPort::new(COM_PORTS[1])),
+    Mutex::new(SerialPort::new(COM_PORTS[2])),
+    Mutex::new(SerialPort::new(COM_PORTS[3])),
+];
+
+const RX_BUFFER_SIZE: usize = 1024;
+const TX_BUFFER_SIZE: usize = 1024;
+const FIFO_SIZE: usize = 16;
+
+/// Frequency of the UART clock divided by 16, the baud rate for a divisor of 1.
+const MAX_BAUD_RATE: u32 = 115200;
+
+// register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const INTERRUPT_ID: u16 = 2;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+// interrupt enable bits
+const RECEIVED_DATA_AVAILABLE: u8 = 0x01;
+const TRANSMITTER_EMPTY: u8 = 0x02;
+
+// line status bits
+const DATA_READY: u8 = 0x01;
+const TRANSMITTER_HOLDING_EMPTY: u8 = 0x20;
+
+const DIVISOR_LATCH_ACCESS: u8 = 0x80;
+
+#[allow(unused)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always set.
+    Mark,
+    /// Parity bit always cleared.
+    Space,
+}
+
+#[allow(unused)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopBits {
+    One,
+    /// 1.5 stop bits with 5 data bits.
+    Two,
+}
+
+/// Baud rate and frame format of a serial port.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LineConfig {
+    /// Has to divide 115200.
+    pub baud_rate: u32,
+    /// 5-8 bits per character.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineConfig {
+    fn divisor(&self) -> Result<u16, &'static str> {
+        if !MAX_BAUD_RATE.is_multiple_of(self.baud_rate) {
+            return Err("Unsupported baud rate");
+        }
+        Ok((MAX_BAUD_RATE / self.baud_rate) as u16)
+    }
+
+    fn line_control(&self) -> Result<u8, &'static str> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err("Unsupported number of data bits");
+        }
+        let parity = match self.parity {
+            Parity::None => 0x00,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0x00,
+            StopBits::Two => 0x04,
+        };
+        Ok((self.data_bits - 5) | stop_bits | parity)
+    }
+}
+
+/// 115200 baud, 8 data bits, no parity and one stop bit (8N1).
+impl Default for LineConfig {
+    fn default() -> Self {
+        LineConfig { baud_rate: MAX_BAUD_RATE, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One }
+    }
+}
+
+/// Fixed-size byte queue.
+struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> RingBuffer<N> {
+        RingBuffer { data: [0; N], head: 0, len: 0 }
+    }
+
+    /// Appends `byte`, returns false if the buffer is full.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.data[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A 16550 UART.
+pub struct SerialPort {
+    base: u16,
+    present: bool,
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort { base, present: false, rx: RingBuffer::new(), tx: RingBuffer::new() }
+    }
+
+    /// IRQ line of the port.
+    pub fn irq(&self) -> u8 {
+        if self.base == COM_PORTS[0] || self.base == COM_PORTS[2] { COM1_COM3_IRQ } else { COM2_COM4_IRQ }
+    }
+
+    /// Configures the port and enables the receive interrupt.
+    ///
+    /// Fails if the configuration is not supported or if no working UART answers the loopback test.
+    ///
+    /// # Safety
+    ///
+    /// The IRQ of the port needs a handler that calls `handle_interrupt`.
+    pub unsafe fn init(&mut self, config: &LineConfig) -> Result<(), &'static str> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+        self.present = false;
+
+        self.port(INTERRUPT_ENABLE).write(0x00);
+        self.port(LINE_CONTROL).write(DIVISOR_LATCH_ACCESS);
+        self.port(DATA).write(divisor as u8);
+        self.port(INTERRUPT_ENABLE).write((divisor >> 8) as u8);
+        self.port(LINE_CONTROL).write(line_control);
+        // enable and clear the FIFOs, interrupt once 14 bytes were received
+        self.port(FIFO_CONTROL).write(0xC7);
+
+        // loopback mode (and RTS, OUT1, OUT2), the transmitted byte has to come back
+        self.port(MODEM_CONTROL).write(0x1E);
+        self.port(DATA).write(0xAE);
+        if self.port(DATA).read() != 0xAE {
+            // leave loopback mode and keep OUT2 off, so the UART does not drive the shared IRQ line
+            self.port(MODEM_CONTROL).write(0x00);
+            return Err("Serial port failed the loopback test");
+        }
+
+        // normal operation with DTR, RTS, OUT1 and OUT2 (which connects the UART to its IRQ line)
+        self.port(MODEM_CONTROL).write(0x0F);
+        self.port(INTERRUPT_ENABLE).write(RECEIVED_DATA_AVAILABLE);
+        self.present = true;
+
+        Ok(())
+    }
+
+    /// Queues as many bytes as fit into the transmit buffer without blocking.
+    ///
+    /// Returns the number of queued bytes. Everything is discarded if the port is not present.
+    pub fn write(&mut self, bytes: &[u8]) -> usize {
+        if !self.present {
+            return bytes.len();
+        }
+
+        let count = bytes.iter().take_while(|&&byte| self.tx.push(byte)).count();
+        self.transmit();
+        count
+    }
+
+    /// Queues all of `bytes`, waits for the UART whenever the transmit buffer is full.
+    pub fn write_all(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let count = self.write(bytes);
+            bytes = &bytes[count..];
+        }
+    }
+
+    /// Blocks until every queued byte was handed to the UART.
+    pub fn flush(&mut self) {
+        while self.present && !self.tx.is_empty() {
+            self.transmit();
+        }
+    }
+
+    /// Copies received bytes into `buffer` without blocking, returns how many.
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        self.receive();
+
+        let mut count = 0;
+        for slot in buffer.iter_mut() {
+            let Some(byte) = self.rx.pop() else { break };
+            *slot = byte;
+            count += 1;
+        }
+        count
+    }
+
+    /// Handles all pending interrupts of the UART.
+    pub fn handle_interrupt(&mut self) {
+        if !self.present {
+            return;
+        }
+
+        // bit 0 is cleared while an interrupt is pending
+        loop {
+            let interrupt_id = unsafe { self.port(INTERRUPT_ID).read() };
+            if interrupt_id & 0x01 != 0 {
+                break;
+            }
+
+            match interrupt_id & 0x0E {
+                // line status (errors) and modem status changes are acknowledged by reading them
+                0x06 => unsafe { self.port(LINE_STATUS).read(); },
+                0x00 => unsafe { self.port(MODEM_STATUS).read(); },
+                // received data, 0x0C if it sat in the FIFO below the trigger level for a while
+                0x04 | 0x0C => self.receive(),
+                0x02 => self.transmit(),
+                _ => break,
+            }
+        }
+    }
+
+    /// Moves all bytes from the receive FIFO into the receive buffer.
+    fn receive(&mut self) {
+        if !self.present {
+            return;
+        }
+
+        while self.line_status() & DATA_READY != 0 {
+            // bytes are dropped if nobody reads them in time
+            let byte = unsafe { self.port(DATA).read() };
+            self.rx.push(byte);
+        }
+    }
+
+    /// Refills the transmit FIFO if it is empty and enables the transmitter interrupt while bytes are left.
+    fn transmit(&mut self) {
+        if self.line_status() & TRANSMITTER_HOLDING_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                let Some(byte) = self.tx.pop() else { break };
+                unsafe { self.port(DATA).write(byte) };
+            }
+        }
+
+        let interrupts = if self.tx.is_empty() {
+            RECEIVED_DATA_AVAILABLE
+        } else {
+            RECEIVED_DATA_AVAILABLE | TRANSMITTER_EMPTY
+        };
+        unsafe { self.port(INTERRUPT_ENABLE).write(interrupts) };
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.port(LINE_STATUS).read() }
+    }
+
+    fn port(&self, register: u16) -> Port<u8> {
+        Port::new(self.base + register)
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        self.write_all(string.as_bytes());
+        Ok(())
+    }
+}
+
+/// Probes COM1-COM4 and configures the ports that are present.
+///
+/// Returns the IRQ lines that have to be unmasked.
+pub fn init(config: &LineConfig) -> [Option<u8>; 4] {
+    let mut irqs = [None; 4];
+    for (index, port) in PORTS.iter().enumerate() {
+        let mut port = port.lock();
+        if unsafe { port.init(config) }.is_ok() {
+            info!("COM{} present at port 0x{:X}, IRQ {}", index + 1, port.base, port.irq());
+            irqs[index] = Some(port.irq());
+        }
+    }
+    irqs
+}
+
+/// Handles the interrupts of every port on `irq`.
+pub fn handle_irq(irq: u8) {
+    for port in PORTS.iter() {
+        let mut port = port.lock();
+        if port.irq() == irq {
+            port.handle_interrupt();
+        }
+    }
+}
+
+/// Waits until bytes were received on `port` and copies them into `buffer`.
+///
+/// Halts the CPU in between, so interrupts have to be enabled.
+pub fn read_blocking(port: &Mutex<SerialPort>, buffer: &mut [u8]) -> usize {
+    loop {
+        // the check and `hlt` must not be interrupted, otherwise the wake up could be missed
+        interrupts::disable();
+        let count = port.lock().read(buffer);
+        if count > 0 {
+            interrupts::enable();
+            return count;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
@@ -0,0 +1,320 @@
+/*!
+Text console on the VGA buffer (80x25 characters).
+
+Supports scrolling, tabs, backspace and a subset of the ANSI escape sequences:
+cursor movement (`A`, `B`, `C`, `D`, `G`, `H`, `f`), erasing (`J`, `K`) and colours (SGR `m`).
+*/
+
+use core::fmt::{self, Write};
+use core::ptr;
+
+use x86_64::addr::PAddr;
+use x86_64::ansi::{Action, ControlSequence, Parser};
+use x86_64::port::Port;
+use x86_64::sync::{Lazy, Mutex};
+
+use crate::memory;
+
+const BUFFER_HEIGHT: usize = 25;
+const BUFFER_WIDTH: usize = 80;
+const TAB_WIDTH: usize = 8;
+
+/// Physical address of the VGA text buffer.
+const BUFFER_ADDR: u64 = 0xB8000;
+
+/// CRT controller registers, used to move the hardware cursor.
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::vga::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+pub static WRITER: Lazy<Mutex<Writer>> = Lazy::new(|| {
+    let buffer = memory::phys_to_virt(PAddr::new(BUFFER_ADDR));
+    let mut writer = Writer::new(unsafe { &mut *buffer.as_mut_ptr::<Buffer>() });
+    writer.clear_screen();
+    Mutex::new(writer)
+});
+
+pub fn _print(args: fmt::Arguments) {
+    WRITER.lock().write_fmt(args).expect("Writing to the VGA buffer failed");
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Color {
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+impl Color {
+    const ALL: [Color; 16] = [
+        Color::Black, Color::Blue, Color::Green, Color::Cyan,
+        Color::Red, Color::Magenta, Color::Brown, Color::LightGray,
+        Color::DarkGray, Color::LightBlue, Color::LightGreen, Color::LightCyan,
+        Color::LightRed, Color::Pink, Color::Yellow, Color::White,
+    ];
+
+    /// Converts the ANSI colour index (black, red, green, yellow, blue, magenta, cyan, white).
+    fn from_ansi(index: u16, bright: bool) -> Color {
+        const ANSI_TO_VGA: [usize; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        let color = Color::ALL[ANSI_TO_VGA[index as usize % 8]];
+        if bright { color.bright() } else { color }
+    }
+
+    /// The bright variant of a colour (bit 3 of the colour value).
+    fn bright(self) -> Color {
+        Color::ALL[self as usize | 8]
+    }
+}
+
+/// Foreground colour in the low and background colour in the high nibble.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(transparent)]
+struct ColorCode(u8);
+
+impl ColorCode {
+    fn new(foreground: Color, background: Color) -> ColorCode {
+        ColorCode((background as u8) << 4 | (foreground as u8))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+struct ScreenChar {
+    character: u8,
+    color: ColorCode,
+}
+
+#[repr(transparent)]
+struct Buffer {
+    chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
+/// Current colours, changed through SGR sequences.
+#[derive(Clone, Copy)]
+struct Attributes {
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Attributes = Attributes {
+        foreground: Color::LightGray,
+        background: Color::Black,
+        bold: false,
+        reverse: false,
+    };
+
+    fn color_code(&self) -> ColorCode {
+        let foreground = if self.bold { self.foreground.bright() } else { self.foreground };
+        if self.reverse {
+            ColorCode::new(self.background, foreground)
+        } else {
+            ColorCode::new(foreground, self.background)
+        }
+    }
+}
+
+/// Writes text to the VGA buffer, interpreting control characters and escape sequences.
+pub struct Writer {
+    row: usize,
+    column: usize,
+    attributes: Attributes,
+    parser: Parser,
+    buffer: &'static mut Buffer,
+}
+
+impl Writer {
+    fn new(buffer: &'static mut Buffer) -> Writer {
+        Writer { row: 0, column: 0, attributes: Attributes::DEFAULT, parser: Parser::new(), buffer }
+    }
+
+    /// Feeds `c` to the escape sequence parser, the cursor is moved by `write_str`.
+    fn process_char(&mut self, c: char) {
+        match self.parser.advance(c) {
+            Some(Action::Print(c)) => self.print_char(c),
+            Some(Action::Execute(c)) => self.execute(c),
+            Some(Action::Csi(sequence)) => self.control_sequence(&sequence),
+            None => (),
+        }
+    }
+
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row, 0, BUFFER_WIDTH);
+        }
+        self.row = 0;
+        self.column = 0;
+    }
+
+    fn print_char(&mut self, c: char) {
+        // the line wraps when the next character is printed, so text can end in the last column
+        if self.column >= BUFFER_WIDTH {
+            self.new_line();
+        }
+
+        // code page 437 matches ASCII for printable characters, everything else becomes a ■
+        let character = match c {
+            ' '..='~' => c as u8,
+            _ => 0xFE,
+        };
+        self.write_at(self.row, self.column, ScreenChar { character, color: self.attributes.color_code() });
+        self.column += 1;
+    }
+
+    fn execute(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column = 0,
+            '\t' => self.column = ((self.column / TAB_WIDTH + 1) * TAB_WIDTH).min(BUFFER_WIDTH - 1),
+            '\x08' => self.column = self.column.min(BUFFER_WIDTH - 1).saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    fn control_sequence(&mut self, sequence: &ControlSequence) {
+        if sequence.private {
+            return;
+        }
+
+        let count = sequence.param(0, 1) as usize;
+        let column = self.column.min(BUFFER_WIDTH - 1);
+        match sequence.final_byte {
+            b'A' => self.row = self.row.saturating_sub(count),
+            b'B' => self.row = (self.row + count).min(BUFFER_HEIGHT - 1),
+            b'C' => self.column = (column + count).min(BUFFER_WIDTH - 1),
+            b'D' => self.column = column.saturating_sub(count),
+            b'G' => self.column = count.min(BUFFER_WIDTH) - 1,
+            b'H' | b'f' => {
+                self.row = count.min(BUFFER_HEIGHT) - 1;
+                self.column = (sequence.param(1, 1) as usize).min(BUFFER_WIDTH) - 1;
+            }
+            b'J' => match sequence.param(0, 0) {
+                0 => {
+                    self.clear_row(self.row, column, BUFFER_WIDTH);
+                    (self.row + 1..BUFFER_HEIGHT).for_each(|row| self.clear_row(row, 0, BUFFER_WIDTH));
+                }
+                1 => {
+                    (0..self.row).for_each(|row| self.clear_row(row, 0, BUFFER_WIDTH));
+                    self.clear_row(self.row, 0, column + 1);
+                }
+                2 | 3 => (0..BUFFER_HEIGHT).for_each(|row| self.clear_row(row, 0, BUFFER_WIDTH)),
+                _ => (),
+            },
+            b'K' => match sequence.param(0, 0) {
+                0 => self.clear_row(self.row, column, BUFFER_WIDTH),
+                1 => self.clear_row(self.row, 0, column + 1),
+                2 => self.clear_row(self.row, 0, BUFFER_WIDTH),
+                _ => (),
+            },
+            b'm' => self.select_graphic_rendition(sequence),
+            _ => (),
+        }
+    }
+
+    /// Applies the SGR parameters in order, `ESC [ m` resets all attributes.
+    fn select_graphic_rendition(&mut self, sequence: &ControlSequence) {
+        if sequence.params().is_empty() {
+            self.attributes = Attributes::DEFAULT;
+        }
+
+        let attributes = &mut self.attributes;
+        for &param in sequence.params() {
+            match param {
+                0 => *attributes = Attributes::DEFAULT,
+                1 => attributes.bold = true,
+                22 => attributes.bold = false,
+                7 => attributes.reverse = true,
+                27 => attributes.reverse = false,
+                30..=37 => attributes.foreground = Color::from_ansi(param - 30, false),
+                39 => attributes.foreground = Attributes::DEFAULT.foreground,
+                40..=47 => attributes.background = Color::from_ansi(param - 40, false),
+                49 => attributes.background = Attributes::DEFAULT.background,
+                90..=97 => attributes.foreground = Color::from_ansi(param - 90, true),
+                // bit 7 of the colour code makes characters blink in the default text mode,
+                // so bright backgrounds are shown with the dark colour
+                100..=107 => attributes.background = Color::from_ansi(param - 100, false),
+                _ => (),
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row < BUFFER_HEIGHT - 1 {
+            self.row += 1;
+            return;
+        }
+
+        for row in 1..BUFFER_HEIGHT {
+            for column in 0..BUFFER_WIDTH {
+                let character = self.read_at(row, column);
+                self.write_at(row - 1, column, character);
+            }
+        }
+        self.clear_row(BUFFER_HEIGHT - 1, 0, BUFFER_WIDTH);
+    }
+
+    /// Clears the columns `[start, end)` of `row` with the current background colour.
+    fn clear_row(&mut self, row: usize, start: usize, end: usize) {
+        let blank = ScreenChar { character: b' ', color: self.attributes.color_code() };
+        for column in start..end {
+            self.write_at(row, column, blank);
+        }
+    }
+
+    fn read_at(&self, row: usize, column: usize) -> ScreenChar {
+        unsafe { ptr::read_volatile(&self.buffer.chars[row][column]) }
+    }
+
+    fn write_at(&mut self, row: usize, column: usize, character: ScreenChar) {
+        unsafe { ptr::write_volatile(&mut self.buffer.chars[row][column], character) }
+    }
+
+    /// Moves the blinking hardware cursor to the current position.
+    fn update_cursor(&self) {
+        let position = (self.row * BUFFER_WIDTH + self.column.min(BUFFER_WIDTH - 1)) as u16;
+
+        let mut index = Port::<u8>::new(CRTC_INDEX);
+        let mut data = Port::<u8>::new(CRTC_DATA);
+        unsafe {
+            index.write(0x0F);
+            data.write(position as u8);
+            index.write(0x0E);
+            data.write((position >> 8) as u8);
+        }
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        string.chars().for_each(|c| self.process_char(c));
+        self.update_cursor();
+        Ok(())
+    }
+}
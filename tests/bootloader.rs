@@ -0,0 +1,476 @@
+//! Host-side tests of the bootloader: its memory setup and the kernel image format.
+//!
+//! The bootloader modules are compiled for the host (like `bootloader/build.rs` does for
+//! `kernel_blob.rs`) and run against a synthetic physical address space. E820 maps are written into
+//! it and the resulting page tables are inspected without booting a VM.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::{mem, ptr};
+
+use x86_64::addr::{PAddr, VAddr};
+use x86_64::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::frame::Frame;
+use x86_64::page_table::{MappedPageTable, PageDir, PageTable, PageTableFrameMapping, Translate};
+
+#[path = "../bootloader/src/memory.rs"]
+#[allow(dead_code)]
+mod memory;
+use memory::{MemRegion, MemoryMap, PhysicalMemory};
+
+#[path = "../bootloader/src/allocator.rs"]
+#[allow(dead_code)]
+mod allocator;
+use allocator::BumpFrameAllocator;
+
+#[path = "../bootloader/src/kernel_blob.rs"]
+#[allow(dead_code)]
+mod kernel_blob;
+use kernel_blob::{crc32, Compression, ImageHeader, SlotEntry, HEADER_SIZE, MAX_SLOTS};
+
+#[path = "../bootloader/src/lz4.rs"]
+mod lz4;
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => (std::print!($($arg)*));
+}
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => (std::println!($($arg)*));
+}
+
+const GIB: u64 = 1 << 30;
+const USABLE: u32 = 1;
+const RESERVED: u32 = 2;
+
+/// Where the synthetic e820 map is stored (stage 2 uses a buffer in low memory as well).
+const MEMORY_MAP_ADDR: u64 = 0x8000;
+/// The level 4 table that stage 3 set up.
+const LEVEL_4_TABLE_ADDR: u64 = 0x1000;
+
+/// The e820 map of QEMU with 128MiB of memory.
+const QEMU_128MIB: &[(u64, u64, u32)] = &[
+    (0x0, 0x9FC00, USABLE),
+    (0x9FC00, 0x400, RESERVED),
+    (0xF0000, 0x10000, RESERVED),
+    (0x100000, 0x7EE0000, USABLE),
+    (0x7FE0000, 0x20000, RESERVED),
+    (0xFFFC0000, 0x40000, RESERVED),
+];
+
+/// Sparse physical address space, frames are allocated on first access.
+///
+/// Every access has to lie inside of a usable region of the e820 map, so writes into holes or
+/// reserved memory fail the test.
+struct SimulatedMemory {
+    usable: Vec<(u64, u64)>,
+    frames: RefCell<BTreeMap<u64, Box<PageTable>>>,
+}
+
+impl SimulatedMemory {
+    /// Creates the address space described by `regions` (address, length, type) and stores the
+    /// e820 map at `MEMORY_MAP_ADDR`.
+    fn new(regions: &[(u64, u64, u32)]) -> &'static SimulatedMemory {
+        let usable = regions
+            .iter()
+            .filter(|&&(_, length, reg_type)| reg_type == USABLE && length > 0)
+            .map(|&(address, length, _)| (address, address + length))
+            .collect();
+        let memory = Box::leak(Box::new(SimulatedMemory { usable, frames: RefCell::new(BTreeMap::new()) }));
+
+        let len = regions.len() * mem::size_of::<MemRegion>();
+        let data = memory.phys_to_ptr(PAddr::new(MEMORY_MAP_ADDR), len) as *mut MemRegion;
+        for (i, &(address, length, reg_type)) in regions.iter().enumerate() {
+            unsafe { ptr::write(data.add(i), MemRegion { address, length, reg_type, attr: 0 }) };
+        }
+
+        memory
+    }
+
+    fn memory_map(&'static self, len: usize) -> MemoryMap {
+        MemoryMap::from(self, PAddr::new(MEMORY_MAP_ADDR), len)
+    }
+
+    /// Number of frames that were written or read.
+    fn touched_frames(&self) -> usize {
+        self.frames.borrow().len()
+    }
+}
+
+unsafe impl PhysicalMemory for SimulatedMemory {
+    fn phys_to_ptr(&self, addr: PAddr, len: usize) -> *mut u8 {
+        let (start, end) = (addr.as_u64(), addr.as_u64() + len as u64);
+        assert!(
+            self.usable.iter().any(|&(usable_start, usable_end)| start >= usable_start && end <= usable_end),
+            "Access to unusable physical memory [0x{:X}, 0x{:X})", start, end
+        );
+        let frame = start & !0xFFF;
+        assert!(end <= frame + 4096, "Access crosses a frame boundary");
+
+        let mut frames = self.frames.borrow_mut();
+        let table = frames.entry(frame).or_insert_with(|| Box::new(PageTable::new()));
+        unsafe { (&mut **table as *mut PageTable as *mut u8).add((start - frame) as usize) }
+    }
+}
+
+unsafe impl PageTableFrameMapping for &SimulatedMemory {
+    fn frame_to_pointer(&self, frame: Frame) -> *mut PageTable {
+        self.phys_to_ptr(frame.start_address(), 4096) as *mut PageTable
+    }
+}
+
+/// Builds the memory map, an allocator starting at `start` and an empty level 4 table.
+fn setup(
+    regions: &[(u64, u64, u32)],
+    start: u64,
+) -> (&'static SimulatedMemory, BumpFrameAllocator, MappedPageTable<'static, &'static SimulatedMemory>) {
+    let memory = SimulatedMemory::new(regions);
+    let memory_map = memory.memory_map(regions.len());
+    let allocator = BumpFrameAllocator::starting_at(Frame::containing_address(PAddr::new(start)), memory_map);
+
+    let level_4_table = unsafe { &mut *memory.frame_to_pointer(Frame::containing_address(PAddr::new(LEVEL_4_TABLE_ADDR))) };
+    let page_table = unsafe { MappedPageTable::new(level_4_table, memory) };
+    (memory, allocator, page_table)
+}
+
+fn with_high_memory(size: u64) -> Vec<(u64, u64, u32)> {
+    let mut regions = QEMU_128MIB.to_vec();
+    regions.push((1 << 32, size, USABLE));
+    regions
+}
+
+#[test]
+fn memory_map() {
+    let memory = SimulatedMemory::new(QEMU_128MIB);
+    let memory_map = memory.memory_map(QEMU_128MIB.len());
+    assert_eq!(memory_map.data.len(), 6);
+    assert!(memory_map.data[0].usable());
+    assert!(!memory_map.data[1].usable());
+    assert_eq!(memory_map.max_addr, 0xFFFF_FFFF);
+}
+
+#[test]
+fn memory_map_high_regions() {
+    // regions that start above 4GiB are ignored (a region starting at exactly 4GiB is not)
+    let mut regions = with_high_memory(2 * GIB);
+    let memory = SimulatedMemory::new(&regions);
+    assert_eq!(memory.memory_map(regions.len()).max_addr, 0x1_7FFF_FFFF);
+
+    regions.push((0xFD_0000_0000, 0x3_0000_0000, RESERVED));
+    let memory = SimulatedMemory::new(&regions);
+    assert_eq!(memory.memory_map(regions.len()).max_addr, 0x1_7FFF_FFFF);
+}
+
+#[test]
+fn memory_map_tiny_and_overlapping_regions() {
+    let regions = [
+        (0x0, 0x0, RESERVED),
+        (0x0, 0x9FC00, USABLE),
+        (0x9F000, 0x1000, RESERVED),
+        (0x100000, 0x1, USABLE),
+        (0x100000, 0x3FF00000, USABLE),
+        (0x40000000, 0x0, RESERVED),
+    ];
+    let memory = SimulatedMemory::new(&regions);
+    assert_eq!(memory.memory_map(regions.len()).max_addr, 0x3FFF_FFFF);
+}
+
+#[test]
+#[should_panic(expected = "no regions in memory map")]
+fn memory_map_empty() {
+    SimulatedMemory::new(QEMU_128MIB).memory_map(0);
+}
+
+#[test]
+fn boot_memory_regions() {
+    let regions = [(0x0, 0x9FC00, USABLE), (0x9FC00, 0x400, RESERVED), (0x100000, 0x700000, USABLE)];
+    let memory = SimulatedMemory::new(&regions);
+    let memory_map = memory.memory_map(regions.len());
+
+    // the second range covers the hole between the first two e820 regions
+    let in_use = [
+        MemoryRegion { start: 0x400000, end: 0x500000, kind: MemoryRegionKind::Initrd },
+        MemoryRegion { start: 0x7000, end: 0x100000, kind: MemoryRegionKind::Bootloader },
+    ];
+    let mut output = [MemoryRegion { start: 0, end: 0, kind: MemoryRegionKind::Reserved }; 8];
+    let count = memory_map.boot_memory_regions(&in_use, &mut output);
+
+    let expected = [
+        (0x0, 0x7000, MemoryRegionKind::Usable),
+        (0x7000, 0x9FC00, MemoryRegionKind::Bootloader),
+        (0x9FC00, 0xA0000, MemoryRegionKind::Reserved),
+        (0x100000, 0x400000, MemoryRegionKind::Usable),
+        (0x400000, 0x500000, MemoryRegionKind::Initrd),
+        (0x500000, 0x800000, MemoryRegionKind::Usable),
+    ];
+    let output: Vec<_> = output[..count].iter().map(|region| (region.start, region.end, region.kind)).collect();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn allocate_frames() {
+    let (_, mut allocator, _) = setup(QEMU_128MIB, 0x500000);
+    assert_eq!(allocator.allocate_frame().start_address().as_u64(), 0x500000);
+    assert_eq!(allocator.allocate_frame().start_address().as_u64(), 0x501000);
+
+    let first = allocator.allocate_contiguous(4);
+    assert_eq!(first.start_address().as_u64(), 0x502000);
+    assert_eq!(allocator.next_free_addr(), 0x506000);
+}
+
+#[test]
+fn allocate_last_frame_of_region() {
+    let (_, mut allocator, _) = setup(QEMU_128MIB, 0x7FDE000);
+    assert_eq!(allocator.allocate_contiguous(2).start_address().as_u64(), 0x7FDE000);
+    assert_eq!(allocator.next_free_addr(), 0x7FE0000);
+}
+
+#[test]
+#[should_panic(expected = "Out of memory in the current region")]
+fn allocate_past_region() {
+    let (_, mut allocator, _) = setup(QEMU_128MIB, 0x7FDF000);
+    allocator.allocate_frame();
+    allocator.allocate_frame();
+}
+
+#[test]
+#[should_panic(expected = "Tried to init allocator in invalid memory region")]
+fn allocator_in_hole() {
+    setup(QEMU_128MIB, 0xA0000);
+}
+
+#[test]
+#[should_panic(expected = "Tried to init allocator in invalid memory region")]
+fn allocator_in_tiny_region() {
+    // a usable region smaller than a frame can not hold a single allocation
+    let mut regions = QEMU_128MIB.to_vec();
+    regions.push((0x8000000, 0x800, USABLE));
+    setup(&regions, 0x8000000);
+}
+
+#[test]
+fn identity_map_1gib_pages() {
+    let (memory, mut allocator, mut page_table) = setup(QEMU_128MIB, 0x500000);
+    allocator.identity_map_all(&mut page_table, true);
+
+    // a single PDPT maps the upper three GiB, the first one is left to stage 3
+    assert_eq!(allocator.next_free_addr(), 0x501000);
+    assert_eq!(page_table.translate_addr(VAddr::new(0x3FFF_FFFF)), None);
+    for addr in [GIB, 0x8765_4321, 0xFFFF_FFFF] {
+        assert_eq!(page_table.translate_addr(VAddr::new(addr)), Some(PAddr::new(addr)));
+    }
+    assert_eq!(page_table.translate_addr(VAddr::new(4 * GIB)), None);
+
+    let pdpt = unsafe { &*memory.frame_to_pointer(Frame::containing_address(PAddr::new(0x500000))) };
+    assert!(pdpt[0].is_unused());
+    for entry in pdpt.iter().skip(1).take(3) {
+        assert!(entry.flags().contains(PageDir::Present | PageDir::Write | PageDir::HugePage));
+    }
+    assert!(pdpt[4].is_unused());
+}
+
+#[test]
+fn identity_map_2mib_pages() {
+    let (memory, mut allocator, mut page_table) = setup(QEMU_128MIB, 0x500000);
+    allocator.identity_map_all(&mut page_table, false);
+
+    // one PDPT and a page directory per GiB
+    assert_eq!(allocator.next_free_addr(), 0x504000);
+    for addr in [GIB, 0x8765_4321, 0xFFFF_FFFF] {
+        assert_eq!(page_table.translate_addr(VAddr::new(addr)), Some(PAddr::new(addr)));
+    }
+
+    let page_directory = unsafe { &*memory.frame_to_pointer(Frame::containing_address(PAddr::new(0x501000))) };
+    assert!(page_directory.iter().all(|entry| entry.flags().contains(PageDir::Present | PageDir::HugePage)));
+    assert_eq!(page_directory[1].addr(), PAddr::new(GIB + 0x200000));
+}
+
+#[test]
+fn identity_map_high_memory() {
+    let regions = with_high_memory(GIB + 0x1000);
+    let (_, mut allocator, mut page_table) = setup(&regions, 0x500000);
+    allocator.identity_map_all(&mut page_table, true);
+
+    // the end is rounded up to the next GiB
+    for addr in [4 * GIB, 5 * GIB, 6 * GIB - 1] {
+        assert_eq!(page_table.translate_addr(VAddr::new(addr)), Some(PAddr::new(addr)));
+    }
+    assert_eq!(page_table.translate_addr(VAddr::new(6 * GIB)), None);
+}
+
+#[test]
+fn identity_map_small_address_space() {
+    // without the BIOS region at the end of the 32-bit address space everything fits into the first GiB
+    let regions = &QEMU_128MIB[..5];
+    let (memory, mut allocator, mut page_table) = setup(regions, 0x500000);
+    let touched_frames = memory.touched_frames();
+    allocator.identity_map_all(&mut page_table, true);
+
+    assert_eq!(allocator.next_free_addr(), 0x500000);
+    assert_eq!(memory.touched_frames(), touched_frames);
+    assert_eq!(page_table.translate_addr(VAddr::new(GIB)), None);
+}
+
+#[test]
+fn map_physical_memory() {
+    let regions = with_high_memory(GIB);
+    let offset = VAddr::new(0xFFFF_C000_0000_0000);
+    for use_1gib_pages in [true, false] {
+        let (_, mut allocator, mut page_table) = setup(&regions, 0x500000);
+        allocator.map_physical_memory(&mut page_table, offset, PageDir::Write | PageDir::NoExecute, use_1gib_pages);
+
+        for addr in [0, 0x7C00, 0xFFFF_FFFF, 0x1_2345_6789, 5 * GIB - 1] {
+            assert_eq!(page_table.translate_addr(offset + addr), Some(PAddr::new(addr)));
+        }
+        assert_eq!(page_table.translate_addr(offset + 5 * GIB), None);
+        assert_eq!(page_table.translate_addr(VAddr::new(0x7C00)), None);
+    }
+}
+
+/// `Hello Hello Hello Hello, LZ4 world! world! world!` compressed by the reference `lz4` tool.
+const LZ4_BLOCK: &[u8] = b"\x6DHello \x06\x00\xC5, LZ4 world!\x07\x00\x50orld!";
+const LZ4_TEXT: &[u8] = b"Hello Hello Hello Hello, LZ4 world! world! world!";
+
+#[test]
+fn lz4_reference_block() {
+    let mut output = [0; 64];
+    assert_eq!(lz4::decompress(LZ4_BLOCK, &mut output), Ok(LZ4_TEXT.len()));
+    assert_eq!(&output[..LZ4_TEXT.len()], LZ4_TEXT);
+
+    // the output buffer may be exactly as large as the data
+    let mut output = [0; LZ4_TEXT.len()];
+    assert_eq!(lz4::decompress(LZ4_BLOCK, &mut output), Ok(LZ4_TEXT.len()));
+}
+
+#[test]
+fn lz4_long_lengths() {
+    // 15 + 255 + 2 literals and an overlapping match of 15 + 255 + 0 + 4 bytes
+    let mut block = vec![0xFF, 255, 2];
+    block.extend(std::iter::repeat_n(b'a', 272));
+    block.extend([1, 0, 255, 0, 0x00]);
+
+    let mut output = vec![0; 272 + 274];
+    assert_eq!(lz4::decompress(&block, &mut output), Ok(output.len()));
+    assert!(output.iter().all(|&byte| byte == b'a'));
+}
+
+#[test]
+fn lz4_truncated_block() {
+    let mut output = [0; 64];
+    assert_eq!(lz4::decompress(&[], &mut output), Err("LZ4 block ended unexpectedly"));
+    assert_eq!(lz4::decompress(&[0x50, b'a', b'b'], &mut output), Err("LZ4 literals exceed input size"));
+    assert_eq!(lz4::decompress(&[0xF0, 255], &mut output), Err("LZ4 length ended unexpectedly"));
+    assert_eq!(lz4::decompress(&[0x1F, b'a', 1, 0, 255], &mut output), Err("LZ4 length ended unexpectedly"));
+    assert_eq!(lz4::decompress(&[0x10, b'a', 1], &mut output), Err("LZ4 match offset missing"));
+    // the last sequence must not end in a match
+    assert_eq!(lz4::decompress(&[0x10, b'a', 1, 0], &mut output), Err("LZ4 block ended unexpectedly"));
+}
+
+#[test]
+fn lz4_overlong_lengths() {
+    let mut output = [0; 8];
+    assert_eq!(lz4::decompress(b"\x90123456789", &mut output), Err("LZ4 literals exceed output size"));
+    assert_eq!(lz4::decompress(&[0x15, b'a', 1, 0, 0x00], &mut output), Err("LZ4 match exceeds output size"));
+    assert_eq!(lz4::decompress(&[0xFF, 255, 255, 255], &mut output), Err("LZ4 length ended unexpectedly"));
+}
+
+#[test]
+fn lz4_invalid_offsets() {
+    let mut output = [0; 64];
+    assert_eq!(lz4::decompress(&[0x10, b'a', 0, 0, 0x00], &mut output), Err("Invalid LZ4 match offset"));
+    assert_eq!(lz4::decompress(&[0x10, b'a', 2, 0, 0x00], &mut output), Err("Invalid LZ4 match offset"));
+    assert_eq!(lz4::decompress(&[0x00, 1, 0, 0x00], &mut output), Err("Invalid LZ4 match offset"));
+}
+
+#[test]
+fn crc32_check_value() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+/// Builds an image with one slot per kernel, the primary one LZ4 compressed.
+fn kernel_image(kernels: &[&[u8]]) -> (ImageHeader, Vec<u8>) {
+    let mut header = ImageHeader { slot_count: kernels.len(), slots: [SlotEntry::default(); MAX_SLOTS] };
+    let mut data = Vec::new();
+    for (slot, kernel) in header.slots.iter_mut().zip(kernels) {
+        slot.offset = (HEADER_SIZE + data.len()) as u64;
+        slot.size = kernel.len() as u64;
+        slot.uncompressed_size = kernel.len() as u64;
+        slot.checksum = crc32(kernel);
+        data.extend_from_slice(kernel);
+        data.resize(data.len().next_multiple_of(kernel_blob::SLOT_ALIGN), 0);
+    }
+    header.slots[0].uncompressed_size = LZ4_TEXT.len() as u64;
+    header.slots[0].compression = Compression::Lz4;
+
+    let mut image = header.to_bytes().to_vec();
+    image.extend(data);
+    (header, image)
+}
+
+#[test]
+fn image_header_round_trip() {
+    let (_, image) = kernel_image(&[LZ4_BLOCK, b"fallback kernel"]);
+    let header = ImageHeader::parse(&image).unwrap();
+    assert_eq!(header.slot_count, 2);
+
+    let primary = header.slots[0];
+    assert_eq!((primary.offset, primary.size, primary.uncompressed_size), (512, 30, 49));
+    assert_eq!(primary.checksum, crc32(LZ4_BLOCK));
+    assert!(primary.compression == Compression::Lz4);
+
+    let fallback = header.slots[kernel_blob::FALLBACK_SLOT];
+    assert_eq!((fallback.offset, fallback.size), (1024, 15));
+    assert!(fallback.compression == Compression::None);
+}
+
+#[test]
+fn image_header_errors() {
+    let (header, image) = kernel_image(&[LZ4_BLOCK]);
+    let parse_error = |image: &[u8]| ImageHeader::parse(image).err();
+
+    assert_eq!(parse_error(&image[..HEADER_SIZE - 1]), Some("Kernel image is smaller than its header"));
+
+    let mut bad_magic = image.clone();
+    bad_magic[0] = b'X';
+    assert_eq!(parse_error(&bad_magic), Some("Wrong magic number in kernel image header"));
+
+    let mut too_many_slots = image.clone();
+    too_many_slots[8] = MAX_SLOTS as u8 + 1;
+    assert_eq!(parse_error(&too_many_slots), Some("Too many slots in kernel image header"));
+
+    let mut corrupt = image.clone();
+    corrupt[16] ^= 1;
+    assert_eq!(parse_error(&corrupt), Some("Kernel image header is corrupt"));
+
+    // an unknown compression with a valid header checksum
+    let mut unknown_compression = header.to_bytes();
+    unknown_compression[16 + 28] = 2;
+    let checksum = crc32(&unknown_compression[16..48]);
+    unknown_compression[12..16].copy_from_slice(&checksum.to_le_bytes());
+    assert_eq!(parse_error(&unknown_compression), Some("Unknown kernel compression in image header"));
+}
+
+#[test]
+fn verified_slot() {
+    let (mut header, mut image) = kernel_image(&[LZ4_BLOCK, b"fallback kernel"]);
+    let primary = header.verified_slot(&image, kernel_blob::PRIMARY_SLOT).unwrap();
+    let mut output = [0; 49];
+    assert_eq!(lz4::decompress(primary, &mut output), Ok(49));
+    assert_eq!(header.verified_slot(&image, 1).unwrap(), b"fallback kernel");
+    assert_eq!(header.verified_slot(&image, 2).err(), Some("Kernel slot does not exist"));
+
+    // a corrupt primary kernel leaves the fallback intact
+    image[HEADER_SIZE + 3] ^= 0x80;
+    assert_eq!(header.verified_slot(&image, 0).err(), Some("Kernel checksum mismatch"));
+    assert!(header.verified_slot(&image, 1).is_ok());
+
+    header.slots[1].offset = 0;
+    assert_eq!(header.verified_slot(&image, 1).err(), Some("Kernel slot lies outside of the kernel image"));
+    header.slots[1].offset = image.len() as u64 - 8;
+    assert_eq!(header.verified_slot(&image, 1).err(), Some("Kernel slot lies outside of the kernel image"));
+    header.slots[1].size = u64::MAX;
+    assert_eq!(header.verified_slot(&image, 1).err(), Some("Kernel slot size overflows"));
+}